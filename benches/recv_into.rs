@@ -0,0 +1,73 @@
+//! Compares `recv_into` on one reused `RecvBuf` with reading into a freshly zeroed buffer
+//! for every read, the way a server handling many small messages would.
+
+use std::{
+    hint::black_box,
+    io::{Read, Write},
+    time::Instant,
+};
+
+use rio::{
+    net::RecvBuf,
+    tcp::{TcpListener, TcpStream},
+};
+
+const READS: u32 = 100_000;
+const BUF_SIZE: usize = 64 * 1024;
+const MESSAGE: [u8; 64] = [7; 64];
+
+fn pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let server = loop {
+        match listener.accept() {
+            Ok((server, _)) => break server,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(err) => panic!("accept failed: {}", err),
+        }
+    };
+    (client, server)
+}
+
+/// Sends one message and returns how long `read` took to receive it, retrying while it
+/// hasn't arrived yet.
+fn time_read(client: &mut TcpStream, mut read: impl FnMut() -> std::io::Result<usize>) -> u128 {
+    client.write_all(&MESSAGE).unwrap();
+    let start = Instant::now();
+    loop {
+        match read() {
+            Ok(n) => {
+                assert_eq!(n, MESSAGE.len());
+                return start.elapsed().as_nanos();
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(err) => panic!("read failed: {}", err),
+        }
+    }
+}
+
+fn main() {
+    let (mut client, mut server) = pair();
+
+    let mut fresh = 0;
+    for _ in 0..READS {
+        fresh += time_read(&mut client, || {
+            let mut buf = vec![0; BUF_SIZE];
+            let n = server.read(&mut buf)?;
+            black_box(&buf);
+            Ok(n)
+        });
+    }
+
+    let mut buf = RecvBuf::with_capacity(BUF_SIZE);
+    let mut reused = 0;
+    for _ in 0..READS {
+        reused += time_read(&mut client, || {
+            buf.clear();
+            server.recv_into(&mut buf)
+        });
+    }
+
+    println!("fresh zeroed buffer: {:>6} ns/read", fresh / READS as u128);
+    println!("reused RecvBuf:      {:>6} ns/read", reused / READS as u128);
+}
@@ -0,0 +1,198 @@
+//! A thin, safe wrapper around Linux epoll.
+
+use std::{
+    fmt, io,
+    ops::{BitOr, BitOrAssign},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    time::Duration,
+};
+
+use libc::{
+    c_int, epoll_event, EPOLLERR, EPOLLET, EPOLLHUP, EPOLLIN, EPOLLONESHOT, EPOLLOUT, EPOLLPRI,
+    EPOLLRDHUP, EPOLL_CLOEXEC, EPOLL_CTL_ADD, EPOLL_CTL_DEL, EPOLL_CTL_MOD,
+};
+
+fn cvt(res: c_int) -> io::Result<c_int> {
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+/// Identifies a registration in the events it produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Token(pub usize);
+
+/// The readiness a registration asks to be woken for.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Interest(u32);
+
+impl Interest {
+    pub const READABLE: Interest = Interest((EPOLLIN | EPOLLRDHUP) as u32);
+    pub const WRITABLE: Interest = Interest(EPOLLOUT as u32);
+    /// Urgent data, e.g. TCP out-of-band bytes.
+    pub const PRIORITY: Interest = Interest(EPOLLPRI as u32);
+    pub const EDGE: Interest = Interest(EPOLLET as u32);
+    pub const ONESHOT: Interest = Interest(EPOLLONESHOT as u32);
+
+    pub const fn from_bits(bits: u32) -> Interest {
+        Interest(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Interest) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn remove(self, other: Interest) -> Interest {
+        Interest(self.0 & !other.0)
+    }
+}
+
+impl BitOr for Interest {
+    type Output = Interest;
+
+    fn bitor(self, other: Interest) -> Interest {
+        Interest(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for Interest {
+    fn bitor_assign(&mut self, other: Interest) {
+        self.0 |= other.0;
+    }
+}
+
+impl fmt::Debug for Interest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Interest({:#x})", self.0)
+    }
+}
+
+/// A readiness event, laid out like the kernel's `struct epoll_event`.
+#[repr(transparent)]
+#[derive(Clone, Copy)]
+pub struct Event(epoll_event);
+
+impl Event {
+    pub fn new(bits: u32, token: Token) -> Event {
+        Event(epoll_event {
+            events: bits,
+            u64: token.0 as u64,
+        })
+    }
+
+    pub fn token(&self) -> Token {
+        Token(self.0.u64 as usize)
+    }
+
+    /// The event mask as reported by the kernel.
+    pub fn bits(&self) -> u32 {
+        self.0.events
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.bits() & EPOLLIN as u32 != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.bits() & EPOLLOUT as u32 != 0
+    }
+
+    pub fn is_priority(&self) -> bool {
+        self.bits() & EPOLLPRI as u32 != 0
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.bits() & EPOLLERR as u32 != 0
+    }
+
+    pub fn is_hup(&self) -> bool {
+        self.bits() & EPOLLHUP as u32 != 0
+    }
+
+    pub fn is_read_closed(&self) -> bool {
+        self.bits() & (EPOLLRDHUP | EPOLLHUP) as u32 != 0
+    }
+}
+
+impl fmt::Debug for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Event")
+            .field("token", &self.token())
+            .field("bits", &format_args!("{:#x}", self.bits()))
+            .finish()
+    }
+}
+
+/// An epoll instance.
+pub struct Epoll {
+    fd: OwnedFd,
+}
+
+impl Epoll {
+    pub fn new() -> io::Result<Epoll> {
+        let fd = cvt(unsafe { libc::epoll_create1(EPOLL_CLOEXEC) })?;
+        Ok(Epoll {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    pub fn add(&self, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+        self.ctl(EPOLL_CTL_ADD, fd, token, interest)
+    }
+
+    pub fn modify(&self, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+        self.ctl(EPOLL_CTL_MOD, fd, token, interest)
+    }
+
+    pub fn delete(&self, fd: RawFd) -> io::Result<()> {
+        self.ctl(EPOLL_CTL_DEL, fd, Token(0), Interest(0))
+    }
+
+    fn ctl(&self, op: c_int, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+        let mut event = Event::new(interest.0, token);
+        cvt(unsafe { libc::epoll_ctl(self.fd.as_raw_fd(), op, fd, &mut event.0) })?;
+        Ok(())
+    }
+
+    /// Waits for events, replacing the contents of `events` with up to its capacity of
+    /// them. `None` waits indefinitely. Returns the number of events received.
+    pub fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<usize> {
+        let timeout = match timeout {
+            // Rounds up, so that a short timeout doesn't turn into a busy loop.
+            Some(timeout) => timeout
+                .as_nanos()
+                .div_ceil(1_000_000)
+                .min(c_int::MAX as u128) as c_int,
+            None => -1,
+        };
+        events.clear();
+        let n = cvt(unsafe {
+            libc::epoll_wait(
+                self.fd.as_raw_fd(),
+                events.as_mut_ptr() as *mut epoll_event,
+                events.capacity().min(c_int::MAX as usize) as c_int,
+                timeout,
+            )
+        })? as usize;
+        unsafe { events.set_len(n) };
+        Ok(n)
+    }
+}
+
+impl AsRawFd for Epoll {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for Epoll {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
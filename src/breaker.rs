@@ -0,0 +1,246 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt, io,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// When a [`CircuitBreaker`] opens and for how long.
+#[derive(Debug, Clone)]
+pub struct BreakerConfig {
+    /// Failures within `window` that open the circuit.
+    pub threshold: usize,
+    pub window: Duration,
+    /// How long an open circuit fails connects before letting a single probe through, and
+    /// how long that probe may take to report back before another one is let through.
+    pub cooldown: Duration,
+    /// Connects that haven't completed in this long count as failures.
+    pub connect_timeout: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            threshold: 5,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The error connects fail with while a circuit is open, wrapped in an `io::Error`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CircuitOpen {
+    pub addr: SocketAddr,
+    /// Time left until a probe is let through. While one is in flight, that's when it will
+    /// be taken as abandoned.
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "circuit open for {}", self.addr)
+    }
+}
+
+impl Error for CircuitOpen {}
+
+#[derive(Default)]
+struct Circuit {
+    failures: VecDeque<Instant>,
+    open_until: Option<Instant>,
+    /// When the probe in flight was let through.
+    probe_started: Option<Instant>,
+}
+
+/// Tracks connect failures per address and fails connects to an address fast once it has
+/// failed `threshold` times within `window`, see
+/// [`TcpStream::connect_breaker`](crate::tcp::TcpStream::connect_breaker). After the
+/// cooldown one probe is let through: success closes the circuit, failure reopens it.
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    circuits: Mutex<HashMap<SocketAddr, Circuit>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            config,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &BreakerConfig {
+        &self.config
+    }
+
+    /// Fails with [`CircuitOpen`] if a connect to `addr` shouldn't be attempted now. Once
+    /// the cooldown has passed, the first caller gets to probe and must report the outcome
+    /// with `record_success` or `record_failure`, or `cancel_probe` if it never got as far
+    /// as connecting. A probe that hasn't reported back within another cooldown is taken
+    /// as abandoned, and the next caller probes instead.
+    pub fn check(&self, addr: SocketAddr) -> io::Result<()> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = match circuits.get_mut(&addr) {
+            Some(circuit) => circuit,
+            None => return Ok(()),
+        };
+        let open_until = match circuit.open_until {
+            Some(open_until) => open_until,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let retry_at = match circuit.probe_started {
+            Some(started) => started + self.config.cooldown,
+            None => open_until,
+        };
+        if now >= retry_at {
+            circuit.probe_started = Some(now);
+            return Ok(());
+        }
+        let open = CircuitOpen {
+            addr,
+            retry_after: retry_at - now,
+        };
+        Err(io::Error::other(open))
+    }
+
+    pub fn record_success(&self, addr: SocketAddr) {
+        self.circuits.lock().unwrap().remove(&addr);
+    }
+
+    pub fn record_failure(&self, addr: SocketAddr) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(addr).or_default();
+        let now = Instant::now();
+        if circuit.probe_started.take().is_some() {
+            circuit.open_until = Some(now + self.config.cooldown);
+            return;
+        }
+
+        circuit.failures.push_back(now);
+        while let Some(&oldest) = circuit.failures.front() {
+            if now.duration_since(oldest) <= self.config.window {
+                break;
+            }
+            circuit.failures.pop_front();
+        }
+        if circuit.failures.len() >= self.config.threshold {
+            circuit.failures.clear();
+            circuit.open_until = Some(now + self.config.cooldown);
+        }
+    }
+
+    /// Gives up the probe handed out by `check` without judging the address, e.g. when the
+    /// connect failed for local reasons, so the next caller gets to probe right away.
+    pub fn cancel_probe(&self, addr: SocketAddr) {
+        if let Some(circuit) = self.circuits.lock().unwrap().get_mut(&addr) {
+            circuit.probe_started = None;
+        }
+    }
+
+    /// Whether the circuit for `addr` is open, including while waiting on a probe.
+    pub fn is_open(&self, addr: SocketAddr) -> bool {
+        let circuits = self.circuits.lock().unwrap();
+        circuits
+            .get(&addr)
+            .is_some_and(|circuit| circuit.open_until.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::tcp::{TcpListener, TcpStream};
+
+    fn config(cooldown: Duration) -> BreakerConfig {
+        BreakerConfig {
+            threshold: 3,
+            window: Duration::from_secs(10),
+            cooldown,
+            connect_timeout: Duration::from_secs(5),
+        }
+    }
+
+    fn circuit_open<T>(result: io::Result<T>) -> CircuitOpen {
+        let err = result.err().expect("not an error");
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<CircuitOpen>())
+            .expect("not a CircuitOpen error")
+            .clone()
+    }
+
+    #[test]
+    fn trips_fails_fast_and_recovers() {
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let breaker = CircuitBreaker::new(config(Duration::from_millis(100)));
+
+        for _ in 0..3 {
+            let err = TcpStream::connect_breaker(addr, &breaker).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        }
+        assert!(breaker.is_open(addr));
+        let open = circuit_open(TcpStream::connect_breaker(addr, &breaker));
+        assert_eq!(open.addr, addr);
+        assert!(open.retry_after > Duration::ZERO);
+
+        // After the cooldown a probe is let through, and its success closes the circuit.
+        let _listener = TcpListener::bind(addr).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        TcpStream::connect_breaker(addr, &breaker).unwrap();
+        assert!(!breaker.is_open(addr));
+        TcpStream::connect_breaker(addr, &breaker).unwrap();
+    }
+
+    #[test]
+    fn failed_probe_reopens_the_circuit() {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let breaker = CircuitBreaker::new(config(Duration::ZERO));
+        for _ in 0..3 {
+            breaker.record_failure(addr);
+        }
+        breaker.check(addr).unwrap();
+        breaker.record_failure(addr);
+        assert!(breaker.is_open(addr));
+        breaker.check(addr).unwrap();
+        breaker.record_success(addr);
+        assert!(!breaker.is_open(addr));
+    }
+
+    #[test]
+    fn abandoned_probe_is_replaced_after_the_cooldown() {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let breaker = CircuitBreaker::new(config(Duration::from_millis(50)));
+        for _ in 0..3 {
+            breaker.record_failure(addr);
+        }
+        thread::sleep(Duration::from_millis(50));
+        breaker.check(addr).unwrap();
+        // The prober never reports back.
+        circuit_open(breaker.check(addr));
+        thread::sleep(Duration::from_millis(50));
+        breaker.check(addr).unwrap();
+    }
+
+    #[test]
+    fn cancelled_probe_lets_the_next_caller_probe() {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let breaker = CircuitBreaker::new(config(Duration::from_millis(50)));
+        for _ in 0..3 {
+            breaker.record_failure(addr);
+        }
+        thread::sleep(Duration::from_millis(50));
+        breaker.check(addr).unwrap();
+        breaker.cancel_probe(addr);
+        breaker.check(addr).unwrap();
+        assert!(breaker.is_open(addr));
+    }
+}
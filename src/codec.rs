@@ -0,0 +1,394 @@
+use std::io;
+
+/// The outcome of decoding from the bytes received so far, typically
+/// [`RecvBuf::filled`](crate::net::RecvBuf::filled).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decoded<T> {
+    /// A complete value, and how many bytes it took up. Pass that count to
+    /// [`RecvBuf::consume`](crate::net::RecvBuf::consume) before decoding the next value.
+    Complete(T, usize),
+    /// The buffer holds only part of a value; receive more and decode again.
+    Incomplete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefixSize {
+    U16,
+    U32,
+}
+
+impl PrefixSize {
+    fn len(self) -> usize {
+        match self {
+            PrefixSize::U16 => 2,
+            PrefixSize::U32 => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Big,
+    Little,
+}
+
+/// Frames messages with a length prefix that counts only the payload.
+#[derive(Debug, Clone)]
+pub struct LengthDelimitedCodec {
+    pub prefix: PrefixSize,
+    pub endian: Endian,
+    /// Longer frames are rejected, on decode as soon as the prefix arrives.
+    pub max_frame: usize,
+}
+
+impl Default for LengthDelimitedCodec {
+    fn default() -> Self {
+        LengthDelimitedCodec {
+            prefix: PrefixSize::U32,
+            endian: Endian::Big,
+            max_frame: 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl LengthDelimitedCodec {
+    pub fn decode(&self, buf: &[u8]) -> io::Result<Decoded<Vec<u8>>> {
+        let prefix_len = self.prefix.len();
+        let prefix = match buf.get(..prefix_len) {
+            Some(prefix) => prefix,
+            None => return Ok(Decoded::Incomplete),
+        };
+        let length = match (self.prefix, self.endian) {
+            (PrefixSize::U16, Endian::Big) => u16::from_be_bytes([prefix[0], prefix[1]]) as u64,
+            (PrefixSize::U16, Endian::Little) => u16::from_le_bytes([prefix[0], prefix[1]]) as u64,
+            (PrefixSize::U32, Endian::Big) => u32::from_be_bytes(prefix.try_into().unwrap()) as u64,
+            (PrefixSize::U32, Endian::Little) => {
+                u32::from_le_bytes(prefix.try_into().unwrap()) as u64
+            }
+        };
+        if length > self.max_frame as u64 {
+            return Err(frame_too_large(length, self.max_frame));
+        }
+
+        let end = prefix_len + length as usize;
+        match buf.get(prefix_len..end) {
+            Some(frame) => Ok(Decoded::Complete(frame.to_vec(), end)),
+            None => Ok(Decoded::Incomplete),
+        }
+    }
+
+    /// Appends `msg` to `out` with its length prefix.
+    pub fn encode(&self, msg: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let prefix_max = match self.prefix {
+            PrefixSize::U16 => u16::MAX as usize,
+            PrefixSize::U32 => u32::MAX as usize,
+        };
+        if msg.len() > self.max_frame.min(prefix_max) {
+            return Err(frame_too_large(
+                msg.len() as u64,
+                self.max_frame.min(prefix_max),
+            ));
+        }
+
+        let length = msg.len();
+        match (self.prefix, self.endian) {
+            (PrefixSize::U16, Endian::Big) => out.extend_from_slice(&(length as u16).to_be_bytes()),
+            (PrefixSize::U16, Endian::Little) => {
+                out.extend_from_slice(&(length as u16).to_le_bytes())
+            }
+            (PrefixSize::U32, Endian::Big) => out.extend_from_slice(&(length as u32).to_be_bytes()),
+            (PrefixSize::U32, Endian::Little) => {
+                out.extend_from_slice(&(length as u32).to_le_bytes())
+            }
+        }
+        out.extend_from_slice(msg);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineDelimiter {
+    /// Lines end with `\n`, and a `\r` before it is dropped too, so both styles are
+    /// accepted.
+    Lf,
+    /// Lines end with `\r\n`; a bare `\n` is part of the line.
+    CrLf,
+}
+
+impl LineDelimiter {
+    fn bytes(self) -> &'static [u8] {
+        match self {
+            LineDelimiter::Lf => b"\n",
+            LineDelimiter::CrLf => b"\r\n",
+        }
+    }
+}
+
+/// Frames messages as delimited lines, as in SMTP, IRC or Redis inline commands. Decoded
+/// lines don't include the delimiter.
+#[derive(Debug, Clone)]
+pub struct LineCodec {
+    pub delimiter: LineDelimiter,
+    /// Longer lines are rejected, on decode as soon as more than this many bytes arrive
+    /// without a delimiter.
+    pub max_line: usize,
+}
+
+impl Default for LineCodec {
+    fn default() -> Self {
+        LineCodec {
+            delimiter: LineDelimiter::CrLf,
+            max_line: 8 * 1024,
+        }
+    }
+}
+
+impl LineCodec {
+    pub fn decode(&self, buf: &[u8]) -> io::Result<Decoded<Vec<u8>>> {
+        let delimiter = self.delimiter.bytes();
+        // Only looks as far as the longest allowed line and a `\r\n` could reach, so a
+        // flood without delimiters is caught without scanning all of it.
+        let limit = self.max_line + 2;
+        let window = &buf[..buf.len().min(limit)];
+        let mut line = match window
+            .windows(delimiter.len())
+            .position(|candidate| candidate == delimiter)
+        {
+            Some(len) => &buf[..len],
+            None if window.len() == limit => {
+                return Err(line_too_long(self.max_line));
+            }
+            // A delimiter split across reads, e.g. a trailing `\r`, is found once the rest
+            // arrives.
+            None => return Ok(Decoded::Incomplete),
+        };
+        let consumed = line.len() + delimiter.len();
+        if self.delimiter == LineDelimiter::Lf {
+            line = line.strip_suffix(b"\r").unwrap_or(line);
+        }
+        if line.len() > self.max_line {
+            return Err(line_too_long(self.max_line));
+        }
+        Ok(Decoded::Complete(line.to_vec(), consumed))
+    }
+
+    /// Appends `line` to `out` followed by the delimiter, refusing lines that contain it.
+    pub fn encode(&self, line: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        if line.len() > self.max_line {
+            return Err(line_too_long(self.max_line));
+        }
+        let delimiter = self.delimiter.bytes();
+        if line
+            .windows(delimiter.len())
+            .any(|window| window == delimiter)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "line contains the delimiter",
+            ));
+        }
+        out.extend_from_slice(line);
+        out.extend_from_slice(delimiter);
+        Ok(())
+    }
+}
+
+fn line_too_long(max: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line exceeds the maximum of {} bytes", max),
+    )
+}
+
+fn frame_too_large(length: u64, max: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("frame of {} bytes exceeds the maximum of {}", length, max),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn length_codecs() -> Vec<LengthDelimitedCodec> {
+        let mut codecs = Vec::new();
+        for prefix in [PrefixSize::U16, PrefixSize::U32] {
+            for endian in [Endian::Big, Endian::Little] {
+                codecs.push(LengthDelimitedCodec {
+                    prefix,
+                    endian,
+                    max_frame: 16,
+                });
+            }
+        }
+        codecs
+    }
+
+    #[test]
+    fn length_delimited_round_trips() {
+        for codec in length_codecs() {
+            let mut buf = Vec::new();
+            codec.encode(b"hello", &mut buf).unwrap();
+            codec.encode(b"", &mut buf).unwrap();
+            let frame_len = codec.prefix.len() + 5;
+
+            assert_eq!(
+                codec.decode(&buf).unwrap(),
+                Decoded::Complete(b"hello".to_vec(), frame_len)
+            );
+            assert_eq!(
+                codec.decode(&buf[frame_len..]).unwrap(),
+                Decoded::Complete(Vec::new(), codec.prefix.len())
+            );
+        }
+    }
+
+    #[test]
+    fn length_delimited_prefix_byte_order() {
+        let mut codec = LengthDelimitedCodec::default();
+        let mut buf = Vec::new();
+        codec.encode(b"abc", &mut buf).unwrap();
+        assert_eq!(buf, b"\0\0\0\x03abc");
+
+        codec.prefix = PrefixSize::U16;
+        codec.endian = Endian::Little;
+        buf.clear();
+        codec.encode(b"abc", &mut buf).unwrap();
+        assert_eq!(buf, b"\x03\0abc");
+    }
+
+    #[test]
+    fn length_delimited_waits_for_the_whole_frame() {
+        for codec in length_codecs() {
+            let mut buf = Vec::new();
+            codec.encode(b"hello", &mut buf).unwrap();
+            for end in 0..buf.len() {
+                assert_eq!(codec.decode(&buf[..end]).unwrap(), Decoded::Incomplete);
+            }
+        }
+    }
+
+    #[test]
+    fn length_delimited_enforces_max_frame() {
+        let codec = LengthDelimitedCodec {
+            max_frame: 4,
+            ..LengthDelimitedCodec::default()
+        };
+        let mut buf = Vec::new();
+        let err = codec.encode(b"hello", &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(buf.is_empty());
+
+        // Rejected from the prefix alone, before the payload arrives.
+        let err = codec.decode(b"\0\0\0\x05").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = codec.decode(b"\xff\xff\xff\xff").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn length_delimited_refuses_messages_the_prefix_cannot_count() {
+        let codec = LengthDelimitedCodec {
+            prefix: PrefixSize::U16,
+            endian: Endian::Big,
+            max_frame: usize::MAX,
+        };
+        let mut buf = Vec::new();
+        codec.encode(&[0; u16::MAX as usize], &mut buf).unwrap();
+        buf.clear();
+        let err = codec
+            .encode(&[0; u16::MAX as usize + 1], &mut buf)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn line_codec(delimiter: LineDelimiter) -> LineCodec {
+        LineCodec {
+            delimiter,
+            max_line: 8,
+        }
+    }
+
+    #[test]
+    fn line_round_trips() {
+        for delimiter in [LineDelimiter::Lf, LineDelimiter::CrLf] {
+            let codec = line_codec(delimiter);
+            let mut buf = Vec::new();
+            codec.encode(b"HELO", &mut buf).unwrap();
+            codec.encode(b"", &mut buf).unwrap();
+            let first_len = 4 + delimiter.bytes().len();
+
+            assert_eq!(
+                codec.decode(&buf).unwrap(),
+                Decoded::Complete(b"HELO".to_vec(), first_len)
+            );
+            assert_eq!(
+                codec.decode(&buf[first_len..]).unwrap(),
+                Decoded::Complete(Vec::new(), delimiter.bytes().len())
+            );
+        }
+    }
+
+    #[test]
+    fn line_delimiters() {
+        // Lf accepts both line endings.
+        let lf = line_codec(LineDelimiter::Lf);
+        assert_eq!(
+            lf.decode(b"a\r\nb").unwrap(),
+            Decoded::Complete(b"a".to_vec(), 3)
+        );
+        assert_eq!(
+            lf.decode(b"a\nb").unwrap(),
+            Decoded::Complete(b"a".to_vec(), 2)
+        );
+
+        // CrLf keeps a bare `\n` in the line.
+        let crlf = line_codec(LineDelimiter::CrLf);
+        assert_eq!(
+            crlf.decode(b"a\nb\r\n").unwrap(),
+            Decoded::Complete(b"a\nb".to_vec(), 5)
+        );
+        let err = crlf.encode(b"a\r\nb", &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = lf.encode(b"a\nb", &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn line_waits_for_the_delimiter() {
+        let codec = line_codec(LineDelimiter::CrLf);
+        assert_eq!(codec.decode(b"").unwrap(), Decoded::Incomplete);
+        assert_eq!(codec.decode(b"QUIT").unwrap(), Decoded::Incomplete);
+        // The delimiter split across reads.
+        assert_eq!(codec.decode(b"QUIT\r").unwrap(), Decoded::Incomplete);
+        assert_eq!(
+            codec.decode(b"QUIT\r\n").unwrap(),
+            Decoded::Complete(b"QUIT".to_vec(), 6)
+        );
+    }
+
+    #[test]
+    fn line_enforces_max_line() {
+        for delimiter in [LineDelimiter::Lf, LineDelimiter::CrLf] {
+            let codec = line_codec(delimiter);
+            let mut line = b"12345678".to_vec();
+            line.extend_from_slice(delimiter.bytes());
+            assert_eq!(
+                codec.decode(&line).unwrap(),
+                Decoded::Complete(b"12345678".to_vec(), line.len())
+            );
+
+            let err = codec.decode(b"123456789\n").unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            let err = codec.encode(b"123456789", &mut Vec::new()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        // A flood without delimiters fails once it can no longer fit a line.
+        let codec = line_codec(LineDelimiter::CrLf);
+        assert_eq!(codec.decode(&[b'x'; 9]).unwrap(), Decoded::Incomplete);
+        let err = codec.decode(&[b'x'; 1024]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,581 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io,
+    mem::{size_of, zeroed},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+    time::{Duration, Instant},
+};
+
+use libc::{c_void, itimerspec, CLOCK_MONOTONIC, TFD_CLOEXEC, TFD_NONBLOCK};
+
+use crate::syscall;
+
+/// An `Epoll` is pollable itself, so one can be nested in an outer event loop: register its
+/// fd there as readable, and when the outer loop reports it, call [`Epoll::wait`] with a
+/// zero timeout to collect the inner events. Keep the outer registration level-triggered, or
+/// drain the inner one completely each time, since the fd stays readable while inner events
+/// are pending.
+pub use epoll_rs::Epoll;
+pub use epoll_rs::{Event, Interest, Token};
+
+/// An fd registered with an [`Epoll`], remembering its token and interest so they don't
+/// have to be tracked alongside it. Anything `AsRawFd` can be registered, including a bare
+/// `RawFd` such as a pipe or inotify fd from another library. It doesn't own the fd: keep the
+/// fd open while it is registered and [`deregister`](Registration::deregister) it before
+/// closing, or a later fd reusing the number may be reported under this token.
+#[derive(Debug)]
+pub struct Registration {
+    fd: RawFd,
+    token: Token,
+    interest: Interest,
+    paused: bool,
+}
+
+impl Registration {
+    pub fn new(
+        epoll: &Epoll,
+        fd: &impl AsRawFd,
+        token: Token,
+        interest: Interest,
+    ) -> io::Result<Registration> {
+        let fd = fd.as_raw_fd();
+        epoll.add(fd, token, interest)?;
+        Ok(Registration {
+            fd,
+            token,
+            interest,
+            paused: false,
+        })
+    }
+
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    /// The interest registered, kept while paused.
+    pub fn interest(&self) -> Interest {
+        self.interest
+    }
+
+    /// Adds to the registered interest, e.g. [`Interest::WRITABLE`] once a write would
+    /// block, making no syscall if it is there already.
+    pub fn add_interest(&mut self, epoll: &Epoll, interest: Interest) -> io::Result<()> {
+        self.set_interest(epoll, self.interest | interest)
+    }
+
+    /// Removes from the registered interest, e.g. [`Interest::WRITABLE`] once everything
+    /// queued has been written, making no syscall if it isn't there.
+    pub fn remove_interest(&mut self, epoll: &Epoll, interest: Interest) -> io::Result<()> {
+        self.set_interest(epoll, self.interest.remove(interest))
+    }
+
+    /// Replaces the registered interest. While paused, it takes effect on resume.
+    pub fn set_interest(&mut self, epoll: &Epoll, interest: Interest) -> io::Result<()> {
+        if interest == self.interest {
+            return Ok(());
+        }
+        if !self.paused {
+            epoll.modify(self.fd, self.token, interest)?;
+        }
+        self.interest = interest;
+        Ok(())
+    }
+
+    /// Stops events for the fd without deregistering it, e.g. to stop accepting on a
+    /// listener under overload. Nothing is lost meanwhile: connections wait in the listen
+    /// backlog, and data in the receive buffer. Errors and hangups are still reported, as
+    /// epoll always reports them.
+    pub fn pause(&mut self, epoll: &Epoll) -> io::Result<()> {
+        if !self.paused {
+            epoll.modify(self.fd, self.token, Interest::from_bits(0))?;
+            self.paused = true;
+        }
+        Ok(())
+    }
+
+    /// Restores the interest paused by [`pause`](Registration::pause). Readiness that
+    /// built up meanwhile is reported by the next wait, edge-triggered or not.
+    pub fn resume(&mut self, epoll: &Epoll) -> io::Result<()> {
+        if self.paused {
+            epoll.modify(self.fd, self.token, self.interest)?;
+            self.paused = false;
+        }
+        Ok(())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn deregister(self, epoll: &Epoll) -> io::Result<()> {
+        epoll.delete(self.fd)
+    }
+}
+
+/// Registers many fds at once, e.g. a burst of accepted connections, returning each one's
+/// registration or error in order, so that one bad fd doesn't hold up the rest. Linux has
+/// no batched `epoll_ctl`, so this still makes one syscall per fd.
+pub fn register_batch(
+    epoll: &Epoll,
+    items: &[(RawFd, Token, Interest)],
+) -> Vec<io::Result<Registration>> {
+    items
+        .iter()
+        .map(|&(fd, token, interest)| Registration::new(epoll, &fd, token, interest))
+        .collect()
+}
+
+/// Merges events that share a token into one, ORing their flags (error and hangup
+/// included), so each registration is handled once per [`Epoll::wait`]. Events keep the
+/// order in which their token first appeared. One `epoll_wait` reports each fd once, so
+/// duplicates come from fds registered more than once under one token, or from events
+/// collected across several waits.
+pub fn coalesce(events: &mut Vec<Event>) {
+    let mut first: HashMap<Token, usize> = HashMap::with_capacity(events.len());
+    let mut merged: Vec<Event> = Vec::with_capacity(events.len());
+    for event in events.drain(..) {
+        match first.get(&event.token()) {
+            Some(&i) => {
+                let bits = merged[i].bits() | event.bits();
+                merged[i] = Event::new(bits, event.token());
+            }
+            None => {
+                first.insert(event.token(), merged.len());
+                merged.push(event);
+            }
+        }
+    }
+    *events = merged;
+}
+
+/// Deadlines for any number of tokens behind a single `timerfd`, always armed for the
+/// earliest, so an event loop can time out idle connections without a timer for each.
+/// Register it for [`Interest::READABLE`] under a token of its own, and when that token
+/// fires, call [`expired`](Deadlines::expired) for the tokens whose deadlines have passed.
+pub struct Deadlines {
+    timer: OwnedFd,
+    deadlines: HashMap<Token, Instant>,
+    /// The same deadlines, ordered by when they expire.
+    queue: BTreeSet<(Instant, Token)>,
+    /// The deadline the timer is armed for, if any.
+    armed: Option<Instant>,
+}
+
+impl Deadlines {
+    pub fn new() -> io::Result<Deadlines> {
+        let fd = syscall!(timerfd_create(CLOCK_MONOTONIC, TFD_NONBLOCK | TFD_CLOEXEC))?;
+        Ok(Deadlines {
+            timer: unsafe { OwnedFd::from_raw_fd(fd) },
+            deadlines: HashMap::new(),
+            queue: BTreeSet::new(),
+            armed: None,
+        })
+    }
+
+    /// Sets `token`'s deadline, replacing any earlier one, e.g. to push it back whenever
+    /// the connection is active.
+    pub fn set(&mut self, token: Token, deadline: Instant) -> io::Result<()> {
+        if let Some(old) = self.deadlines.insert(token, deadline) {
+            self.queue.remove(&(old, token));
+        }
+        self.queue.insert((deadline, token));
+        self.rearm()
+    }
+
+    /// Drops `token`'s deadline, e.g. once its connection is closed.
+    pub fn remove(&mut self, token: Token) -> io::Result<()> {
+        if let Some(old) = self.deadlines.remove(&token) {
+            self.queue.remove(&(old, token));
+        }
+        self.rearm()
+    }
+
+    pub fn get(&self, token: Token) -> Option<Instant> {
+        self.deadlines.get(&token).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.deadlines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deadlines.is_empty()
+    }
+
+    /// Removes and returns the tokens whose deadlines have passed, earliest first, and
+    /// re-arms the timer for the next one.
+    pub fn expired(&mut self) -> io::Result<Vec<Token>> {
+        let mut expirations = 0u64;
+        match syscall!(read(
+            self.timer.as_raw_fd(),
+            &mut expirations as *mut u64 as *mut c_void,
+            size_of::<u64>()
+        )) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        while let Some(&(deadline, token)) = self.queue.first() {
+            if deadline > now {
+                break;
+            }
+            self.queue.pop_first();
+            self.deadlines.remove(&token);
+            expired.push(token);
+        }
+        // The timer was disarmed by firing.
+        self.armed = None;
+        self.rearm()?;
+        Ok(expired)
+    }
+
+    /// Arms the timer for the earliest deadline, or disarms it if there are none.
+    fn rearm(&mut self) -> io::Result<()> {
+        let next = self.queue.first().map(|&(deadline, _)| deadline);
+        if next == self.armed {
+            return Ok(());
+        }
+        let mut spec: itimerspec = unsafe { zeroed() };
+        if let Some(next) = next {
+            // An all-zero value disarms the timer, so a passed deadline fires in 1ns.
+            let delay = next
+                .saturating_duration_since(Instant::now())
+                .max(Duration::from_nanos(1));
+            spec.it_value.tv_sec = delay.as_secs() as _;
+            spec.it_value.tv_nsec = delay.subsec_nanos() as _;
+        }
+        syscall!(timerfd_settime(
+            self.timer.as_raw_fd(),
+            0,
+            &spec,
+            ptr::null_mut()
+        ))?;
+        self.armed = next;
+        Ok(())
+    }
+}
+
+impl AsRawFd for Deadlines {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::SocketAddr,
+        os::unix::net::UnixStream,
+    };
+
+    use super::*;
+    use crate::tcp::{TcpListener, TcpStream};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn wait(epoll: &Epoll, timeout: Duration) -> Vec<Event> {
+        let mut events = Vec::with_capacity(8);
+        epoll.wait(&mut events, Some(timeout)).unwrap();
+        events
+    }
+
+    fn listener() -> TcpListener {
+        TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    fn connect(addr: SocketAddr) -> TcpStream {
+        TcpStream::connect(addr).unwrap()
+    }
+
+    /// A connected client and server stream over loopback.
+    fn pair() -> (TcpStream, TcpStream) {
+        let open = listener();
+        let client = connect(open.local_addr().unwrap());
+        let epoll = Epoll::new().unwrap();
+        let registration = Registration::new(&epoll, &open, Token(0), Interest::READABLE).unwrap();
+        wait(&epoll, TIMEOUT);
+        registration.deregister(&epoll).unwrap();
+        (client, open.accept().unwrap().0)
+    }
+
+    #[test]
+    fn paused_listeners_keep_connections_queued() {
+        let epoll = Epoll::new().unwrap();
+        let open = listener();
+        let mut registration =
+            Registration::new(&epoll, &open, Token(1), Interest::READABLE).unwrap();
+        registration.pause(&epoll).unwrap();
+        assert!(registration.is_paused());
+
+        let addr = open.local_addr().unwrap();
+        let _clients: Vec<_> = (0..3).map(|_| connect(addr)).collect();
+        assert!(wait(&epoll, Duration::from_millis(50)).is_empty());
+        // Pausing twice is harmless.
+        registration.pause(&epoll).unwrap();
+
+        registration.resume(&epoll).unwrap();
+        assert!(!registration.is_paused());
+        let events = wait(&epoll, TIMEOUT);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].token(), Token(1));
+        assert!(events[0].is_readable());
+        for _ in 0..3 {
+            open.accept().unwrap();
+        }
+
+        registration.deregister(&epoll).unwrap();
+        connect(addr);
+        assert!(wait(&epoll, Duration::from_millis(50)).is_empty());
+    }
+
+    #[test]
+    fn resume_reports_readiness_to_edge_triggered_registrations() {
+        let epoll = Epoll::new().unwrap();
+        let open = listener();
+        let interest = Interest::READABLE | Interest::EDGE;
+        let mut registration = Registration::new(&epoll, &open, Token(7), interest).unwrap();
+        registration.pause(&epoll).unwrap();
+        let _client = connect(open.local_addr().unwrap());
+        assert!(wait(&epoll, Duration::from_millis(50)).is_empty());
+
+        registration.resume(&epoll).unwrap();
+        assert_eq!(registration.interest(), interest);
+        let events = wait(&epoll, TIMEOUT);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].token(), Token(7));
+    }
+
+    #[test]
+    fn urgent_data_shows_in_the_raw_event_mask() {
+        let (client, server) = pair();
+        let epoll = Epoll::new().unwrap();
+        let interest = Interest::READABLE | Interest::PRIORITY;
+        let _registration = Registration::new(&epoll, &server, Token(3), interest).unwrap();
+
+        client.send_with_flags(b"!", libc::MSG_OOB).unwrap();
+        let events = wait(&epoll, TIMEOUT);
+        assert_eq!(events.len(), 1);
+        assert!(events[0].is_priority());
+        assert_ne!(events[0].bits() & libc::EPOLLPRI as u32, 0);
+
+        // The urgent byte isn't part of the stream, so without the interest it wakes
+        // nothing.
+        let (client, server) = pair();
+        let epoll = Epoll::new().unwrap();
+        let _registration =
+            Registration::new(&epoll, &server, Token(4), Interest::READABLE).unwrap();
+        client.send_with_flags(b"!", libc::MSG_OOB).unwrap();
+        assert!(wait(&epoll, Duration::from_millis(50)).is_empty());
+    }
+
+    #[test]
+    fn writable_interest_toggles_one_direction_at_a_time() {
+        let (mut client, server) = pair();
+        let epoll = Epoll::new().unwrap();
+        let mut registration =
+            Registration::new(&epoll, &server, Token(5), Interest::READABLE).unwrap();
+        client.write_all(b"ping").unwrap();
+        let events = wait(&epoll, TIMEOUT);
+        assert!(events[0].is_readable() && !events[0].is_writable());
+
+        registration
+            .add_interest(&epoll, Interest::WRITABLE)
+            .unwrap();
+        assert_eq!(
+            registration.interest(),
+            Interest::READABLE | Interest::WRITABLE
+        );
+        let events = wait(&epoll, TIMEOUT);
+        assert_eq!(events.len(), 1);
+        assert!(events[0].is_readable() && events[0].is_writable());
+
+        registration
+            .remove_interest(&epoll, Interest::WRITABLE)
+            .unwrap();
+        assert_eq!(registration.interest(), Interest::READABLE);
+        let events = wait(&epoll, TIMEOUT);
+        assert!(events[0].is_readable() && !events[0].is_writable());
+
+        // Changed while paused, the interest applies once resumed.
+        registration.pause(&epoll).unwrap();
+        registration
+            .set_interest(&epoll, Interest::WRITABLE)
+            .unwrap();
+        assert!(wait(&epoll, Duration::from_millis(20)).is_empty());
+        registration.resume(&epoll).unwrap();
+        let events = wait(&epoll, TIMEOUT);
+        assert!(!events[0].is_readable() && events[0].is_writable());
+    }
+
+    #[test]
+    fn deadlines_fire_for_the_tokens_that_pass_them() {
+        let epoll = Epoll::new().unwrap();
+        let mut deadlines = Deadlines::new().unwrap();
+        let timer = Token(100);
+        let _registration =
+            Registration::new(&epoll, &deadlines, timer, Interest::READABLE).unwrap();
+
+        let start = Instant::now();
+        deadlines
+            .set(Token(2), start + Duration::from_secs(60))
+            .unwrap();
+        deadlines
+            .set(Token(1), start + Duration::from_millis(30))
+            .unwrap();
+        let events = wait(&epoll, TIMEOUT);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].token(), timer);
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert_eq!(deadlines.expired().unwrap(), [Token(1)]);
+        assert_eq!(deadlines.len(), 1);
+        assert_eq!(deadlines.get(Token(1)), None);
+
+        // Nothing more is due for a minute.
+        assert!(wait(&epoll, Duration::from_millis(50)).is_empty());
+        assert!(deadlines.expired().unwrap().is_empty());
+    }
+
+    #[test]
+    fn moved_and_removed_deadlines_rearm_the_timer() {
+        let epoll = Epoll::new().unwrap();
+        let mut deadlines = Deadlines::new().unwrap();
+        let _registration =
+            Registration::new(&epoll, &deadlines, Token(100), Interest::READABLE).unwrap();
+
+        // Activity pushes the deadline back before it is reached.
+        let start = Instant::now();
+        deadlines
+            .set(Token(3), start + Duration::from_millis(20))
+            .unwrap();
+        deadlines
+            .set(Token(3), start + Duration::from_millis(120))
+            .unwrap();
+        assert_eq!(deadlines.len(), 1);
+        assert!(wait(&epoll, Duration::from_millis(60)).is_empty());
+        assert_eq!(wait(&epoll, TIMEOUT).len(), 1);
+        assert!(start.elapsed() >= Duration::from_millis(120));
+        assert_eq!(deadlines.expired().unwrap(), [Token(3)]);
+
+        // A removed deadline never fires.
+        deadlines
+            .set(Token(4), Instant::now() + Duration::from_millis(20))
+            .unwrap();
+        deadlines.remove(Token(4)).unwrap();
+        assert!(deadlines.is_empty());
+        assert!(wait(&epoll, Duration::from_millis(50)).is_empty());
+
+        // Deadlines already past fire at once.
+        deadlines.set(Token(5), start).unwrap();
+        deadlines.set(Token(6), start).unwrap();
+        assert_eq!(wait(&epoll, TIMEOUT).len(), 1);
+        let mut expired = deadlines.expired().unwrap();
+        expired.sort();
+        assert_eq!(expired, [Token(5), Token(6)]);
+    }
+
+    #[test]
+    fn register_batch_keeps_going_past_a_bad_fd() {
+        let epoll = Epoll::new().unwrap();
+        let pairs: Vec<_> = (0..32).map(|_| UnixStream::pair().unwrap()).collect();
+        let mut items: Vec<_> = pairs
+            .iter()
+            .enumerate()
+            .map(|(i, (ours, _))| (ours.as_raw_fd(), Token(i), Interest::READABLE))
+            .collect();
+        items.insert(10, (-1, Token(999), Interest::READABLE));
+
+        let results = register_batch(&epoll, &items);
+        assert_eq!(results.len(), 33);
+        assert_eq!(
+            results[10].as_ref().unwrap_err().raw_os_error(),
+            Some(libc::EBADF)
+        );
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 32);
+        assert_eq!(results[11].as_ref().unwrap().token(), Token(10));
+
+        for (_, theirs) in &pairs {
+            (&*theirs).write_all(b"x").unwrap();
+        }
+        let mut events = Vec::with_capacity(64);
+        let mut tokens = Vec::new();
+        while tokens.len() < 32 {
+            assert!(epoll.wait(&mut events, Some(TIMEOUT)).unwrap() > 0);
+            tokens.extend(events.iter().map(|event| event.token().0));
+            // Level-triggered, so each is reported until read; drop them as they come.
+            for event in &events {
+                epoll.delete(pairs[event.token().0].0.as_raw_fd()).unwrap();
+            }
+        }
+        tokens.sort();
+        assert_eq!(tokens, (0..32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn nested_epoll_reports_inner_events_to_the_outer_one() {
+        let outer = Epoll::new().unwrap();
+        let inner = Epoll::new().unwrap();
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        Registration::new(&inner, &ours, Token(1), Interest::READABLE).unwrap();
+        Registration::new(&outer, &inner, Token(100), Interest::READABLE).unwrap();
+        assert!(wait(&outer, Duration::from_millis(50)).is_empty());
+
+        (&theirs).write_all(b"x").unwrap();
+        let events = wait(&outer, TIMEOUT);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].token(), Token(100));
+        let events = wait(&inner, Duration::ZERO);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].token(), Token(1));
+        assert!(events[0].is_readable());
+
+        (&ours).read_exact(&mut [0; 1]).unwrap();
+        assert!(wait(&outer, Duration::from_millis(50)).is_empty());
+    }
+
+    #[test]
+    fn bare_pipe_fds_can_be_registered() {
+        let mut fds = [0; 2];
+        syscall!(pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK)).unwrap();
+        let [read_end, write_end] = fds.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+        let epoll = Epoll::new().unwrap();
+        let read_fd: RawFd = read_end.as_raw_fd();
+        let registration =
+            Registration::new(&epoll, &read_fd, Token(3), Interest::READABLE).unwrap();
+        assert!(wait(&epoll, Duration::from_millis(50)).is_empty());
+
+        syscall!(write(write_end.as_raw_fd(), b"x".as_ptr().cast(), 1)).unwrap();
+        let events = wait(&epoll, TIMEOUT);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].token(), Token(3));
+        assert!(events[0].is_readable());
+        registration.deregister(&epoll).unwrap();
+    }
+
+    #[test]
+    fn coalesce_merges_flags_per_token() {
+        let in_ = libc::EPOLLIN as u32;
+        let out = libc::EPOLLOUT as u32;
+        let err = libc::EPOLLERR as u32;
+        let hup = libc::EPOLLHUP as u32;
+        let mut events = vec![
+            Event::new(in_, Token(7)),
+            Event::new(out, Token(2)),
+            Event::new(err | hup, Token(7)),
+            Event::new(out, Token(7)),
+            Event::new(in_, Token(2)),
+        ];
+        coalesce(&mut events);
+
+        let merged: Vec<_> = events.iter().map(|e| (e.token(), e.bits())).collect();
+        assert_eq!(
+            merged,
+            [(Token(7), in_ | out | err | hup), (Token(2), in_ | out)]
+        );
+        assert!(events[0].is_error() && events[0].is_hup());
+    }
+}
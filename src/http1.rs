@@ -0,0 +1,237 @@
+use std::io;
+
+use crate::codec::Decoded;
+
+/// Default limit on the size of the request line and headers together.
+pub const MAX_HEAD_SIZE: usize = 8 * 1024;
+/// Default limit on the number of headers.
+pub const MAX_HEADERS: usize = 100;
+
+/// The request line and headers of an HTTP/1.x request. The body, if any, follows the
+/// consumed bytes and is left to the caller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    /// The minor version: 0 for HTTP/1.0, 1 for HTTP/1.1.
+    pub version: u8,
+    /// Header names and values in the order received, values trimmed of surrounding
+    /// whitespace.
+    pub headers: Vec<(String, Vec<u8>)>,
+}
+
+impl RequestHead {
+    pub fn parse(buf: &[u8]) -> io::Result<Decoded<RequestHead>> {
+        Self::parse_limited(buf, MAX_HEAD_SIZE, MAX_HEADERS)
+    }
+
+    /// Parses a request head, failing with `InvalidData` once it exceeds `max_size` bytes
+    /// or `max_headers` headers, even if the end of it hasn't arrived yet.
+    pub fn parse_limited(
+        buf: &[u8],
+        max_size: usize,
+        max_headers: usize,
+    ) -> io::Result<Decoded<RequestHead>> {
+        let mut lines = Vec::new();
+        let mut start = 0;
+        let mut end = None;
+        while let Some(pos) = buf[start..].iter().position(|&b| b == b'\n') {
+            let line_end = start + pos;
+            let line = &buf[start..line_end];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            start = line_end + 1;
+            if start > max_size {
+                return Err(invalid("request head too large"));
+            }
+
+            if line.is_empty() {
+                // Empty lines before the request line are tolerated, after it they end the head.
+                if lines.is_empty() {
+                    continue;
+                }
+                end = Some(start);
+                break;
+            }
+            if lines.len() > max_headers {
+                return Err(invalid("too many headers"));
+            }
+            lines.push(line);
+        }
+
+        let consumed = match end {
+            Some(consumed) => consumed,
+            None if buf.len() > max_size => return Err(invalid("request head too large")),
+            None => return Ok(Decoded::Incomplete),
+        };
+
+        let (method, path, version) = parse_request_line(lines[0])?;
+        let headers = lines[1..]
+            .iter()
+            .map(|line| parse_header(line))
+            .collect::<io::Result<_>>()?;
+
+        let head = RequestHead {
+            method,
+            path,
+            version,
+            headers,
+        };
+        Ok(Decoded::Complete(head, consumed))
+    }
+
+    /// Returns the value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn is_token(s: &[u8]) -> bool {
+    !s.is_empty()
+        && s.iter()
+            .all(|&b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn parse_request_line(line: &[u8]) -> io::Result<(String, String, u8)> {
+    let mut parts = line.split(|&b| b == b' ');
+    let (method, path, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version), None) => (method, path, version),
+        _ => return Err(invalid("malformed request line")),
+    };
+
+    if !is_token(method) {
+        return Err(invalid("invalid method"));
+    }
+    if path.is_empty() || !path.iter().all(|b| b.is_ascii_graphic()) {
+        return Err(invalid("invalid request target"));
+    }
+    let version = match version {
+        b"HTTP/1.0" => 0,
+        b"HTTP/1.1" => 1,
+        _ => return Err(invalid("unsupported HTTP version")),
+    };
+
+    // Both were checked to be ASCII above.
+    let method = String::from_utf8_lossy(method).into_owned();
+    let path = String::from_utf8_lossy(path).into_owned();
+    Ok((method, path, version))
+}
+
+fn parse_header(line: &[u8]) -> io::Result<(String, Vec<u8>)> {
+    let colon = match line.iter().position(|&b| b == b':') {
+        Some(colon) => colon,
+        None => return Err(invalid("malformed header")),
+    };
+    // Rejects whitespace before the colon and obsolete line folding.
+    let name = &line[..colon];
+    if !is_token(name) {
+        return Err(invalid("invalid header name"));
+    }
+
+    let mut value = &line[colon + 1..];
+    while let [b' ' | b'\t', rest @ ..] = value {
+        value = rest;
+    }
+    while let [rest @ .., b' ' | b'\t'] = value {
+        value = rest;
+    }
+    if value.iter().any(|&b| b == b'\r' || b == 0) {
+        return Err(invalid("invalid header value"));
+    }
+
+    let name = String::from_utf8_lossy(name).into_owned();
+    Ok((name, value.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &[u8] =
+        b"POST /submit?x=1 HTTP/1.1\r\nHost: example.com\r\nContent-Length:  4 \t\r\nX-Empty:\r\n\r\nbody";
+
+    fn parse(buf: &[u8]) -> io::Result<RequestHead> {
+        match RequestHead::parse(buf)? {
+            Decoded::Complete(head, _) => Ok(head),
+            Decoded::Incomplete => panic!("incomplete"),
+        }
+    }
+
+    #[test]
+    fn parses_a_request_head() {
+        let head_len = REQUEST.len() - 4;
+        let head = match RequestHead::parse(REQUEST).unwrap() {
+            Decoded::Complete(head, consumed) => {
+                assert_eq!(consumed, head_len);
+                head
+            }
+            Decoded::Incomplete => panic!("incomplete"),
+        };
+        assert_eq!(head.method, "POST");
+        assert_eq!(head.path, "/submit?x=1");
+        assert_eq!(head.version, 1);
+        assert_eq!(head.headers.len(), 3);
+        assert_eq!(head.header("content-length"), Some(&b"4"[..]));
+        assert_eq!(head.header("HOST"), Some(&b"example.com"[..]));
+        assert_eq!(head.header("x-empty"), Some(&b""[..]));
+        assert_eq!(head.header("accept"), None);
+    }
+
+    #[test]
+    fn accepts_bare_lf_and_leading_empty_lines() {
+        let head = parse(b"\r\n\nGET / HTTP/1.0\nHost: a\n\n").unwrap();
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.version, 0);
+        assert_eq!(head.header("host"), Some(&b"a"[..]));
+    }
+
+    #[test]
+    fn waits_for_the_end_of_the_head() {
+        let head_len = REQUEST.len() - 4;
+        for end in 0..head_len {
+            assert_eq!(
+                RequestHead::parse(&REQUEST[..end]).unwrap(),
+                Decoded::Incomplete
+            );
+        }
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let err = RequestHead::parse_limited(REQUEST, 16, MAX_HEADERS).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Caught before the end of the head arrives.
+        let err = RequestHead::parse_limited(&[b'a'; 17], 16, MAX_HEADERS).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let head_len = REQUEST.len() - 4;
+        assert!(RequestHead::parse_limited(REQUEST, head_len, 3).is_ok());
+        let err = RequestHead::parse_limited(REQUEST, head_len, 2).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_malformed_heads() {
+        let malformed: [&[u8]; 9] = [
+            b"GET /\r\n\r\n",
+            b"GET / HTTP/1.1 extra\r\n\r\n",
+            b"G(T / HTTP/1.1\r\n\r\n",
+            b"GET /\x7f HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/2.0\r\n\r\n",
+            b"GET / HTTP/1.1\r\nno colon\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost : a\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\n folded\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\rb\r\n\r\n",
+        ];
+        for buf in malformed {
+            let err = parse(buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", buf);
+        }
+    }
+}
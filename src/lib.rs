@@ -1,14 +1,33 @@
-pub mod epoll {
-    pub use epoll_rs::{Epoll, Event, Interest, Token};
-}
+pub mod epoll;
 pub mod tcp;
+pub mod udp;
 pub mod net;
+pub mod resolve;
+pub mod breaker;
+pub mod slab;
+pub mod pool;
+pub mod server;
+pub mod unix;
+pub mod codec;
+#[cfg(feature = "http1")]
+pub mod http1;
+#[cfg(feature = "ws")]
+pub mod ws;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "sctp")]
+pub mod sctp;
+#[cfg(feature = "proxy")]
+pub mod proxy;
+#[cfg(feature = "resp")]
+pub mod resp;
 
 #[allow(unused_macros)]
-
 #[macro_export]
 macro_rules! syscall {
     ($fn: ident ( $($arg: expr),* $(,)* ) ) => {{
+        // Callers pass the arguments of the libc call, which is unsafe to make anyway.
+        #[allow(clippy::macro_metavars_in_unsafe)]
         let res = unsafe { libc::$fn($($arg, )*) };
         if res == -1 {
             Err(std::io::Error::last_os_error())
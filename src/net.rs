@@ -1,18 +1,806 @@
 use libc::{
-    c_int, in6_addr, in_addr, sa_family_t, sockaddr, sockaddr_in, sockaddr_in6, sockaddr_storage,
-    socklen_t, AF_INET, AF_INET6, SOCK_CLOEXEC, SOCK_NONBLOCK,
+    c_char, c_int, c_short, c_void, in6_addr, in_addr, iovec, msghdr, nfds_t, pollfd, rlimit,
+    sa_family_t, sockaddr, sockaddr_in, sockaddr_in6, sockaddr_storage, socklen_t, AF_INET,
+    AF_INET6, FD_CLOEXEC, F_GETFD, F_SETFD, IF_NAMESIZE, IPPROTO_ICMP, IPPROTO_ICMPV6, IPPROTO_IP,
+    IPPROTO_IPV6, IPPROTO_TCP, IPPROTO_UDP, IPV6_MTU_DISCOVER, IP_MTU_DISCOVER, IP_PMTUDISC_DO,
+    IP_PMTUDISC_DONT, IP_PMTUDISC_PROBE, IP_PMTUDISC_WANT, MSG_DONTWAIT, MSG_NOSIGNAL, POLLIN,
+    POLLOUT, RLIMIT_NOFILE, SHUT_RDWR, SOCK_CLOEXEC, SOCK_NONBLOCK, SOCK_STREAM, SOL_SOCKET,
+    SO_ACCEPTCONN, SO_DOMAIN, SO_PROTOCOL, SO_REUSEADDR, SO_TYPE, TCP_REPAIR_OPTIONS,
+    TCP_REPAIR_WINDOW, TCP_TIMESTAMP,
 };
 use std::{
-    io,
-    mem::size_of,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    collections::VecDeque,
+    ffi::{CStr, CString},
+    fmt, fs,
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    mem::{size_of, zeroed, MaybeUninit},
+    net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+    path::{Path, PathBuf},
+    ptr,
+    time::{Duration, Instant},
 };
 
-use crate::syscall;
+use crate::{
+    epoll::{Epoll, Interest, Registration},
+    syscall,
+    tcp::{ConnState, RepairQueue, TcpListener, TcpStream},
+    unix::{UnixListener, UnixStream},
+};
+
+pub mod cmsg;
+pub mod proxy_protocol;
+
+pub use proxy_protocol::read_proxy_header;
+
+/// An owned socket fd. It is closed exactly once: when the `Socket` is dropped, or by
+/// whichever type it is converted into.
+pub(crate) struct Socket {
+    fd: OwnedFd,
+}
+
+impl Socket {
+    /// Hands the fd over to a std socket type, or any other type that owns an fd.
+    pub(crate) fn into_std<T: From<OwnedFd>>(self) -> T {
+        T::from(self.fd)
+    }
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for Socket {
+    fn into_raw_fd(self) -> RawFd {
+        self.fd.into_raw_fd()
+    }
+}
+
+impl FromRawFd for Socket {
+    unsafe fn from_raw_fd(fd: RawFd) -> Socket {
+        Socket {
+            fd: OwnedFd::from_raw_fd(fd),
+        }
+    }
+}
+
+pub(crate) fn create_new_socket(domain: c_int, socket_type: c_int) -> io::Result<Socket> {
+    create_new_socket_with_protocol(domain, socket_type, 0)
+}
 
-pub(crate) fn create_new_socket(domain: c_int, socket_type: c_int) -> io::Result<c_int> {
+pub(crate) fn create_new_socket_with_protocol(
+    domain: c_int,
+    socket_type: c_int,
+    protocol: c_int,
+) -> io::Result<Socket> {
     let socket_type = socket_type | SOCK_NONBLOCK | SOCK_CLOEXEC;
-    syscall!(socket(domain, socket_type, 0))
+    syscall!(socket(domain, socket_type, protocol)).map(|fd| unsafe { Socket::from_raw_fd(fd) })
+}
+
+pub(crate) fn setsockopt<T>(fd: c_int, level: c_int, name: c_int, value: T) -> io::Result<()> {
+    syscall!(setsockopt(
+        fd,
+        level,
+        name,
+        &value as *const T as *const c_void,
+        size_of::<T>() as socklen_t,
+    ))
+    .map(|_| ())
+}
+
+pub(crate) fn getsockopt<T: Copy>(fd: c_int, level: c_int, name: c_int) -> io::Result<T> {
+    let mut value = MaybeUninit::<T>::zeroed();
+    let mut length = size_of::<T>() as socklen_t;
+    syscall!(getsockopt(
+        fd,
+        level,
+        name,
+        value.as_mut_ptr() as *mut c_void,
+        &mut length,
+    ))?;
+    Ok(unsafe { value.assume_init() })
+}
+
+/// `send(2)` with `MSG_NOSIGNAL` always set, so writing to a closed connection fails with
+/// `EPIPE` instead of raising `SIGPIPE`.
+pub(crate) fn send(fd: c_int, buf: &[u8], flags: c_int) -> io::Result<usize> {
+    syscall!(send(
+        fd,
+        buf.as_ptr() as *const c_void,
+        buf.len(),
+        flags | MSG_NOSIGNAL,
+    ))
+    .map(|n| n as usize)
+}
+
+/// The vectored counterpart of [`send`], built on `sendmsg(2)` since `writev(2)` takes no flags.
+pub(crate) fn send_vectored(fd: c_int, bufs: &[IoSlice<'_>], flags: c_int) -> io::Result<usize> {
+    let mut msg: msghdr = unsafe { zeroed() };
+    // `IoSlice` is guaranteed to be ABI compatible with `iovec`.
+    msg.msg_iov = bufs.as_ptr() as *mut iovec;
+    msg.msg_iovlen = bufs.len() as _;
+    syscall!(sendmsg(fd, &msg, flags | MSG_NOSIGNAL)).map(|n| n as usize)
+}
+
+/// A classic BPF instruction, laid out like the kernel's `struct sock_filter`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SockFilter {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+impl SockFilter {
+    pub const fn new(code: u16, jt: u8, jf: u8, k: u32) -> SockFilter {
+        SockFilter { code, jt, jf, k }
+    }
+}
+
+/// Raises the soft `RLIMIT_NOFILE` to `target`, capped at the hard limit, and returns the
+/// resulting soft limit. A limit that is already at least `target` is left alone.
+pub fn raise_nofile_limit(target: u64) -> io::Result<u64> {
+    let mut limit = rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    syscall!(getrlimit(RLIMIT_NOFILE, &mut limit))?;
+
+    let wanted = target.min(limit.rlim_max);
+    if wanted > limit.rlim_cur {
+        limit.rlim_cur = wanted;
+        syscall!(setrlimit(RLIMIT_NOFILE, &limit))?;
+    }
+    Ok(limit.rlim_cur)
+}
+
+pub(crate) fn set_cloexec(fd: c_int, cloexec: bool) -> io::Result<()> {
+    let flags = syscall!(fcntl(fd, F_GETFD))?;
+    let flags = if cloexec {
+        flags | FD_CLOEXEC
+    } else {
+        flags & !FD_CLOEXEC
+    };
+    syscall!(fcntl(fd, F_SETFD, flags)).map(drop)
+}
+
+pub(crate) fn cloexec(fd: c_int) -> io::Result<bool> {
+    syscall!(fcntl(fd, F_GETFD)).map(|flags| flags & FD_CLOEXEC != 0)
+}
+
+/// Converts an optional timeout into milliseconds for `poll`/`epoll_wait`, where `None` blocks
+/// indefinitely. Rounds up so a sub-millisecond remainder doesn't turn into a busy loop.
+pub(crate) fn timeout_ms(timeout: Option<Duration>) -> c_int {
+    match timeout {
+        Some(timeout) => {
+            let ms = timeout.as_nanos().div_ceil(1_000_000);
+            ms.min(c_int::MAX as u128) as c_int
+        }
+        None => -1,
+    }
+}
+
+/// Splits the byte count returned by a vectored read into how many bytes landed in each
+/// buffer. Buffers are filled in order, so every entry is full up to the last non-empty one.
+pub fn distribute_read(bufs: &[IoSliceMut<'_>], n: usize) -> Vec<usize> {
+    let mut remaining = n;
+    bufs.iter()
+        .map(|buf| {
+            let filled = remaining.min(buf.len());
+            remaining -= filled;
+            filled
+        })
+        .collect()
+}
+
+/// A reusable receive buffer that tracks which bytes hold data (`filled`) and which have ever
+/// been written (`init`), so that refilling it never has to zero memory again.
+pub struct RecvBuf {
+    buf: Box<[MaybeUninit<u8>]>,
+    filled: usize,
+    init: usize,
+}
+
+impl RecvBuf {
+    pub fn with_capacity(capacity: usize) -> RecvBuf {
+        let mut buf = Vec::with_capacity(capacity);
+        buf.resize_with(capacity, MaybeUninit::uninit);
+        RecvBuf {
+            buf: buf.into_boxed_slice(),
+            filled: 0,
+            init: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.filled
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filled == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.filled == self.buf.len()
+    }
+
+    pub fn init_len(&self) -> usize {
+        self.init
+    }
+
+    pub fn filled(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.buf.as_ptr() as *const u8, self.filled) }
+    }
+
+    /// Returns the unfilled part of the buffer as initialized memory for use with `Read`,
+    /// zeroing only the bytes that have never been written. Follow up with [`advance`].
+    ///
+    /// [`advance`]: RecvBuf::advance
+    pub fn unfilled_mut(&mut self) -> &mut [u8] {
+        for byte in &mut self.buf[self.init..] {
+            byte.write(0);
+        }
+        self.init = self.buf.len();
+        let unfilled = &mut self.buf[self.filled..];
+        unsafe { std::slice::from_raw_parts_mut(unfilled.as_mut_ptr() as *mut u8, unfilled.len()) }
+    }
+
+    /// Marks `n` more bytes of the unfilled region as filled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if that would extend past the initialized part of the buffer.
+    pub fn advance(&mut self, n: usize) {
+        assert!(
+            self.filled + n <= self.init,
+            "advanced past initialized bytes"
+        );
+        self.filled += n;
+    }
+
+    /// Drops the first `n` filled bytes, moving the rest to the front of the buffer.
+    pub fn consume(&mut self, n: usize) {
+        let n = n.min(self.filled);
+        self.buf.copy_within(n..self.filled, 0);
+        self.filled -= n;
+    }
+
+    /// Empties the buffer without forgetting which bytes are initialized.
+    pub fn clear(&mut self) {
+        self.filled = 0;
+    }
+
+    /// Records that the kernel wrote `n` bytes at the start of the unfilled region.
+    pub(crate) unsafe fn assume_filled(&mut self, n: usize) {
+        self.filled += n;
+        self.init = self.init.max(self.filled);
+    }
+
+    pub(crate) fn unfilled_ptr(&mut self) -> (*mut c_void, usize) {
+        let unfilled = &mut self.buf[self.filled..];
+        (unfilled.as_mut_ptr() as *mut c_void, unfilled.len())
+    }
+}
+
+/// The most buffers handed to a single `writev`, Linux's `IOV_MAX`.
+const MAX_IOVECS: usize = 1024;
+
+enum QueuedBuf {
+    Owned(Vec<u8>),
+    #[cfg(feature = "bytes")]
+    Bytes(bytes::Bytes),
+}
+
+impl QueuedBuf {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            QueuedBuf::Owned(buf) => buf,
+            #[cfg(feature = "bytes")]
+            QueuedBuf::Bytes(buf) => buf,
+        }
+    }
+}
+
+/// A queue of buffers waiting to be written, each owned by the queue until every byte of
+/// it has been written. Buffers are written with vectored writes, so queuing never copies.
+#[derive(Default)]
+pub struct WriteQueue {
+    bufs: VecDeque<QueuedBuf>,
+    /// Bytes of the front buffer that have already been written.
+    offset: usize,
+    len: usize,
+}
+
+impl WriteQueue {
+    pub fn new() -> WriteQueue {
+        WriteQueue::default()
+    }
+
+    pub fn push_owned(&mut self, buf: Vec<u8>) {
+        self.push(QueuedBuf::Owned(buf));
+    }
+
+    #[cfg(feature = "bytes")]
+    pub fn push_bytes(&mut self, buf: bytes::Bytes) {
+        self.push(QueuedBuf::Bytes(buf));
+    }
+
+    fn push(&mut self, buf: QueuedBuf) {
+        let len = buf.as_slice().len();
+        if len > 0 {
+            self.len += len;
+            self.bufs.push_back(buf);
+        }
+    }
+
+    /// The number of bytes still to be written.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of buffers the queue still holds, including a partially written one.
+    pub fn buffers(&self) -> usize {
+        self.bufs.len()
+    }
+
+    /// Writes as much of the queue as a single vectored write accepts, freeing the buffers
+    /// that were written completely. Returns the number of bytes written.
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
+        self.write_capped(writer, MAX_IOVECS)
+    }
+
+    /// Writes until the queue is empty or the writer would block. Returns whether the
+    /// queue was emptied.
+    pub fn flush_to<W: Write>(&mut self, writer: &mut W) -> io::Result<bool> {
+        self.flush_capped(writer, MAX_IOVECS)
+    }
+
+    /// Like [`flush_to`](WriteQueue::flush_to), but hands at most `max_iov` buffers to each
+    /// vectored write. `max_iov` is clamped to between 1 and Linux's `IOV_MAX` of 1024,
+    /// above which `writev` fails with `EINVAL`.
+    pub fn flush_capped<W: Write>(&mut self, writer: &mut W, max_iov: usize) -> io::Result<bool> {
+        let max_iov = max_iov.clamp(1, MAX_IOVECS);
+        while !self.is_empty() {
+            match self.write_capped(writer, max_iov) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Like [`flush_to`](WriteQueue::flush_to), keeping `registration`'s writable interest
+    /// in step with the queue: added while bytes are left waiting on the peer, and removed
+    /// once the queue empties, so the connection isn't woken by writability it has no use
+    /// for. Call it after queuing data and on each writable event.
+    pub fn flush_registered<W: Write>(
+        &mut self,
+        writer: &mut W,
+        epoll: &Epoll,
+        registration: &mut Registration,
+    ) -> io::Result<bool> {
+        let drained = self.flush_to(writer)?;
+        if drained {
+            registration.remove_interest(epoll, Interest::WRITABLE)?;
+        } else {
+            registration.add_interest(epoll, Interest::WRITABLE)?;
+        }
+        Ok(drained)
+    }
+
+    fn write_capped<W: Write>(&mut self, writer: &mut W, max_iov: usize) -> io::Result<usize> {
+        if self.bufs.is_empty() {
+            return Ok(0);
+        }
+        let slices: Vec<IoSlice<'_>> = self
+            .bufs
+            .iter()
+            .take(max_iov)
+            .enumerate()
+            .map(|(i, buf)| match i {
+                0 => IoSlice::new(&buf.as_slice()[self.offset..]),
+                _ => IoSlice::new(buf.as_slice()),
+            })
+            .collect();
+        let n = writer.write_vectored(&slices)?;
+        self.advance(n);
+        Ok(n)
+    }
+
+    fn advance(&mut self, mut n: usize) {
+        self.len -= n;
+        while let Some(front) = self.bufs.front() {
+            let remaining = front.as_slice().len() - self.offset;
+            if n < remaining {
+                self.offset += n;
+                return;
+            }
+            n -= remaining;
+            self.offset = 0;
+            self.bufs.pop_front();
+        }
+    }
+}
+
+/// Sends `buf` to every stream, e.g. to fan a pub/sub message out to its subscribers,
+/// returning each stream's result in the same order. Sends never wait, so a slow or closed
+/// peer doesn't hold up the others: a full send buffer shows up as `WouldBlock`, and a
+/// partial write as a count short of `buf.len()`, leaving the rest to the caller, e.g. to
+/// queue in that stream's [`WriteQueue`].
+pub fn broadcast(streams: &[&TcpStream], buf: &[u8]) -> Vec<io::Result<usize>> {
+    streams
+        .iter()
+        .map(|stream| loop {
+            let result = send(stream.as_raw_fd(), buf, MSG_DONTWAIT);
+            match result {
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                _ => {
+                    stream.record_activity(&result);
+                    break result;
+                }
+            }
+        })
+        .collect()
+}
+
+/// Copies data between `a` and `b` in both directions until both have reached EOF, shutting
+/// down the write side of one stream once the other has nothing more to send. Returns the
+/// number of bytes copied from `a` to `b` and from `b` to `a`.
+pub fn copy_bidirectional(a: &mut TcpStream, b: &mut TcpStream) -> io::Result<(u64, u64)> {
+    let mut a_to_b = CopyBuffer::new();
+    let mut b_to_a = CopyBuffer::new();
+    loop {
+        a_to_b.pump(a, b)?;
+        b_to_a.pump(b, a)?;
+        if a_to_b.is_done() && b_to_a.is_done() {
+            return Ok((a_to_b.transferred, b_to_a.transferred));
+        }
+
+        let mut fds = [
+            pollfd {
+                fd: a.as_raw_fd(),
+                events: a_to_b.read_interest() | b_to_a.write_interest(),
+                revents: 0,
+            },
+            pollfd {
+                fd: b.as_raw_fd(),
+                events: b_to_a.read_interest() | a_to_b.write_interest(),
+                revents: 0,
+            },
+        ];
+        // A stream we're done with keeps reporting POLLHUP, so leave it out entirely.
+        for fd in &mut fds {
+            if fd.events == 0 {
+                fd.fd = -1;
+            }
+        }
+        match syscall!(poll(fds.as_mut_ptr(), fds.len() as nfds_t, -1)) {
+            Err(err) if err.kind() != io::ErrorKind::Interrupted => return Err(err),
+            _ => {}
+        }
+    }
+}
+
+/// One direction of [`copy_bidirectional`].
+struct CopyBuffer {
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+    read_done: bool,
+    shut_down: bool,
+    transferred: u64,
+}
+
+impl CopyBuffer {
+    fn new() -> CopyBuffer {
+        CopyBuffer {
+            buf: vec![0; 16 * 1024].into_boxed_slice(),
+            pos: 0,
+            cap: 0,
+            read_done: false,
+            shut_down: false,
+            transferred: 0,
+        }
+    }
+
+    /// Moves as much data from `src` to `dst` as possible without blocking.
+    fn pump(&mut self, src: &mut TcpStream, dst: &mut TcpStream) -> io::Result<()> {
+        loop {
+            if self.pos == self.cap && !self.read_done {
+                match src.read(&mut self.buf) {
+                    Ok(0) => self.read_done = true,
+                    Ok(n) => {
+                        self.pos = 0;
+                        self.cap = n;
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+
+            while self.pos < self.cap {
+                match dst.write(&self.buf[self.pos..self.cap]) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(n) => {
+                        self.pos += n;
+                        self.transferred += n as u64;
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+            }
+
+            if self.read_done {
+                if !self.shut_down {
+                    dst.shutdown(Shutdown::Write)?;
+                    self.shut_down = true;
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    fn read_interest(&self) -> c_short {
+        if self.pos == self.cap && !self.read_done {
+            POLLIN
+        } else {
+            0
+        }
+    }
+
+    fn write_interest(&self) -> c_short {
+        if self.pos < self.cap {
+            POLLOUT
+        } else {
+            0
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.shut_down
+    }
+}
+
+/// Waits for `events` on `fd` and returns the events that fired, or 0 if the timeout elapsed
+/// or the wait was interrupted by a signal.
+pub(crate) fn poll_fd(
+    fd: c_int,
+    events: c_short,
+    timeout: Option<Duration>,
+) -> io::Result<c_short> {
+    let mut pollfd = pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    match syscall!(poll(&mut pollfd, 1, timeout_ms(timeout))) {
+        Ok(_) => Ok(pollfd.revents),
+        Err(err) if err.kind() == io::ErrorKind::Interrupted => Ok(0),
+        Err(err) => Err(err),
+    }
+}
+
+/// The transport protocol of a socket, as reported by `SO_PROTOCOL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketProtocol {
+    Tcp,
+    Udp,
+    Icmp,
+    IcmpV6,
+    /// Includes 0, which Unix sockets report.
+    Unknown(i32),
+}
+
+impl From<c_int> for SocketProtocol {
+    fn from(protocol: c_int) -> Self {
+        match protocol {
+            IPPROTO_TCP => SocketProtocol::Tcp,
+            IPPROTO_UDP => SocketProtocol::Udp,
+            IPPROTO_ICMP => SocketProtocol::Icmp,
+            IPPROTO_ICMPV6 => SocketProtocol::IcmpV6,
+            other => SocketProtocol::Unknown(other),
+        }
+    }
+}
+
+/// The socket's address family, e.g. `AF_INET6` or `AF_UNIX`.
+pub fn socket_domain(fd: RawFd) -> io::Result<c_int> {
+    getsockopt(fd, SOL_SOCKET, SO_DOMAIN)
+}
+
+/// The socket's type, e.g. `SOCK_STREAM` or `SOCK_DGRAM`.
+pub fn socket_type(fd: RawFd) -> io::Result<c_int> {
+    getsockopt(fd, SOL_SOCKET, SO_TYPE)
+}
+
+/// Together with [`socket_domain`] and [`socket_type`], tells which wrapper an inherited fd
+/// belongs in.
+pub fn socket_protocol(fd: RawFd) -> io::Result<SocketProtocol> {
+    getsockopt::<c_int>(fd, SOL_SOCKET, SO_PROTOCOL).map(SocketProtocol::from)
+}
+
+// Not exported by libc.
+const IP_MTU: c_int = 14;
+const IPV6_MTU: c_int = 24;
+
+/// How a socket uses path MTU discovery, set with `IP_MTU_DISCOVER`, or
+/// `IPV6_MTU_DISCOVER` on IPv6 sockets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MtuDiscover {
+    /// Never sets the don't-fragment bit, so routers may fragment packets on the way.
+    Dont,
+    /// Follows the path MTU, but fragments datagrams larger than it locally. The default
+    /// for new sockets, TCP included, unless `net.ipv4.ip_no_pmtu_disc` is set.
+    Want,
+    /// Always sets the don't-fragment bit; datagrams larger than the path MTU fail with
+    /// `EMSGSIZE`.
+    Do,
+    /// Sets the don't-fragment bit but ignores the path MTU, for probing it with larger
+    /// datagrams.
+    Probe,
+}
+
+impl MtuDiscover {
+    fn as_raw(self) -> c_int {
+        match self {
+            MtuDiscover::Dont => IP_PMTUDISC_DONT,
+            MtuDiscover::Want => IP_PMTUDISC_WANT,
+            MtuDiscover::Do => IP_PMTUDISC_DO,
+            MtuDiscover::Probe => IP_PMTUDISC_PROBE,
+        }
+    }
+
+    fn from_raw(mode: c_int) -> io::Result<MtuDiscover> {
+        match mode {
+            IP_PMTUDISC_DONT => Ok(MtuDiscover::Dont),
+            IP_PMTUDISC_WANT => Ok(MtuDiscover::Want),
+            IP_PMTUDISC_DO => Ok(MtuDiscover::Do),
+            IP_PMTUDISC_PROBE => Ok(MtuDiscover::Probe),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown path MTU discovery mode {}", other),
+            )),
+        }
+    }
+}
+
+/// The MTU option level and name for the socket's family. The IPv6 discovery modes share
+/// their values with the IPv4 ones.
+fn mtu_options(fd: c_int) -> io::Result<(c_int, c_int, c_int)> {
+    match socket_domain(fd)? {
+        AF_INET6 => Ok((IPPROTO_IPV6, IPV6_MTU, IPV6_MTU_DISCOVER)),
+        _ => Ok((IPPROTO_IP, IP_MTU, IP_MTU_DISCOVER)),
+    }
+}
+
+/// The path MTU the kernel currently knows for a connected socket.
+pub(crate) fn path_mtu(fd: c_int) -> io::Result<u32> {
+    let (level, mtu, _) = mtu_options(fd)?;
+    getsockopt::<c_int>(fd, level, mtu).map(|mtu| mtu as u32)
+}
+
+pub(crate) fn set_mtu_discover(fd: c_int, mode: MtuDiscover) -> io::Result<()> {
+    let (level, _, discover) = mtu_options(fd)?;
+    setsockopt(fd, level, discover, mode.as_raw())
+}
+
+pub(crate) fn mtu_discover(fd: c_int) -> io::Result<MtuDiscover> {
+    let (level, _, discover) = mtu_options(fd)?;
+    getsockopt::<c_int>(fd, level, discover).and_then(MtuDiscover::from_raw)
+}
+
+pub fn if_nametoindex(name: &str) -> io::Result<u32> {
+    let name = CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "interface name contains nul"))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            io::Error::last_os_error(),
+        )),
+        index => Ok(index),
+    }
+}
+
+pub fn if_indextoname(index: u32) -> io::Result<String> {
+    let mut name = [0 as c_char; IF_NAMESIZE];
+    if unsafe { libc::if_indextoname(index, name.as_mut_ptr()) }.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            io::Error::last_os_error(),
+        ));
+    }
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+/// Parses `[addr%zone]:port`, where the zone is an interface name or a numeric scope id.
+pub fn parse_scoped_v6(s: &str) -> io::Result<SocketAddrV6> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid IPv6 socket address");
+
+    let (host, port) = s
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once("]:"))
+        .ok_or_else(invalid)?;
+    let port: u16 = port.parse().map_err(|_| invalid())?;
+    let (ip, scope_id) = match host.split_once('%') {
+        Some((ip, zone)) => match zone.parse() {
+            Ok(scope_id) => (ip, scope_id),
+            Err(_) => (ip, if_nametoindex(zone)?),
+        },
+        None => (host, 0),
+    };
+    let ip: Ipv6Addr = ip.parse().map_err(|_| invalid())?;
+    Ok(SocketAddrV6::new(ip, port, 0, scope_id))
+}
+
+/// Formats `addr` as `[addr%zone]:port`, naming the zone after its interface when it exists.
+pub fn format_scoped_v6(addr: &SocketAddrV6) -> String {
+    match addr.scope_id() {
+        0 => format!("[{}]:{}", addr.ip(), addr.port()),
+        scope_id => match if_indextoname(scope_id) {
+            Ok(zone) => format!("[{}%{}]:{}", addr.ip(), zone, addr.port()),
+            Err(_) => format!("[{}%{}]:{}", addr.ip(), scope_id, addr.port()),
+        },
+    }
+}
+
+/// Formats `addr` the way its `Display` impl does, straight into `out`. Unlike going
+/// through `to_string`, nothing is allocated.
+pub fn write_addr(out: &mut impl Write, addr: &SocketAddr) -> io::Result<()> {
+    write!(out, "{}", addr)
+}
+
+/// The longest formatted address, `[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff%4294967295]:65535`.
+const MAX_ADDR_LEN: usize = 58;
+
+/// A formatted socket address held on the stack, for logging peers without allocating.
+#[derive(Clone, Copy)]
+pub struct AddrBuf {
+    buf: [u8; MAX_ADDR_LEN],
+    len: usize,
+}
+
+impl AddrBuf {
+    pub fn new(addr: &SocketAddr) -> AddrBuf {
+        let mut buf = [0; MAX_ADDR_LEN];
+        let mut rest = &mut buf[..];
+        write_addr(&mut rest, addr).expect("formatted address fits in MAX_ADDR_LEN");
+        let len = MAX_ADDR_LEN - rest.len();
+        AddrBuf { buf, len }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever holds the output of `Display`.
+        std::str::from_utf8(&self.buf[..self.len]).unwrap()
+    }
+}
+
+impl fmt::Display for AddrBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for AddrBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
 }
 
 /// Used in converting Rust level SocketAddr* types into their system representation
@@ -65,6 +853,44 @@ pub(crate) fn socket_addr(addr: &SocketAddr) -> (SocketAddrCRepr, socklen_t) {
     }
 }
 
+/// The raw `sockaddr_in` or `sockaddr_in6` the kernel uses for `addr`, the inverse of
+/// [`parse_sockaddr`].
+pub fn sockaddr_bytes(addr: &SocketAddr) -> Vec<u8> {
+    let (raw_addr, raw_addr_length) = socket_addr(addr);
+    let bytes = unsafe {
+        std::slice::from_raw_parts(raw_addr.as_ptr() as *const u8, raw_addr_length as usize)
+    };
+    bytes.to_vec()
+}
+
+/// Parses a raw `sockaddr_in` or `sockaddr_in6`, as received from the kernel or another
+/// process. Buffers too short for their address family, or longer than any socket address,
+/// are rejected with `InvalidInput`.
+pub fn parse_sockaddr(bytes: &[u8]) -> io::Result<SocketAddr> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    if bytes.len() > size_of::<sockaddr_storage>() {
+        return Err(invalid("socket address too long"));
+    }
+    let family = match bytes {
+        [a, b, ..] => sa_family_t::from_ne_bytes([*a, *b]) as c_int,
+        _ => return Err(invalid("socket address too short")),
+    };
+    let min_length = match family {
+        AF_INET => size_of::<sockaddr_in>(),
+        AF_INET6 => size_of::<sockaddr_in6>(),
+        _ => return Err(invalid("unsupported address family")),
+    };
+    if bytes.len() < min_length {
+        return Err(invalid("socket address too short"));
+    }
+
+    let mut storage = MaybeUninit::<sockaddr_storage>::zeroed();
+    unsafe {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), storage.as_mut_ptr() as *mut u8, bytes.len());
+        to_socket_addr(storage.as_ptr())
+    }
+}
+
 pub(crate) unsafe fn to_socket_addr(storage: *const sockaddr_storage) -> io::Result<SocketAddr> {
     match (*storage).ss_family as c_int {
         AF_INET => {
@@ -87,3 +913,1066 @@ pub(crate) unsafe fn to_socket_addr(storage: *const sockaddr_storage) -> io::Res
         _ => Err(io::ErrorKind::InvalidInput.into()),
     }
 }
+
+/// Cleanup a listener runs when it is dropped. Listeners declare it before their socket so
+/// it runs while the fd is still open, and disarm it when the fd is taken by `into_raw_fd`.
+#[derive(Default)]
+pub(crate) struct DropCleanup {
+    pub(crate) shutdown: Option<RawFd>,
+    pub(crate) unlink: Option<PathBuf>,
+}
+
+impl DropCleanup {
+    pub(crate) fn disarm(&mut self) {
+        self.shutdown = None;
+        self.unlink = None;
+    }
+}
+
+impl Drop for DropCleanup {
+    fn drop(&mut self) {
+        if let Some(fd) = self.shutdown {
+            let _ = syscall!(shutdown(fd, SHUT_RDWR));
+        }
+        if let Some(path) = &self.unlink {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Limit on the size of a handoff manifest. Even the most fds one message can carry fit
+/// in a fraction of it.
+const MAX_MANIFEST: usize = 64 * 1024;
+
+/// The old process's side of a hot restart: waits on a Unix socket for its replacement
+/// and passes it the listening sockets, see [`HandoffClient`].
+///
+/// `bind` creates the socket file and dropping the server removes it, so once the handoff
+/// is done the new process can bind the same path for the restart after it. A file left
+/// behind by a process that crashed still has to be removed before binding.
+pub struct HandoffServer {
+    listener: UnixListener,
+}
+
+impl HandoffServer {
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<HandoffServer> {
+        let mut listener = UnixListener::bind(path)?;
+        listener.set_unlink_on_drop(true);
+        Ok(HandoffServer { listener })
+    }
+
+    /// Waits up to `timeout` for the new process to connect, then sends it `listeners`.
+    /// They stay open in this process too, so stop accepting on them once this returns.
+    pub fn hand_off(&self, listeners: &[&TcpListener], timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let stream = loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    wait_until(self.listener.as_raw_fd(), POLLIN, deadline)?
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        };
+
+        // A length-prefixed manifest with one line per fd, e.g. "tcp 0.0.0.0:80".
+        let mut manifest = String::new();
+        for listener in listeners {
+            manifest.push_str(&format!("tcp {}\n", listener.local_addr()?));
+        }
+        if manifest.len() > MAX_MANIFEST {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too many listeners to hand off",
+            ));
+        }
+        let mut msg = (manifest.len() as u32).to_be_bytes().to_vec();
+        msg.extend_from_slice(manifest.as_bytes());
+        let fds: Vec<RawFd> = listeners.iter().map(|l| l.as_raw_fd()).collect();
+
+        let mut sent = 0;
+        while sent < msg.len() {
+            // The fds travel with the first byte only.
+            let fds = if sent == 0 { &fds[..] } else { &[] };
+            match stream.send_with_fds(&msg[sent..], fds) {
+                Ok(n) => sent += n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    wait_until(stream.as_raw_fd(), POLLOUT, deadline)?
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The new process's side of a hot restart, receiving the listeners of a [`HandoffServer`].
+pub struct HandoffClient {
+    stream: UnixStream,
+}
+
+impl HandoffClient {
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<HandoffClient> {
+        UnixStream::connect(path).map(|stream| HandoffClient { stream })
+    }
+
+    /// Receives the handed off listeners in the order they were sent, waiting at most
+    /// `timeout`. Each fd is checked to be a listening socket bound to the address the
+    /// manifest gives for it.
+    pub fn receive(&self, timeout: Duration) -> io::Result<Vec<TcpListener>> {
+        let deadline = Instant::now() + timeout;
+        let mut fds = Vec::new();
+        let mut msg = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            if let Some(prefix) = msg.get(..4) {
+                let length = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+                if length > MAX_MANIFEST {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "handoff manifest too large",
+                    ));
+                }
+                if msg.len() >= 4 + length {
+                    break;
+                }
+            }
+            match self.stream.recv_with_fds(&mut buf, &mut fds) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "handoff connection closed before the manifest arrived",
+                    ))
+                }
+                Ok(n) => msg.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    wait_until(self.stream.as_raw_fd(), POLLIN, deadline)?
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let manifest =
+            std::str::from_utf8(&msg[4..]).map_err(|_| invalid("handoff manifest isn't UTF-8"))?;
+        let addrs = manifest
+            .lines()
+            .map(|line| match line.strip_prefix("tcp ") {
+                Some(addr) => addr.parse::<SocketAddr>().ok(),
+                None => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| invalid("malformed handoff manifest"))?;
+        if addrs.len() != fds.len() {
+            return Err(invalid("handoff manifest doesn't match the fds received"));
+        }
+
+        addrs
+            .into_iter()
+            .zip(fds)
+            .map(|(addr, fd)| {
+                if getsockopt::<c_int>(fd.as_raw_fd(), SOL_SOCKET, SO_ACCEPTCONN)? == 0 {
+                    return Err(invalid("handed off fd isn't a listening socket"));
+                }
+                let listener = TcpListener::from_std(std::net::TcpListener::from(fd));
+                if listener.local_addr()? != addr {
+                    return Err(invalid(
+                        "handed off listener isn't bound to its manifest address",
+                    ));
+                }
+                Ok(listener)
+            })
+            .collect()
+    }
+}
+
+/// Waits for `events` on `fd`, failing with `TimedOut` once `deadline` has passed.
+pub(crate) fn wait_until(fd: c_int, events: c_short, deadline: Instant) -> io::Result<()> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(io::ErrorKind::TimedOut.into());
+    }
+    poll_fd(fd, events, Some(remaining)).map(drop)
+}
+
+/// What a handshake step returned by the closure given to [`drive_handshake`] needs next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handshake<T> {
+    WantRead,
+    WantWrite,
+    Done(T),
+}
+
+/// Runs a handshake over a non-blocking socket: calls `step` to advance the caller's state
+/// machine, and whenever it asks to read or write, waits for `stream` to become readable or
+/// writable before calling it again. Fails with `TimedOut` if the handshake hasn't finished
+/// by `deadline`, however far along it is.
+///
+/// `step` should do as much I/O as it can without blocking, returning `WantRead` or
+/// `WantWrite` on `WouldBlock`.
+pub fn drive_handshake<S: AsRawFd, T>(
+    stream: &mut S,
+    deadline: Instant,
+    mut step: impl FnMut(&mut S) -> io::Result<Handshake<T>>,
+) -> io::Result<T> {
+    loop {
+        let events = match step(stream)? {
+            Handshake::WantRead => POLLIN,
+            Handshake::WantWrite => POLLOUT,
+            Handshake::Done(value) => return Ok(value),
+        };
+        wait_until(stream.as_raw_fd(), events, deadline)?;
+    }
+}
+
+// Not exported by libc.
+const TCPOPT_MSS: u32 = 2;
+const TCPOPT_WINDOW: u32 = 3;
+const TCPOPT_SACK_PERM: u32 = 4;
+const TCPOPT_TIMESTAMP: u32 = 8;
+
+/// Laid out like the kernel's `struct tcp_repair_opt`.
+#[repr(C)]
+struct RepairOpt {
+    code: u32,
+    value: u32,
+}
+
+/// Rebuilds a connection from a [`ConnState`] taken with
+/// [`TcpStream::export_state`], resuming it where the exported socket left off. The
+/// exported socket must have been closed first, since the new one binds its address.
+///
+/// The socket is created in repair mode, so nothing is sent to the peer until the state
+/// has been restored and repair mode is left. This requires `CAP_NET_ADMIN`, see
+/// [`TcpStream::repair_mode`].
+pub fn import_state(state: ConnState) -> io::Result<TcpStream> {
+    let domain = match state.local_addr {
+        SocketAddr::V4(_) => AF_INET,
+        SocketAddr::V6(_) => AF_INET6,
+    };
+    let stream = TcpStream::from_std(create_new_socket(domain, SOCK_STREAM)?.into_std());
+    let fd = stream.as_raw_fd();
+    stream.repair_mode(true)?;
+
+    // Sequence numbers can only be set before connecting, and are those of the start of
+    // each queue, as the queues are filled in afterwards.
+    stream.set_repair_queue(RepairQueue::Send)?;
+    stream.set_queue_seq(state.send_seq.wrapping_sub(state.send_queue.len() as u32))?;
+    stream.set_repair_queue(RepairQueue::Recv)?;
+    stream.set_queue_seq(state.recv_seq.wrapping_sub(state.recv_queue.len() as u32))?;
+
+    setsockopt(fd, SOL_SOCKET, SO_REUSEADDR, 1 as c_int)?;
+    let (raw_addr, raw_addr_length) = socket_addr(&state.local_addr);
+    syscall!(bind(fd, raw_addr.as_ptr(), raw_addr_length))?;
+    // In repair mode this skips the handshake and leaves the socket established.
+    let (raw_addr, raw_addr_length) = socket_addr(&state.peer_addr);
+    syscall!(connect(fd, raw_addr.as_ptr(), raw_addr_length))?;
+
+    let mut opts = vec![RepairOpt {
+        code: TCPOPT_MSS,
+        value: state.mss,
+    }];
+    if let Some((snd_wscale, rcv_wscale)) = state.wscale {
+        opts.push(RepairOpt {
+            code: TCPOPT_WINDOW,
+            value: snd_wscale as u32 | (rcv_wscale as u32) << 16,
+        });
+    }
+    if state.sack {
+        opts.push(RepairOpt {
+            code: TCPOPT_SACK_PERM,
+            value: 0,
+        });
+    }
+    if state.timestamp.is_some() {
+        opts.push(RepairOpt {
+            code: TCPOPT_TIMESTAMP,
+            value: 0,
+        });
+    }
+    syscall!(setsockopt(
+        fd,
+        IPPROTO_TCP,
+        TCP_REPAIR_OPTIONS,
+        opts.as_ptr() as *const c_void,
+        (opts.len() * size_of::<RepairOpt>()) as socklen_t,
+    ))?;
+    if let Some(timestamp) = state.timestamp {
+        setsockopt(fd, IPPROTO_TCP, TCP_TIMESTAMP, timestamp)?;
+    }
+
+    // The window is checked against the end of the receive queue, so it goes in after it.
+    stream.set_repair_queue(RepairQueue::Recv)?;
+    fill_repair_queue(fd, &state.recv_queue)?;
+    setsockopt(fd, IPPROTO_TCP, TCP_REPAIR_WINDOW, state.window)?;
+    stream.set_repair_queue(RepairQueue::Send)?;
+    fill_repair_queue(fd, &state.send_queue)?;
+
+    stream.repair_mode(false)?;
+    Ok(stream)
+}
+
+fn fill_repair_queue(fd: c_int, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        let n = send(fd, data, 0)?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        data = &data[n..];
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::epoll::Token;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn recv_buf_tracks_filled_and_initialized_bytes() {
+        let mut buf = RecvBuf::with_capacity(8);
+        assert!(buf.is_empty());
+        assert_eq!(buf.init_len(), 0);
+
+        let unfilled = buf.unfilled_mut();
+        assert_eq!(unfilled.len(), 8);
+        unfilled[..3].copy_from_slice(b"abc");
+        buf.advance(3);
+        assert_eq!(buf.filled(), b"abc");
+        assert_eq!(buf.init_len(), 8);
+
+        buf.unfilled_mut()[..5].copy_from_slice(b"defgh");
+        buf.advance(5);
+        assert!(buf.is_full());
+        assert_eq!(buf.filled(), b"abcdefgh");
+
+        buf.consume(2);
+        assert_eq!(buf.filled(), b"cdefgh");
+        assert_eq!(buf.unfilled_mut().len(), 2);
+        // Consuming more than is filled empties the buffer.
+        buf.consume(100);
+        assert!(buf.is_empty());
+
+        // Bytes written before stay initialized, so they aren't zeroed again.
+        buf.clear();
+        assert_eq!(buf.init_len(), 8);
+        assert_eq!(buf.unfilled_mut()[..2], *b"cd");
+    }
+
+    #[test]
+    #[should_panic(expected = "advanced past initialized bytes")]
+    fn recv_buf_refuses_to_advance_past_initialized_bytes() {
+        let mut buf = RecvBuf::with_capacity(8);
+        buf.advance(1);
+    }
+
+    #[test]
+    fn hand_off_keeps_accepting_on_the_receiver() {
+        let path = std::env::temp_dir().join(format!("rio-handoff-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = HandoffServer::bind(&path).unwrap();
+        let old = thread::spawn(move || {
+            server.hand_off(&[&listener], TIMEOUT).unwrap();
+        });
+        let received = HandoffClient::connect(&path)
+            .unwrap()
+            .receive(TIMEOUT)
+            .unwrap();
+        // The old side has closed its copy of the listener by now.
+        old.join().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].local_addr().unwrap(), addr);
+
+        for _ in 0..2 {
+            let client = TcpStream::connect(addr).unwrap();
+            poll_fd(received[0].as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+            let (_stream, peer) = received[0].accept().unwrap();
+            assert_eq!(peer, client.local_addr().unwrap());
+        }
+
+        // The socket file went away with the old server, so the next restart can bind it.
+        assert!(!path.exists());
+        drop(HandoffServer::bind(&path).unwrap());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn receive_rejects_an_oversized_manifest() {
+        let path = std::env::temp_dir().join(format!("rio-manifest-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut server = UnixListener::bind(&path).unwrap();
+        server.set_unlink_on_drop(true);
+
+        let client = HandoffClient::connect(&path).unwrap();
+        poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        let (mut stream, _) = server.accept().unwrap();
+        // Claims a manifest of almost 4 GiB, of which only the start ever arrives.
+        stream.write_all(&u32::MAX.to_be_bytes()).unwrap();
+        stream.write_all(b"tcp ").unwrap();
+
+        let err = client.receive(TIMEOUT).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        poll_fd(listener.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        poll_fd(client.as_raw_fd(), POLLOUT, Some(TIMEOUT)).unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn import_state_resumes_an_exported_connection() {
+        // The restored socket rebinds the client's address once it is closed, so the port
+        // is taken from below the ephemeral range, where parallel tests can't grab it.
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = TcpStream::connect_from_range(
+            [127, 0, 0, 3].into(),
+            30000..=32767,
+            listener.local_addr().unwrap(),
+        )
+        .unwrap();
+        poll_fd(listener.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        poll_fd(client.as_raw_fd(), POLLOUT, Some(TIMEOUT)).unwrap();
+        server.send_with_flags(b"queued", 0).unwrap();
+        poll_fd(client.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        let state = match client.export_state() {
+            Ok(state) => state,
+            // Repair mode needs CAP_NET_ADMIN.
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => return,
+            Err(err) => panic!("export_state failed: {}", err),
+        };
+        assert_eq!(state.local_addr, client.local_addr().unwrap());
+        assert_eq!(state.peer_addr, server.local_addr().unwrap());
+        assert_eq!(state.recv_queue, b"queued");
+        // Closed in repair mode, so the peer doesn't notice.
+        drop(client);
+
+        let restored = import_state(state).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(restored.read_timeout_once(&mut buf, TIMEOUT).unwrap(), 6);
+        assert_eq!(&buf[..6], b"queued");
+
+        restored.send_with_flags(b"ping", 0).unwrap();
+        assert_eq!(server.read_timeout_once(&mut buf, TIMEOUT).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ping");
+        server.send_with_flags(b"pong", 0).unwrap();
+        assert_eq!(restored.read_timeout_once(&mut buf, TIMEOUT).unwrap(), 4);
+        assert_eq!(&buf[..4], b"pong");
+    }
+
+    #[test]
+    fn export_state_requires_an_established_connection() {
+        let (client, server) = tcp_pair();
+        drop(server);
+        assert_eq!(client.read_timeout_once(&mut [0; 1], TIMEOUT).unwrap(), 0);
+        let err = client.export_state().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn distribute_read_fills_buffers_in_order() {
+        let cases: &[(&[usize], usize, &[usize])] = &[
+            (&[4, 4], 0, &[0, 0]),
+            (&[4, 4, 4], 6, &[4, 2, 0]),
+            (&[4, 4, 4], 12, &[4, 4, 4]),
+            (&[4, 0, 4], 6, &[4, 0, 2]),
+            (&[0, 4, 0, 0, 4], 8, &[0, 4, 0, 0, 4]),
+            (&[], 0, &[]),
+        ];
+        for &(lens, n, expected) in cases {
+            let mut storage: Vec<Vec<u8>> = lens.iter().map(|&len| vec![0; len]).collect();
+            let bufs: Vec<_> = storage.iter_mut().map(|buf| IoSliceMut::new(buf)).collect();
+            assert_eq!(distribute_read(&bufs, n), expected, "{lens:?} with n = {n}");
+        }
+    }
+
+    #[test]
+    fn interface_names_and_indexes_round_trip() {
+        let lo = if_nametoindex("lo").unwrap();
+        assert!(lo > 0);
+        assert_eq!(if_indextoname(lo).unwrap(), "lo");
+
+        let err = if_nametoindex("rio-missing0").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = if_nametoindex("l\0o").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = if_indextoname(u32::MAX).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn scoped_v6_addresses_resolve_the_zone() {
+        let lo = if_nametoindex("lo").unwrap();
+        let addr = parse_scoped_v6("[fe80::1%lo]:8080").unwrap();
+        assert_eq!(*addr.ip(), "fe80::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(addr.port(), 8080);
+        assert_eq!(addr.scope_id(), lo);
+        assert_eq!(format_scoped_v6(&addr), "[fe80::1%lo]:8080");
+
+        let numeric = parse_scoped_v6(&format!("[fe80::1%{lo}]:8080")).unwrap();
+        assert_eq!(numeric, addr);
+        let unscoped = parse_scoped_v6("[::1]:80").unwrap();
+        assert_eq!(unscoped.scope_id(), 0);
+        assert_eq!(format_scoped_v6(&unscoped), "[::1]:80");
+        // A scope id without an interface is kept as a number.
+        let orphan = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 80, 0, u32::MAX);
+        assert_eq!(format_scoped_v6(&orphan), "[::1%4294967295]:80");
+
+        let err = parse_scoped_v6("[fe80::1%rio-missing0]:80").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        for bad in [
+            "fe80::1%lo:80",
+            "[fe80::1%lo]",
+            "[fe80::1%lo]:port",
+            "[nope%lo]:80",
+        ] {
+            let err = parse_scoped_v6(bad).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{bad}");
+        }
+
+        // A listener bound through a scoped address on `lo` accepts connections.
+        let addr = parse_scoped_v6("[::1%lo]:0").unwrap();
+        let listener = TcpListener::bind(addr.into()).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        poll_fd(client.as_raw_fd(), POLLOUT, Some(TIMEOUT)).unwrap();
+        assert!(client.take_error().unwrap().is_none());
+    }
+
+    /// Sends `data` and shuts down the write side while reading everything up to EOF.
+    fn exchange(stream: &TcpStream, data: &[u8]) -> Vec<u8> {
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut data = data;
+                while !data.is_empty() {
+                    match stream.send_with_flags(data, 0) {
+                        Ok(n) => data = &data[n..],
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                            poll_fd(stream.as_raw_fd(), POLLOUT, Some(TIMEOUT)).unwrap();
+                        }
+                        Err(err) => panic!("{err}"),
+                    }
+                }
+                stream.shutdown(Shutdown::Write).unwrap();
+            });
+            let mut received = Vec::new();
+            let mut buf = [0; 16 * 1024];
+            loop {
+                match stream.read_timeout_once(&mut buf, TIMEOUT).unwrap() {
+                    0 => return received,
+                    n => received.extend_from_slice(&buf[..n]),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn copy_bidirectional_proxies_both_directions_to_eof() {
+        let (client, mut a) = tcp_pair();
+        let (mut b, upstream) = tcp_pair();
+        let request: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+        let response = b"a short response".to_vec();
+
+        let proxy = thread::spawn(move || copy_bidirectional(&mut a, &mut b));
+        let (at_upstream, at_client) = thread::scope(|scope| {
+            let upstream = scope.spawn(|| exchange(&upstream, &response));
+            let client = scope.spawn(|| exchange(&client, &request));
+            (upstream.join().unwrap(), client.join().unwrap())
+        });
+        assert!(at_upstream == request);
+        assert_eq!(at_client, response);
+        assert_eq!(
+            proxy.join().unwrap().unwrap(),
+            (request.len() as u64, response.len() as u64)
+        );
+    }
+
+    #[test]
+    fn raise_nofile_limit_stays_within_the_hard_limit() {
+        let mut limit = rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        syscall!(getrlimit(RLIMIT_NOFILE, &mut limit)).unwrap();
+
+        // A lower target leaves the limit alone.
+        assert_eq!(raise_nofile_limit(0).unwrap(), limit.rlim_cur);
+        assert_eq!(raise_nofile_limit(u64::MAX).unwrap(), limit.rlim_max);
+        let mut raised = limit;
+        syscall!(getrlimit(RLIMIT_NOFILE, &mut raised)).unwrap();
+        assert_eq!(raised.rlim_cur, limit.rlim_max);
+
+        syscall!(setrlimit(RLIMIT_NOFILE, &limit)).unwrap();
+    }
+
+    /// A writer that takes at most `per_write` bytes per call and would block once `budget`
+    /// bytes have been written, recording how many slices each vectored write was given.
+    struct ThrottledWriter {
+        written: Vec<u8>,
+        per_write: usize,
+        budget: usize,
+        slices_per_call: Vec<usize>,
+    }
+
+    impl ThrottledWriter {
+        fn new(per_write: usize, budget: usize) -> ThrottledWriter {
+            ThrottledWriter {
+                written: Vec::new(),
+                per_write,
+                budget,
+                slices_per_call: Vec::new(),
+            }
+        }
+    }
+
+    impl Write for ThrottledWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.slices_per_call.push(bufs.len());
+            let limit = self.per_write.min(self.budget);
+            if limit == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let before = self.written.len();
+            for buf in bufs {
+                let take = buf.len().min(limit - (self.written.len() - before));
+                self.written.extend_from_slice(&buf[..take]);
+            }
+            let n = self.written.len() - before;
+            self.budget -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_queue_resumes_partial_writes() {
+        let mut queue = WriteQueue::new();
+        queue.push_owned(b"hello ".to_vec());
+        queue.push_owned(Vec::new());
+        queue.push_owned(b"big ".to_vec());
+        queue.push_owned(b"world".to_vec());
+        assert_eq!((queue.len(), queue.buffers()), (15, 3));
+
+        // Stops partway into the second buffer.
+        let mut writer = ThrottledWriter::new(8, usize::MAX);
+        assert_eq!(queue.write_to(&mut writer).unwrap(), 8);
+        assert_eq!((queue.len(), queue.buffers()), (7, 2));
+        assert_eq!(writer.slices_per_call, [3]);
+
+        // Continues from the offset, but runs out of room.
+        writer.budget = 4;
+        assert!(!queue.flush_to(&mut writer).unwrap());
+        assert_eq!((queue.len(), queue.buffers()), (3, 1));
+
+        writer.budget = usize::MAX;
+        assert!(queue.flush_to(&mut writer).unwrap());
+        assert!(queue.is_empty());
+        assert_eq!(queue.buffers(), 0);
+        assert_eq!(writer.written, b"hello big world");
+        assert_eq!(queue.write_to(&mut writer).unwrap(), 0);
+    }
+
+    #[test]
+    fn socket_identity_is_read_from_the_fd() {
+        let listener = TcpListener::bind("[::1]:0".parse().unwrap()).unwrap();
+        let fd = listener.as_raw_fd();
+        assert_eq!(socket_domain(fd).unwrap(), AF_INET6);
+        assert_eq!(socket_type(fd).unwrap(), SOCK_STREAM);
+        assert_eq!(socket_protocol(fd).unwrap(), SocketProtocol::Tcp);
+
+        let udp = crate::udp::UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let fd = udp.as_raw_fd();
+        assert_eq!(socket_domain(fd).unwrap(), AF_INET);
+        assert_eq!(socket_type(fd).unwrap(), libc::SOCK_DGRAM);
+        assert_eq!(socket_protocol(fd).unwrap(), SocketProtocol::Udp);
+
+        let (unix, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        assert_eq!(socket_domain(unix.as_raw_fd()).unwrap(), libc::AF_UNIX);
+        assert_eq!(
+            socket_protocol(unix.as_raw_fd()).unwrap(),
+            SocketProtocol::Unknown(0)
+        );
+
+        let file = fs::File::open("/dev/null").unwrap();
+        let err = socket_protocol(file.as_raw_fd()).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTSOCK));
+    }
+
+    /// The inode behind `fd`, if it is open. Other tests may reuse the number as soon as it
+    /// is closed, so a closed fd is told apart by its inode rather than by being invalid.
+    fn inode(fd: RawFd) -> Option<libc::ino_t> {
+        let mut stat = MaybeUninit::<libc::stat>::uninit();
+        syscall!(fstat(fd, stat.as_mut_ptr()))
+            .ok()
+            .map(|_| unsafe { stat.assume_init() }.st_ino)
+    }
+
+    #[test]
+    fn socket_fds_are_closed_by_their_last_owner_only() {
+        let socket = create_new_socket(AF_INET, SOCK_STREAM).unwrap();
+        let fd = socket.as_raw_fd();
+        let ino = inode(fd);
+        assert!(ino.is_some());
+        drop(socket);
+        assert_ne!(inode(fd), ino);
+
+        // Handing the fd over doesn't close it; the new owner does.
+        let socket = create_new_socket(AF_INET, SOCK_STREAM).unwrap();
+        let fd = socket.as_raw_fd();
+        let ino = inode(fd);
+        let std_socket: std::net::TcpStream = socket.into_std();
+        assert_eq!(inode(fd), ino);
+        drop(std_socket);
+        assert_ne!(inode(fd), ino);
+
+        let socket = create_new_socket(AF_INET, SOCK_STREAM).unwrap();
+        let ino = inode(socket.as_raw_fd());
+        let fd = socket.into_raw_fd();
+        assert_eq!(inode(fd), ino);
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        drop(socket);
+        assert_ne!(inode(fd), ino);
+
+        // The same holds for the wrappers built on it, e.g. after a failed bind.
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let fd = listener.as_raw_fd();
+        let ino = inode(fd);
+        let addr = listener.local_addr().unwrap();
+        let config = crate::tcp::ListenerConfig {
+            reuseaddr: false,
+            ..Default::default()
+        };
+        let err = TcpListener::bind_config(addr, &config).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert_eq!(inode(fd), ino);
+        drop(listener);
+        assert_ne!(inode(fd), ino);
+    }
+
+    #[test]
+    fn parse_sockaddr_rejects_malformed_input() {
+        let v4 = sockaddr_bytes(&"127.0.0.1:80".parse().unwrap());
+        let v6 = sockaddr_bytes(&"[::1]:80".parse().unwrap());
+        for bytes in [&v4, &v6] {
+            for len in 0..bytes.len() {
+                let err = parse_sockaddr(&bytes[..len]).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{len} bytes");
+            }
+        }
+
+        let mut unix = v6.clone();
+        unix[..2].copy_from_slice(&(libc::AF_UNIX as sa_family_t).to_ne_bytes());
+        let err = parse_sockaddr(&unix).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let mut too_long = v6.clone();
+        too_long.resize(size_of::<sockaddr_storage>() + 1, 0);
+        let err = parse_sockaddr(&too_long).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // Trailing bytes up to the size of any socket address are ignored.
+        too_long.pop();
+        assert_eq!(
+            parse_sockaddr(&too_long).unwrap(),
+            "[::1]:80".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_sockaddr_never_panics_on_arbitrary_bytes() {
+        // xorshift64, so failures are reproducible.
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let families = [AF_INET, AF_INET6, libc::AF_UNIX, 0xffff];
+        for _ in 0..10_000 {
+            let len = (next() % (size_of::<sockaddr_storage>() as u64 + 8)) as usize;
+            let mut bytes: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            // Mostly a family that parses, so the addresses themselves get exercised too.
+            if len >= 2 && next() % 4 != 0 {
+                let family = families[(next() % families.len() as u64) as usize];
+                bytes[..2].copy_from_slice(&(family as sa_family_t).to_ne_bytes());
+            }
+
+            match parse_sockaddr(&bytes) {
+                Ok(addr) => assert_eq!(parse_sockaddr(&sockaddr_bytes(&addr)).unwrap(), addr),
+                Err(err) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+            }
+        }
+    }
+
+    #[test]
+    fn sockaddr_bytes_round_trips() {
+        let addrs: [SocketAddr; 4] = [
+            "127.0.0.1:80".parse().unwrap(),
+            "0.0.0.0:0".parse().unwrap(),
+            "[2001:db8::1]:65535".parse().unwrap(),
+            SocketAddrV6::new("fe80::1".parse().unwrap(), 443, 0x12345, 7).into(),
+        ];
+        for addr in addrs {
+            let bytes = sockaddr_bytes(&addr);
+            let expected = match addr {
+                SocketAddr::V4(_) => size_of::<sockaddr_in>(),
+                SocketAddr::V6(_) => size_of::<sockaddr_in6>(),
+            };
+            assert_eq!(bytes.len(), expected);
+            assert_eq!(parse_sockaddr(&bytes).unwrap(), addr);
+        }
+
+        // The layout is the kernel's: the port in network byte order after the family.
+        let bytes = sockaddr_bytes(&"10.1.2.3:258".parse().unwrap());
+        assert_eq!(&bytes[2..8], &[1, 2, 10, 1, 2, 3]);
+    }
+
+    #[test]
+    fn addr_buf_matches_display() {
+        let longest = SocketAddrV6::new(
+            "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff".parse().unwrap(),
+            65535,
+            0,
+            u32::MAX,
+        );
+        let addrs: [SocketAddr; 4] = [
+            "255.255.255.255:65535".parse().unwrap(),
+            "[2001:db8::1]:443".parse().unwrap(),
+            SocketAddrV6::new("fe80::1".parse().unwrap(), 80, 0, 3).into(),
+            longest.into(),
+        ];
+        for addr in addrs {
+            let buf = AddrBuf::new(&addr);
+            assert_eq!(buf.as_str(), addr.to_string());
+            assert_eq!(buf.to_string(), addr.to_string());
+            assert_eq!(format!("{buf:?}"), format!("{:?}", addr.to_string()));
+
+            let mut out = Vec::new();
+            write_addr(&mut out, &addr).unwrap();
+            assert_eq!(out, addr.to_string().as_bytes());
+        }
+        assert_eq!(AddrBuf::new(&longest.into()).as_str().len(), MAX_ADDR_LEN);
+    }
+
+    #[test]
+    fn flush_capped_limits_buffers_per_write() {
+        let mut queue = WriteQueue::new();
+        for i in 0..10u8 {
+            queue.push_owned(vec![i; 3]);
+        }
+        let mut writer = ThrottledWriter::new(usize::MAX, usize::MAX);
+        assert!(queue.flush_capped(&mut writer, 4).unwrap());
+        assert_eq!(writer.slices_per_call, [4, 4, 2]);
+        assert_eq!(writer.written.len(), 30);
+
+        // A cap of 0 still makes progress, one buffer at a time.
+        queue.push_owned(b"ab".to_vec());
+        queue.push_owned(b"cd".to_vec());
+        writer.slices_per_call.clear();
+        assert!(queue.flush_capped(&mut writer, 0).unwrap());
+        assert_eq!(writer.slices_per_call, [1, 1]);
+        assert!(writer.written.ends_with(b"abcd"));
+
+        // Partial writes resume inside the capped window.
+        queue.push_owned(b"hello".to_vec());
+        queue.push_owned(b"world".to_vec());
+        writer.slices_per_call.clear();
+        writer.per_write = 3;
+        assert!(queue.flush_capped(&mut writer, 1).unwrap());
+        assert_eq!(writer.slices_per_call, [1, 1, 1, 1]);
+        assert!(writer.written.ends_with(b"helloworld"));
+
+        // Caps above `IOV_MAX` are clamped to it.
+        for _ in 0..1500 {
+            queue.push_owned(vec![0]);
+        }
+        writer.slices_per_call.clear();
+        writer.per_write = usize::MAX;
+        assert!(queue.flush_capped(&mut writer, usize::MAX).unwrap());
+        assert_eq!(writer.slices_per_call, [MAX_IOVECS, 1500 - MAX_IOVECS]);
+    }
+
+    /// Sends `hello`, then waits for a four byte reply.
+    #[derive(Default)]
+    struct HelloHandshake {
+        sent: bool,
+        reply: Vec<u8>,
+        steps: usize,
+    }
+
+    impl HelloHandshake {
+        fn step(&mut self, stream: &mut TcpStream) -> io::Result<Handshake<Vec<u8>>> {
+            self.steps += 1;
+            if !self.sent {
+                stream.write_all(b"hello")?;
+                self.sent = true;
+            }
+            let mut buf = [0; 4];
+            while self.reply.len() < 4 {
+                match stream.read(&mut buf[..4 - self.reply.len()]) {
+                    Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    Ok(n) => self.reply.extend_from_slice(&buf[..n]),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(Handshake::WantRead)
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(Handshake::Done(std::mem::take(&mut self.reply)))
+        }
+    }
+
+    #[test]
+    fn drive_handshake_waits_for_each_step() {
+        let (mut client, mut server) = tcp_pair();
+        let peer = thread::spawn(move || {
+            let mut buf = [0; 5];
+            poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+            server.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"hello");
+            // Reply in two parts so that the client has to wait twice.
+            server.write_all(b"wo").unwrap();
+            thread::sleep(Duration::from_millis(20));
+            server.write_all(b"rd").unwrap();
+            server
+        });
+
+        let mut handshake = HelloHandshake::default();
+        let got = drive_handshake(&mut client, Instant::now() + TIMEOUT, |stream| {
+            handshake.step(stream)
+        })
+        .unwrap();
+        assert_eq!(got, b"word");
+        assert!(handshake.steps >= 2);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn drive_handshake_gives_up_at_the_deadline() {
+        let (mut client, _server) = tcp_pair();
+        let mut handshake = HelloHandshake::default();
+        let start = Instant::now();
+        let err = drive_handshake(&mut client, start + Duration::from_millis(30), |stream| {
+            handshake.step(stream)
+        })
+        .err()
+        .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(30));
+
+        // A deadline already past still takes the first step, which may finish the
+        // handshake, and errors from a step are passed through.
+        let done = drive_handshake(&mut client, start, |_| Ok(Handshake::Done(7)));
+        assert_eq!(done.unwrap(), 7);
+        let failed = drive_handshake(&mut client, Instant::now() + TIMEOUT, |_| {
+            Err::<Handshake<()>, _>(io::Error::from(io::ErrorKind::InvalidData))
+        });
+        assert_eq!(failed.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn broadcast_reports_each_stream_without_waiting() {
+        let (fast, mut fast_peer) = tcp_pair();
+        let (mut slow, _slow_peer) = tcp_pair();
+        let (closed, _closed_peer) = tcp_pair();
+
+        let chunk = [0; 64 * 1024];
+        while slow.try_write(&chunk).unwrap().is_some() {}
+        // Even a blocking socket with a full send buffer must not hold up the rest.
+        let flags = syscall!(fcntl(slow.as_raw_fd(), libc::F_GETFL)).unwrap();
+        syscall!(fcntl(
+            slow.as_raw_fd(),
+            libc::F_SETFL,
+            flags & !libc::O_NONBLOCK
+        ))
+        .unwrap();
+        closed.shutdown(Shutdown::Write).unwrap();
+
+        let results = broadcast(&[&fast, &slow, &closed], b"news");
+        assert_eq!(*results[0].as_ref().unwrap(), 4);
+        assert_eq!(
+            results[1].as_ref().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert_eq!(
+            results[2].as_ref().unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+
+        let mut buf = [0; 4];
+        poll_fd(fast_peer.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        fast_peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"news");
+        assert!(broadcast(&[], b"news").is_empty());
+    }
+
+    #[test]
+    fn flush_registered_drops_writable_interest_once_drained() {
+        const LEN: usize = 8 << 20;
+        let (mut client, server) = tcp_pair();
+        let epoll = Epoll::new().unwrap();
+        let mut registration =
+            Registration::new(&epoll, &client, Token(1), Interest::READABLE).unwrap();
+        let reader = thread::spawn(move || {
+            let mut server = server;
+            let mut buf = vec![0; 64 << 10];
+            let mut read = 0;
+            while read < LEN {
+                poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+                match server.read(&mut buf) {
+                    Ok(n) => read += n,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(err) => panic!("{err}"),
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+            server
+        });
+
+        let mut queue = WriteQueue::new();
+        queue.push_owned(vec![7; LEN]);
+        let mut drained = queue
+            .flush_registered(&mut client, &epoll, &mut registration)
+            .unwrap();
+        assert!(!drained);
+        assert!(registration.interest().contains(Interest::WRITABLE));
+
+        let mut events = Vec::with_capacity(8);
+        while !drained {
+            assert!(epoll.wait(&mut events, Some(TIMEOUT)).unwrap() > 0);
+            assert!(events[0].is_writable());
+            drained = queue
+                .flush_registered(&mut client, &epoll, &mut registration)
+                .unwrap();
+        }
+        assert!(!registration.interest().contains(Interest::WRITABLE));
+        let _server = reader.join().unwrap();
+        // The socket is writable with nothing queued, and nothing reports it.
+        assert_eq!(
+            epoll
+                .wait(&mut events, Some(Duration::from_millis(50)))
+                .unwrap(),
+            0
+        );
+    }
+}
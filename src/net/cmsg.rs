@@ -0,0 +1,215 @@
+use std::{
+    mem::{size_of, zeroed},
+    ptr,
+};
+
+use libc::{c_int, c_uint, cmsghdr, CMSG_LEN, CMSG_SPACE};
+
+/// The space a control message with `data_len` bytes of data takes up in a control buffer,
+/// including the padding before the next one (`CMSG_SPACE`). Control buffers for `recvmsg`
+/// are sized by summing this over the messages expected.
+pub fn cmsg_space(data_len: usize) -> usize {
+    unsafe { CMSG_SPACE(data_len as c_uint) as usize }
+}
+
+/// The `cmsg_len` of a control message with `data_len` bytes of data (`CMSG_LEN`).
+pub fn cmsg_len(data_len: usize) -> usize {
+    unsafe { CMSG_LEN(data_len as c_uint) as usize }
+}
+
+/// A control message read by [`CmsgIter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cmsg<'a> {
+    pub level: c_int,
+    pub kind: c_int,
+    pub data: &'a [u8],
+}
+
+impl Cmsg<'_> {
+    /// Reads a `T` from the start of the data, `None` if the data is too short.
+    ///
+    /// # Safety
+    ///
+    /// Any bit pattern must be a valid `T`, as for the C structs control messages carry.
+    pub unsafe fn read<T: Copy>(&self) -> Option<T> {
+        (self.data.len() >= size_of::<T>())
+            .then(|| ptr::read_unaligned(self.data.as_ptr() as *const T))
+    }
+}
+
+/// Iterates over the control messages in a control buffer filled in by `recvmsg`, i.e. the
+/// first `msg_controllen` bytes of it. The buffer doesn't have to be aligned. Iteration
+/// stops at the first malformed message.
+pub struct CmsgIter<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> CmsgIter<'a> {
+    pub fn new(control: &'a [u8]) -> CmsgIter<'a> {
+        CmsgIter { buf: control }
+    }
+}
+
+impl<'a> Iterator for CmsgIter<'a> {
+    type Item = Cmsg<'a>;
+
+    fn next(&mut self) -> Option<Cmsg<'a>> {
+        if self.buf.len() < size_of::<cmsghdr>() {
+            return None;
+        }
+        let header = unsafe { ptr::read_unaligned(self.buf.as_ptr() as *const cmsghdr) };
+        let header_len = cmsg_len(0);
+        let len = header.cmsg_len as usize;
+        if len < header_len || len > self.buf.len() {
+            self.buf = &[];
+            return None;
+        }
+
+        let data = &self.buf[header_len..len];
+        // The last message may not be followed by padding.
+        let next = cmsg_space(data.len()).min(self.buf.len());
+        self.buf = &self.buf[next..];
+        Some(Cmsg {
+            level: header.cmsg_level,
+            kind: header.cmsg_type,
+            data,
+        })
+    }
+}
+
+/// Builds a control buffer for `sendmsg`.
+#[derive(Debug, Clone, Default)]
+pub struct CmsgBuilder {
+    buf: Vec<u8>,
+}
+
+impl CmsgBuilder {
+    pub fn new() -> CmsgBuilder {
+        CmsgBuilder::default()
+    }
+
+    /// Appends a control message carrying `data`.
+    pub fn push(&mut self, level: c_int, kind: c_int, data: &[u8]) -> &mut CmsgBuilder {
+        let start = self.reserve(level, kind, data.len());
+        self.buf[start..start + data.len()].copy_from_slice(data);
+        self
+    }
+
+    /// Appends a control message carrying `value`, e.g. an `in_pktinfo`.
+    ///
+    /// # Safety
+    ///
+    /// `T` must not contain padding, so that every byte of the buffer is initialized.
+    pub unsafe fn push_value<T: Copy>(&mut self, level: c_int, kind: c_int, value: T) {
+        let start = self.reserve(level, kind, size_of::<T>());
+        ptr::write_unaligned(self.buf[start..].as_mut_ptr() as *mut T, value);
+    }
+
+    /// Appends a zeroed message with room for `data_len` bytes of data, returning where the
+    /// data starts.
+    fn reserve(&mut self, level: c_int, kind: c_int, data_len: usize) -> usize {
+        let offset = self.buf.len();
+        self.buf.resize(offset + cmsg_space(data_len), 0);
+
+        let mut header: cmsghdr = unsafe { zeroed() };
+        header.cmsg_level = level;
+        header.cmsg_type = kind;
+        header.cmsg_len = cmsg_len(data_len) as _;
+        unsafe { ptr::write_unaligned(self.buf[offset..].as_mut_ptr() as *mut cmsghdr, header) };
+        offset + cmsg_len(0)
+    }
+
+    /// The buffer to point `msg_control` at, with `msg_controllen` set to its length.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::{IPPROTO_IP, IP_TOS, SCM_RIGHTS, SOL_SOCKET};
+
+    #[test]
+    fn built_messages_are_iterated_back() {
+        let mut builder = CmsgBuilder::new();
+        builder.push(SOL_SOCKET, SCM_RIGHTS, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        unsafe { builder.push_value(IPPROTO_IP, IP_TOS, 0x10u8) };
+        let bytes = builder.as_bytes();
+        assert_eq!(bytes.len(), cmsg_space(8) + cmsg_space(1));
+
+        let cmsgs: Vec<_> = CmsgIter::new(bytes).collect();
+        assert_eq!(
+            cmsgs,
+            [
+                Cmsg {
+                    level: SOL_SOCKET,
+                    kind: SCM_RIGHTS,
+                    data: &[1, 2, 3, 4, 5, 6, 7, 8],
+                },
+                Cmsg {
+                    level: IPPROTO_IP,
+                    kind: IP_TOS,
+                    data: &[0x10],
+                },
+            ]
+        );
+        assert_eq!(unsafe { cmsgs[1].read::<u8>() }, Some(0x10));
+        assert_eq!(unsafe { cmsgs[1].read::<u32>() }, None);
+
+        // The kernel may leave out the padding after the last message.
+        let unpadded = &bytes[..cmsg_space(8) + cmsg_len(1)];
+        assert_eq!(CmsgIter::new(unpadded).count(), 2);
+
+        // Nor does the buffer have to be aligned.
+        let mut shifted = vec![0u8; bytes.len() + 1];
+        shifted[1..].copy_from_slice(bytes);
+        assert_eq!(CmsgIter::new(&shifted[1..]).collect::<Vec<_>>(), cmsgs);
+    }
+
+    #[test]
+    fn truncated_messages_are_not_returned() {
+        let mut builder = CmsgBuilder::new();
+        builder
+            .push(SOL_SOCKET, SCM_RIGHTS, &[1, 2, 3, 4])
+            .push(IPPROTO_IP, IP_TOS, &[0x10]);
+        let bytes = builder.as_bytes();
+        let first = cmsg_space(4);
+
+        // A trailing header cut short.
+        let cut = &bytes[..first + size_of::<cmsghdr>() - 1];
+        assert_eq!(CmsgIter::new(cut).count(), 1);
+
+        // A whole header whose data is cut short.
+        let cut = &bytes[..first + cmsg_len(0)];
+        let cmsgs: Vec<_> = CmsgIter::new(cut).collect();
+        assert_eq!(cmsgs.len(), 1);
+        assert_eq!(cmsgs[0].kind, SCM_RIGHTS);
+
+        // A length shorter than the header stops iteration too.
+        let mut malformed = bytes.to_vec();
+        let mut header = unsafe { ptr::read_unaligned(malformed.as_ptr() as *const cmsghdr) };
+        header.cmsg_len = 1;
+        unsafe { ptr::write_unaligned(malformed.as_mut_ptr() as *mut cmsghdr, header) };
+        assert_eq!(CmsgIter::new(&malformed).count(), 0);
+    }
+
+    #[test]
+    fn cleared_builders_are_empty() {
+        let mut builder = CmsgBuilder::new();
+        assert!(builder.is_empty());
+        builder.push(SOL_SOCKET, SCM_RIGHTS, &[]);
+        assert!(!builder.is_empty());
+        builder.clear();
+        assert!(builder.is_empty());
+        assert_eq!(CmsgIter::new(builder.as_bytes()).count(), 0);
+    }
+}
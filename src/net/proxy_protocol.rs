@@ -0,0 +1,422 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::fd::AsRawFd,
+    time::{Duration, Instant},
+};
+
+use libc::POLLIN;
+
+use crate::{net::wait_until, tcp::TcpStream};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest v1 header, CRLF included.
+const V1_MAX_LEN: usize = 107;
+const V2_CMD_LOCAL: u8 = 0x20;
+const V2_CMD_PROXY: u8 = 0x21;
+const V2_INET_STREAM: u8 = 0x11;
+const V2_INET6_STREAM: u8 = 0x21;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyVersion {
+    /// The text format.
+    V1,
+    /// The binary format.
+    V2,
+}
+
+/// A PROXY protocol header, as sent by a load balancer ahead of the client's data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyInfo {
+    pub version: ProxyVersion,
+    /// The client's address and the address it connected to. `None` for connections the
+    /// proxy made on its own behalf, such as health checks (v1 `UNKNOWN`, v2 `LOCAL`), and
+    /// for v2 headers carrying non-IP addresses.
+    pub addrs: Option<(SocketAddr, SocketAddr)>,
+}
+
+impl ProxyInfo {
+    /// The client's real address, where `peer_addr` would give the proxy's.
+    pub fn source(&self) -> Option<SocketAddr> {
+        self.addrs.map(|(source, _)| source)
+    }
+
+    pub fn destination(&self) -> Option<SocketAddr> {
+        self.addrs.map(|(_, destination)| destination)
+    }
+
+    /// Appends the header to `out`, for a client or proxy to send before any other data.
+    /// An IPv4 address paired with an IPv6 one is sent IPv4-mapped, as both must share a
+    /// family.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let addrs = match self.addrs {
+            Some((source, destination)) if source.is_ipv4() != destination.is_ipv4() => {
+                Some((to_ipv6(source), to_ipv6(destination)))
+            }
+            addrs => addrs,
+        };
+        match self.version {
+            ProxyVersion::V1 => encode_v1(addrs, out),
+            ProxyVersion::V2 => encode_v2(addrs, out),
+        }
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+fn encode_v1(addrs: Option<(SocketAddr, SocketAddr)>, out: &mut Vec<u8>) {
+    let line = match addrs {
+        Some((source, destination)) => {
+            let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {family} {} {} {} {}\r\n",
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+        }
+        None => "PROXY UNKNOWN\r\n".to_string(),
+    };
+    out.extend_from_slice(line.as_bytes());
+}
+
+fn encode_v2(addrs: Option<(SocketAddr, SocketAddr)>, out: &mut Vec<u8>) {
+    out.extend_from_slice(&V2_SIGNATURE);
+    let (source, destination) = match addrs {
+        Some(addrs) => addrs,
+        None => {
+            out.extend_from_slice(&[V2_CMD_LOCAL, 0, 0, 0]);
+            return;
+        }
+    };
+
+    out.push(V2_CMD_PROXY);
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+            out.push(V2_INET_STREAM);
+            out.extend_from_slice(&12u16.to_be_bytes());
+            out.extend_from_slice(&source_ip.octets());
+            out.extend_from_slice(&destination_ip.octets());
+        }
+        (IpAddr::V6(source_ip), IpAddr::V6(destination_ip)) => {
+            out.push(V2_INET6_STREAM);
+            out.extend_from_slice(&36u16.to_be_bytes());
+            out.extend_from_slice(&source_ip.octets());
+            out.extend_from_slice(&destination_ip.octets());
+        }
+        _ => unreachable!("families are unified by encode"),
+    }
+    out.extend_from_slice(&source.port().to_be_bytes());
+    out.extend_from_slice(&destination.port().to_be_bytes());
+}
+
+/// Reads a PROXY protocol v1 or v2 header off the start of `stream`, waiting for it to
+/// arrive in full for at most `timeout`. Exactly the header is consumed, leaving the
+/// client's data unread. Fails with `InvalidData` on anything but a well-formed header,
+/// after which the connection should be dropped.
+pub fn read_proxy_header(stream: &mut TcpStream, timeout: Duration) -> io::Result<ProxyInfo> {
+    let deadline = Instant::now() + timeout;
+    let remaining = || deadline.saturating_duration_since(Instant::now());
+
+    // Enough to tell the versions apart: v1 starts with "PROXY ", v2 with its signature.
+    let mut header = vec![0; 6];
+    stream.read_exact_timeout(&mut header, remaining())?;
+    if header == b"PROXY " {
+        return read_v1_rest(stream, header, deadline);
+    }
+    if header != V2_SIGNATURE[..6] {
+        return Err(malformed("missing PROXY protocol header"));
+    }
+
+    header.resize(16, 0);
+    stream.read_exact_timeout(&mut header[6..], remaining())?;
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    header.resize(16 + len, 0);
+    stream.read_exact_timeout(&mut header[16..], remaining())?;
+    parse_v2(&header)
+}
+
+/// Reads the rest of a v1 header, its first bytes already in `header`. Peeks at what has
+/// arrived and consumes only up to the CRLF, so as not to read into the client's data.
+/// Peeked bytes without a CRLF are all header and are consumed right away, so that waiting
+/// for the rest blocks until more arrives.
+fn read_v1_rest(
+    stream: &mut TcpStream,
+    mut header: Vec<u8>,
+    deadline: Instant,
+) -> io::Result<ProxyInfo> {
+    let mut peeked = [0; V1_MAX_LEN];
+    loop {
+        let start = header.len();
+        let n = match stream.peek(&mut peeked[..V1_MAX_LEN - start]) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                wait_until(stream.as_raw_fd(), POLLIN, deadline)?;
+                continue;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+
+        header.extend_from_slice(&peeked[..n]);
+        // Searches from one byte back, in case the CRLF straddles two peeks.
+        let end = header[start - 1..]
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .map(|pos| start + 1 + pos);
+        header.truncate(end.unwrap_or(header.len()));
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        stream.read_exact_timeout(&mut peeked[..header.len() - start], remaining)?;
+
+        if end.is_some() {
+            return parse_v1(&header);
+        }
+        if header.len() == V1_MAX_LEN {
+            return Err(malformed("v1 header too long"));
+        }
+    }
+}
+
+fn malformed(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed PROXY protocol header: {msg}"),
+    )
+}
+
+/// Parses a full v1 header, CRLF included.
+fn parse_v1(header: &[u8]) -> io::Result<ProxyInfo> {
+    let line = std::str::from_utf8(&header[..header.len() - 2])
+        .map_err(|_| malformed("v1 header isn't ASCII"))?;
+    let mut fields = line.split(' ').skip(1);
+    let family = fields.next().unwrap_or("");
+    if family == "UNKNOWN" {
+        // Anything may follow, and is ignored.
+        return Ok(ProxyInfo {
+            version: ProxyVersion::V1,
+            addrs: None,
+        });
+    }
+
+    let parse_ip = |field: Option<&str>| -> io::Result<IpAddr> {
+        let field = field.ok_or_else(|| malformed("v1 header missing an address"))?;
+        let ip = match family {
+            "TCP4" => field.parse::<Ipv4Addr>().map(IpAddr::V4),
+            "TCP6" => field.parse::<Ipv6Addr>().map(IpAddr::V6),
+            _ => return Err(malformed("unknown v1 protocol")),
+        };
+        ip.map_err(|_| malformed("bad v1 address"))
+    };
+    let parse_port = |field: Option<&str>| -> io::Result<u16> {
+        let field = field.ok_or_else(|| malformed("v1 header missing a port"))?;
+        let valid = !field.is_empty()
+            && field.bytes().all(|b| b.is_ascii_digit())
+            && (field == "0" || !field.starts_with('0'));
+        if !valid {
+            return Err(malformed("bad v1 port"));
+        }
+        field.parse().map_err(|_| malformed("bad v1 port"))
+    };
+
+    let source_ip = parse_ip(fields.next())?;
+    let destination_ip = parse_ip(fields.next())?;
+    let source_port = parse_port(fields.next())?;
+    let destination_port = parse_port(fields.next())?;
+    if fields.next().is_some() {
+        return Err(malformed("trailing data in v1 header"));
+    }
+    Ok(ProxyInfo {
+        version: ProxyVersion::V1,
+        addrs: Some((
+            SocketAddr::new(source_ip, source_port),
+            SocketAddr::new(destination_ip, destination_port),
+        )),
+    })
+}
+
+/// Parses a full v2 header, its 16 fixed bytes followed by the address block.
+fn parse_v2(header: &[u8]) -> io::Result<ProxyInfo> {
+    if header[..12] != V2_SIGNATURE {
+        return Err(malformed("bad v2 signature"));
+    }
+    let block = &header[16..];
+    let local = ProxyInfo {
+        version: ProxyVersion::V2,
+        addrs: None,
+    };
+    match header[12] {
+        V2_CMD_LOCAL => return Ok(local),
+        V2_CMD_PROXY => {}
+        _ => return Err(malformed("unknown v2 version or command")),
+    }
+
+    // The low nibble is the transport, which doesn't affect the addresses.
+    let addrs = match header[13] >> 4 {
+        1 if block.len() >= 12 => {
+            let ip =
+                |at: usize| Ipv4Addr::new(block[at], block[at + 1], block[at + 2], block[at + 3]);
+            let port = |at: usize| u16::from_be_bytes([block[at], block[at + 1]]);
+            (
+                SocketAddr::new(ip(0).into(), port(8)),
+                SocketAddr::new(ip(4).into(), port(10)),
+            )
+        }
+        2 if block.len() >= 36 => {
+            let ip = |at: usize| Ipv6Addr::from(<[u8; 16]>::try_from(&block[at..at + 16]).unwrap());
+            let port = |at: usize| u16::from_be_bytes([block[at], block[at + 1]]);
+            (
+                SocketAddr::new(ip(0).into(), port(32)),
+                SocketAddr::new(ip(16).into(), port(34)),
+            )
+        }
+        1 | 2 => return Err(malformed("v2 address block too short")),
+        // Unspecified or AF_UNIX: nothing to report as a socket address.
+        0 | 3 => return Ok(local),
+        _ => return Err(malformed("unknown v2 address family")),
+    };
+    Ok(ProxyInfo {
+        version: ProxyVersion::V2,
+        addrs: Some(addrs),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, thread};
+
+    use super::*;
+    use crate::{net::poll_fd, tcp::TcpListener};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        poll_fd(listener.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    /// Sends `chunks` a little apart, then reads a header followed by "hello", checking
+    /// the header didn't eat into it.
+    fn read_sent(chunks: &[&[u8]]) -> io::Result<ProxyInfo> {
+        let (mut client, mut server) = pair();
+        let chunks: Vec<Vec<u8>> = chunks.iter().map(|chunk| chunk.to_vec()).collect();
+        let writer = thread::spawn(move || {
+            for chunk in chunks {
+                client.write_all(&chunk).unwrap();
+                thread::sleep(Duration::from_millis(10));
+            }
+            client.write_all(b"hello").unwrap();
+            client
+        });
+        let info = read_proxy_header(&mut server, TIMEOUT);
+        let _client = writer.join().unwrap();
+        if info.is_ok() {
+            let mut data = [0; 5];
+            server.read_exact_timeout(&mut data, TIMEOUT).unwrap();
+            assert_eq!(&data, b"hello");
+        }
+        info
+    }
+
+    fn round_trip(info: ProxyInfo) -> ProxyInfo {
+        let mut header = Vec::new();
+        info.encode(&mut header);
+        read_sent(&[header.as_slice()]).unwrap()
+    }
+
+    fn addrs(source: &str, destination: &str) -> Option<(SocketAddr, SocketAddr)> {
+        Some((source.parse().unwrap(), destination.parse().unwrap()))
+    }
+
+    #[test]
+    fn round_trips_both_versions() {
+        for version in [ProxyVersion::V1, ProxyVersion::V2] {
+            for addrs in [
+                addrs("192.0.2.1:56324", "198.51.100.7:443"),
+                addrs("[2001:db8::1]:56324", "[2001:db8::2]:443"),
+                None,
+            ] {
+                let info = ProxyInfo { version, addrs };
+                assert_eq!(round_trip(info), info);
+            }
+        }
+    }
+
+    #[test]
+    fn mixed_families_are_sent_ipv4_mapped() {
+        for version in [ProxyVersion::V1, ProxyVersion::V2] {
+            let info = ProxyInfo {
+                version,
+                addrs: addrs("192.0.2.1:56324", "[2001:db8::2]:443"),
+            };
+            let expected = ProxyInfo {
+                version,
+                addrs: addrs("[::ffff:192.0.2.1]:56324", "[2001:db8::2]:443"),
+            };
+            assert_eq!(round_trip(info), expected);
+        }
+    }
+
+    #[test]
+    fn reads_headers_split_across_packets() {
+        let v1: [&[u8]; 4] = [
+            b"PROX",
+            b"Y TCP4 192.0.2.1 198.51.100.7 5",
+            b"6324 443\r",
+            b"\n",
+        ];
+        let v1 = read_sent(&v1);
+        assert_eq!(
+            v1.unwrap().source(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+
+        let mut v2 = Vec::new();
+        ProxyInfo {
+            version: ProxyVersion::V2,
+            addrs: addrs("192.0.2.1:56324", "198.51.100.7:443"),
+        }
+        .encode(&mut v2);
+        let v2 = read_sent(&[&v2[..3], &v2[3..14], &v2[14..20], &v2[20..]]);
+        assert_eq!(
+            v2.unwrap().destination(),
+            Some("198.51.100.7:443".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let long = [b"PROXY TCP4 ".as_slice(), &[b'1'; 120][..]].concat();
+        let mut bad_v2 = Vec::new();
+        ProxyInfo {
+            version: ProxyVersion::V2,
+            addrs: addrs("192.0.2.1:56324", "198.51.100.7:443"),
+        }
+        .encode(&mut bad_v2);
+        // Claims IPv6 addresses with only an IPv4-sized block.
+        bad_v2[13] = V2_INET6_STREAM;
+
+        let headers: [&[u8]; 7] = [
+            b"GET / HTTP/1.1\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.7 056324 443\r\n",
+            b"PROXY TCP4 2001:db8::1 198.51.100.7 56324 443\r\n",
+            b"PROXY TCP5 192.0.2.1 198.51.100.7 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443 extra\r\n",
+            &long,
+            &bad_v2,
+        ];
+        for header in headers {
+            let err = read_sent(&[header]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{header:?}");
+        }
+    }
+}
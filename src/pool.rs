@@ -0,0 +1,182 @@
+use std::{
+    cell::{Cell, RefCell},
+    ops::{Deref, DerefMut},
+    rc::Rc,
+};
+
+/// A pool of fixed-size read buffers for a single-threaded event loop. Check one out per
+/// readable event with [`get`](BufferPool::get); it goes back to the pool when dropped.
+/// At most `max_idle` buffers are kept around, so the pool holds no more than
+/// `max_idle * buf_size` bytes beyond what's checked out. Clones share the same pool.
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    buf_size: usize,
+    max_idle: usize,
+    idle: RefCell<Vec<Box<[u8]>>>,
+    allocated: Cell<u64>,
+}
+
+impl BufferPool {
+    pub fn new(buf_size: usize, max_idle: usize) -> BufferPool {
+        BufferPool {
+            inner: Rc::new(Inner {
+                buf_size,
+                max_idle,
+                idle: RefCell::new(Vec::with_capacity(max_idle)),
+                allocated: Cell::new(0),
+            }),
+        }
+    }
+
+    /// Takes an idle buffer, or allocates a zeroed one if there are none. Recycled buffers
+    /// keep whatever was last written to them.
+    pub fn get(&self) -> PooledBuf {
+        let buf = self.inner.idle.borrow_mut().pop().unwrap_or_else(|| {
+            self.inner.allocated.set(self.inner.allocated.get() + 1);
+            vec![0; self.inner.buf_size].into_boxed_slice()
+        });
+        PooledBuf {
+            buf: Some(buf),
+            pool: Rc::clone(&self.inner),
+        }
+    }
+
+    pub fn buf_size(&self) -> usize {
+        self.inner.buf_size
+    }
+
+    pub fn max_idle(&self) -> usize {
+        self.inner.max_idle
+    }
+
+    /// Buffers waiting in the pool.
+    pub fn idle(&self) -> usize {
+        self.inner.idle.borrow().len()
+    }
+
+    /// Buffers allocated over the pool's lifetime, including ones since freed for
+    /// exceeding `max_idle`.
+    pub fn allocated(&self) -> u64 {
+        self.inner.allocated.get()
+    }
+
+    /// Frees every idle buffer.
+    pub fn shrink(&self) {
+        self.inner.idle.borrow_mut().clear();
+    }
+}
+
+/// A buffer checked out of a [`BufferPool`], `buf_size` bytes long. Dropping it returns
+/// it to the pool, or frees it if the pool already holds `max_idle` buffers.
+pub struct PooledBuf {
+    /// Only `None` while dropping.
+    buf: Option<Box<[u8]>>,
+    pool: Rc<Inner>,
+}
+
+impl Deref for PooledBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buf.as_deref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buf.as_deref_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        let buf = self.buf.take().unwrap();
+        let mut idle = self.pool.idle.borrow_mut();
+        if idle.len() < self.pool.max_idle {
+            idle.push(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropped_buffers_are_recycled() {
+        let pool = BufferPool::new(64, 4);
+        let mut buf = pool.get();
+        assert_eq!(buf.len(), 64);
+        assert!(buf.iter().all(|&byte| byte == 0));
+        buf[..5].copy_from_slice(b"hello");
+        let ptr = buf.as_ptr();
+        assert_eq!(pool.idle(), 0);
+        drop(buf);
+        assert_eq!(pool.idle(), 1);
+
+        let buf = pool.get();
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(pool.idle(), 0);
+        assert_eq!(pool.allocated(), 1);
+    }
+
+    #[test]
+    fn allocations_stop_once_buffers_are_recycled() {
+        let pool = BufferPool::new(16, 2);
+        for _ in 0..100 {
+            let a = pool.get();
+            let b = pool.get();
+            drop((a, b));
+        }
+        assert_eq!(pool.allocated(), 2);
+
+        // A clone checks out of the same pool.
+        let clone = pool.clone();
+        let buf = clone.get();
+        assert_eq!(pool.idle(), 1);
+        drop(buf);
+        assert_eq!(pool.idle(), 2);
+        assert_eq!(clone.allocated(), 2);
+    }
+
+    #[test]
+    fn max_idle_caps_the_buffers_kept() {
+        let pool = BufferPool::new(16, 2);
+        assert_eq!((pool.buf_size(), pool.max_idle()), (16, 2));
+        let bufs: Vec<_> = (0..5).map(|_| pool.get()).collect();
+        assert_eq!(pool.allocated(), 5);
+        drop(bufs);
+        assert_eq!(pool.idle(), 2);
+
+        let bufs: Vec<_> = (0..5).map(|_| pool.get()).collect();
+        assert_eq!(pool.allocated(), 8);
+        drop(bufs);
+
+        let none = BufferPool::new(16, 0);
+        drop(none.get());
+        assert_eq!(none.idle(), 0);
+        drop(none.get());
+        assert_eq!(none.allocated(), 2);
+    }
+
+    #[test]
+    fn shrink_frees_idle_buffers_only() {
+        let pool = BufferPool::new(16, 4);
+        let kept = pool.get();
+        drop((pool.get(), pool.get()));
+        assert_eq!(pool.idle(), 2);
+        pool.shrink();
+        assert_eq!(pool.idle(), 0);
+
+        // A buffer checked out across the shrink still goes back.
+        drop(kept);
+        assert_eq!(pool.idle(), 1);
+        drop(pool.get());
+        assert_eq!(pool.allocated(), 3);
+    }
+}
@@ -0,0 +1,356 @@
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr},
+    os::fd::AsRawFd,
+    time::{Duration, Instant},
+};
+
+use libc::{POLLIN, POLLOUT};
+
+use crate::{net::wait_until, tcp::TcpStream};
+
+const SOCKS_VERSION: u8 = 5;
+const METHOD_NONE: u8 = 0;
+const METHOD_PASSWORD: u8 = 2;
+const METHOD_UNACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Where a proxy should connect to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Addr(SocketAddr),
+    /// A hostname and port, resolved by the proxy.
+    Host(String, u16),
+}
+
+impl From<SocketAddr> for Target {
+    fn from(addr: SocketAddr) -> Target {
+        Target::Addr(addr)
+    }
+}
+
+/// Username/password credentials (RFC 1929).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Auth {
+    pub username: String,
+    pub password: String,
+}
+
+impl TcpStream {
+    /// Connects to `target` through the SOCKS5 proxy at `proxy` (RFC 1928), authenticating
+    /// with `auth` if given, and returns the tunneled stream. Blocks until the proxy has
+    /// connected to the target or refused to, failing with `TimedOut` if that takes longer
+    /// than `timeout` altogether, e.g. because the proxy stalls mid-handshake.
+    pub fn connect_socks5(
+        proxy: SocketAddr,
+        target: Target,
+        auth: Option<Auth>,
+        timeout: Duration,
+    ) -> io::Result<TcpStream> {
+        let deadline = Instant::now() + timeout;
+        let mut stream = TcpStream::connect_wait(proxy, timeout)?;
+
+        let methods: &[u8] = match auth {
+            Some(_) => &[METHOD_NONE, METHOD_PASSWORD],
+            None => &[METHOD_NONE],
+        };
+        let mut greeting = vec![SOCKS_VERSION, methods.len() as u8];
+        greeting.extend_from_slice(methods);
+        write_all(&mut stream, &greeting, deadline)?;
+
+        let mut choice = [0; 2];
+        read_exact(&mut stream, &mut choice, deadline)?;
+        if choice[0] != SOCKS_VERSION {
+            return Err(protocol_error("not a SOCKS5 proxy"));
+        }
+        match (choice[1], &auth) {
+            (METHOD_NONE, _) => {}
+            (METHOD_PASSWORD, Some(auth)) => authenticate(&mut stream, auth, deadline)?,
+            (METHOD_UNACCEPTABLE, _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "proxy requires an unsupported authentication method",
+                ))
+            }
+            _ => return Err(protocol_error("proxy chose a method that wasn't offered")),
+        }
+
+        let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+        let port = match &target {
+            Target::Addr(addr) => {
+                match addr.ip() {
+                    IpAddr::V4(ip) => {
+                        request.push(ATYP_IPV4);
+                        request.extend_from_slice(&ip.octets());
+                    }
+                    IpAddr::V6(ip) => {
+                        request.push(ATYP_IPV6);
+                        request.extend_from_slice(&ip.octets());
+                    }
+                }
+                addr.port()
+            }
+            Target::Host(host, port) => {
+                let len = u8::try_from(host.len()).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "hostname longer than 255 bytes",
+                    )
+                })?;
+                request.push(ATYP_DOMAIN);
+                request.push(len);
+                request.extend_from_slice(host.as_bytes());
+                *port
+            }
+        };
+        request.extend_from_slice(&port.to_be_bytes());
+        write_all(&mut stream, &request, deadline)?;
+
+        let mut reply = [0; 4];
+        read_exact(&mut stream, &mut reply, deadline)?;
+        if reply[0] != SOCKS_VERSION {
+            return Err(protocol_error("malformed CONNECT reply"));
+        }
+        if reply[1] != 0 {
+            return Err(reply_error(reply[1]));
+        }
+        // The address the proxy bound for the tunnel, which there's no use for here.
+        let bound_len = match reply[3] {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => {
+                let mut len = [0];
+                read_exact(&mut stream, &mut len, deadline)?;
+                len[0] as usize
+            }
+            _ => return Err(protocol_error("unknown address type in CONNECT reply")),
+        };
+        let mut bound = vec![0; bound_len + 2];
+        read_exact(&mut stream, &mut bound, deadline)?;
+        Ok(stream)
+    }
+}
+
+fn authenticate(stream: &mut TcpStream, auth: &Auth, deadline: Instant) -> io::Result<()> {
+    let too_long = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "credentials longer than 255 bytes",
+        )
+    };
+    let username_len = u8::try_from(auth.username.len()).map_err(|_| too_long())?;
+    let password_len = u8::try_from(auth.password.len()).map_err(|_| too_long())?;
+
+    let mut request = vec![1, username_len];
+    request.extend_from_slice(auth.username.as_bytes());
+    request.push(password_len);
+    request.extend_from_slice(auth.password.as_bytes());
+    write_all(stream, &request, deadline)?;
+
+    let mut status = [0; 2];
+    read_exact(stream, &mut status, deadline)?;
+    if status[1] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "proxy rejected the credentials",
+        ));
+    }
+    Ok(())
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn reply_error(code: u8) -> io::Error {
+    let (kind, msg) = match code {
+        2 => (
+            io::ErrorKind::PermissionDenied,
+            "connection not allowed by ruleset",
+        ),
+        3 => (io::ErrorKind::NetworkUnreachable, "network unreachable"),
+        4 => (io::ErrorKind::HostUnreachable, "host unreachable"),
+        5 => (io::ErrorKind::ConnectionRefused, "connection refused"),
+        6 => (io::ErrorKind::TimedOut, "TTL expired"),
+        7 => (io::ErrorKind::Unsupported, "command not supported"),
+        8 => (io::ErrorKind::Unsupported, "address type not supported"),
+        _ => (io::ErrorKind::Other, "general SOCKS server failure"),
+    };
+    io::Error::new(kind, format!("SOCKS5 proxy: {msg}"))
+}
+
+fn write_all(stream: &mut TcpStream, mut buf: &[u8], deadline: Instant) -> io::Result<()> {
+    while !buf.is_empty() {
+        match stream.write(buf) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => buf = &buf[n..],
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                wait_until(stream.as_raw_fd(), POLLOUT, deadline)?;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+fn read_exact(stream: &mut TcpStream, mut buf: &mut [u8], deadline: Instant) -> io::Result<()> {
+    while !buf.is_empty() {
+        match stream.read(buf) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => buf = &mut buf[n..],
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                wait_until(stream.as_raw_fd(), POLLIN, deadline)?;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, thread};
+
+    use super::*;
+    use crate::{net::copy_bidirectional, tcp::TcpListener};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn deadline() -> Instant {
+        Instant::now() + TIMEOUT
+    }
+
+    fn bind() -> TcpListener {
+        TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    fn accept(listener: &TcpListener) -> TcpStream {
+        wait_until(listener.as_raw_fd(), POLLIN, deadline()).unwrap();
+        listener.accept().unwrap().0
+    }
+
+    /// Echoes one connection back until the client closes its side.
+    fn echo_server() -> SocketAddr {
+        let listener = bind();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut stream = accept(&listener);
+            let mut buf = [0; 1024];
+            loop {
+                match stream.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => write_all(&mut stream, &buf[..n], deadline()).unwrap(),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        wait_until(stream.as_raw_fd(), POLLIN, deadline()).unwrap()
+                    }
+                    Err(err) => panic!("{err}"),
+                }
+            }
+        });
+        addr
+    }
+
+    /// A SOCKS5 proxy for one connection, requiring `auth` if given. Resolves only
+    /// "localhost".
+    fn socks5_server(auth: Option<Auth>) -> SocketAddr {
+        let listener = bind();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut client = accept(&listener);
+            let read = |client: &mut TcpStream, n: usize| {
+                let mut buf = vec![0; n];
+                read_exact(client, &mut buf, deadline()).unwrap();
+                buf
+            };
+
+            let greeting = read(&mut client, 2);
+            let methods = read(&mut client, greeting[1] as usize);
+            match &auth {
+                Some(auth) => {
+                    assert!(methods.contains(&METHOD_PASSWORD));
+                    write_all(&mut client, &[SOCKS_VERSION, METHOD_PASSWORD], deadline()).unwrap();
+                    let header = read(&mut client, 2);
+                    let username = read(&mut client, header[1] as usize);
+                    let password_len = read(&mut client, 1)[0] as usize;
+                    let password = read(&mut client, password_len);
+                    assert_eq!(username, auth.username.as_bytes());
+                    assert_eq!(password, auth.password.as_bytes());
+                    write_all(&mut client, &[1, 0], deadline()).unwrap();
+                }
+                None => write_all(&mut client, &[SOCKS_VERSION, METHOD_NONE], deadline()).unwrap(),
+            }
+
+            let request = read(&mut client, 4);
+            assert_eq!(request[..3], [SOCKS_VERSION, CMD_CONNECT, 0]);
+            let ip = match request[3] {
+                ATYP_IPV4 => {
+                    let octets: [u8; 4] = read(&mut client, 4).try_into().unwrap();
+                    Ipv4Addr::from(octets)
+                }
+                ATYP_DOMAIN => {
+                    let len = read(&mut client, 1)[0] as usize;
+                    assert_eq!(read(&mut client, len), b"localhost");
+                    Ipv4Addr::LOCALHOST
+                }
+                atyp => panic!("unexpected address type {atyp}"),
+            };
+            let port = read(&mut client, 2);
+            let target = SocketAddr::from((ip, u16::from_be_bytes([port[0], port[1]])));
+
+            let mut upstream = TcpStream::connect_wait(target, TIMEOUT).unwrap();
+            let reply = [SOCKS_VERSION, 0, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0];
+            write_all(&mut client, &reply, deadline()).unwrap();
+            copy_bidirectional(&mut client, &mut upstream).unwrap();
+        });
+        addr
+    }
+
+    fn assert_echoes(mut stream: TcpStream) {
+        write_all(&mut stream, b"hello through the tunnel", deadline()).unwrap();
+        let mut echoed = [0; 24];
+        read_exact(&mut stream, &mut echoed, deadline()).unwrap();
+        assert_eq!(&echoed, b"hello through the tunnel");
+    }
+
+    #[test]
+    fn tunnels_to_an_address() {
+        let echo = echo_server();
+        let proxy = socks5_server(None);
+        let stream = TcpStream::connect_socks5(proxy, echo.into(), None, TIMEOUT).unwrap();
+        assert_echoes(stream);
+    }
+
+    #[test]
+    fn tunnels_to_a_host_with_auth() {
+        let echo = echo_server();
+        let auth = Auth {
+            username: "user".to_string(),
+            password: "secret".to_string(),
+        };
+        let proxy = socks5_server(Some(auth.clone()));
+        let target = Target::Host("localhost".to_string(), echo.port());
+        let stream = TcpStream::connect_socks5(proxy, target, Some(auth), TIMEOUT).unwrap();
+        assert_echoes(stream);
+    }
+
+    #[test]
+    fn times_out_on_a_stalled_proxy() {
+        // Completes the TCP handshake from the backlog but never answers the greeting.
+        let stalled = bind();
+        let target = Target::Addr("127.0.0.1:9".parse().unwrap());
+        let err = TcpStream::connect_socks5(
+            stalled.local_addr().unwrap(),
+            target,
+            None,
+            Duration::from_millis(100),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
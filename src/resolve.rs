@@ -0,0 +1,313 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, ToSocketAddrs},
+    sync::{Condvar, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+type Resolver = dyn Fn(&str) -> io::Result<Vec<IpAddr>> + Send + Sync;
+
+/// Failed lookups are cached too, which needs a cloneable stand-in for `io::Error`.
+type CachedResult = Result<Vec<IpAddr>, (io::ErrorKind, String)>;
+
+enum Entry {
+    /// Another thread is resolving this host; wait for it instead of resolving again.
+    InFlight,
+    Resolved {
+        result: CachedResult,
+        expires: Instant,
+        last_used: u64,
+    },
+}
+
+struct State {
+    entries: HashMap<String, Entry>,
+    /// Logical clock for LRU ordering.
+    tick: u64,
+}
+
+/// Memoizes host name lookups for a bounded time, caching failures for a shorter time,
+/// evicting the least recently used host once `max_entries` is reached. Concurrent
+/// lookups of the same host share a single resolution.
+pub struct ResolverCache {
+    max_entries: usize,
+    ttl: Duration,
+    negative_ttl: Duration,
+    resolver: Box<Resolver>,
+    state: Mutex<State>,
+    resolved: Condvar,
+}
+
+impl ResolverCache {
+    /// Creates a cache that resolves with the system resolver (`getaddrinfo`).
+    pub fn new(max_entries: usize, ttl: Duration, negative_ttl: Duration) -> ResolverCache {
+        Self::with_resolver(max_entries, ttl, negative_ttl, |host| {
+            (host, 0)
+                .to_socket_addrs()
+                .map(|addrs| addrs.map(|addr| addr.ip()).collect())
+        })
+    }
+
+    pub fn with_resolver<F>(
+        max_entries: usize,
+        ttl: Duration,
+        negative_ttl: Duration,
+        resolver: F,
+    ) -> ResolverCache
+    where
+        F: Fn(&str) -> io::Result<Vec<IpAddr>> + Send + Sync + 'static,
+    {
+        ResolverCache {
+            max_entries: max_entries.max(1),
+            ttl,
+            negative_ttl,
+            resolver: Box::new(resolver),
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                tick: 0,
+            }),
+            resolved: Condvar::new(),
+        }
+    }
+
+    /// A process-wide cache using the system resolver, created on first use.
+    pub fn global() -> &'static ResolverCache {
+        static GLOBAL: OnceLock<ResolverCache> = OnceLock::new();
+        GLOBAL.get_or_init(|| {
+            ResolverCache::new(1024, Duration::from_secs(60), Duration::from_secs(5))
+        })
+    }
+
+    pub fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            state.tick += 1;
+            let tick = state.tick;
+            match state.entries.get_mut(host) {
+                Some(Entry::InFlight) => {
+                    state = self.resolved.wait(state).unwrap();
+                }
+                Some(Entry::Resolved {
+                    result,
+                    expires,
+                    last_used,
+                }) if *expires > Instant::now() => {
+                    *last_used = tick;
+                    return result
+                        .clone()
+                        .map_err(|(kind, msg)| io::Error::new(kind, msg));
+                }
+                _ => break,
+            }
+        }
+        state.entries.insert(host.to_owned(), Entry::InFlight);
+        drop(state);
+
+        let mut in_flight = InFlight {
+            cache: self,
+            host,
+            done: false,
+        };
+        let result = (self.resolver)(host);
+        let cached = match &result {
+            Ok(ips) => Ok(ips.clone()),
+            Err(err) => Err((err.kind(), err.to_string())),
+        };
+        in_flight.finish(cached);
+        result
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .entries
+            .retain(|_, entry| matches!(entry, Entry::InFlight));
+    }
+}
+
+/// Removes the in-flight marker if the resolver panics so waiters don't block forever.
+struct InFlight<'a> {
+    cache: &'a ResolverCache,
+    host: &'a str,
+    done: bool,
+}
+
+impl InFlight<'_> {
+    fn finish(&mut self, result: CachedResult) {
+        let cache = self.cache;
+        let ttl = match result {
+            Ok(_) => cache.ttl,
+            Err(_) => cache.negative_ttl,
+        };
+
+        let mut state = cache.state.lock().unwrap();
+        state.tick += 1;
+        let entry = Entry::Resolved {
+            result,
+            expires: Instant::now() + ttl,
+            last_used: state.tick,
+        };
+        state.entries.insert(self.host.to_owned(), entry);
+
+        while state.entries.len() > cache.max_entries {
+            let oldest = state
+                .entries
+                .iter()
+                .filter_map(|(host, entry)| match entry {
+                    Entry::Resolved { last_used, .. } => Some((*last_used, host)),
+                    Entry::InFlight => None,
+                })
+                .min()
+                .map(|(_, host)| host.clone());
+            match oldest {
+                Some(host) => state.entries.remove(&host),
+                None => break,
+            };
+        }
+
+        self.done = true;
+        cache.resolved.notify_all();
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            if let Ok(mut state) = self.cache.state.lock() {
+                state.entries.remove(self.host);
+            }
+            self.cache.resolved.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Barrier},
+        thread,
+    };
+
+    use super::*;
+
+    /// A cache whose resolver counts lookups per host and fails for hosts starting with
+    /// "bad", taking `delay` for each.
+    fn counting_cache(
+        max_entries: usize,
+        ttl: Duration,
+        negative_ttl: Duration,
+        delay: Duration,
+    ) -> (ResolverCache, Arc<Mutex<HashMap<String, usize>>>) {
+        let counts = Arc::new(Mutex::new(HashMap::new()));
+        let cache = ResolverCache::with_resolver(max_entries, ttl, negative_ttl, {
+            let counts = counts.clone();
+            move |host| {
+                *counts.lock().unwrap().entry(host.to_owned()).or_insert(0) += 1;
+                thread::sleep(delay);
+                if host.starts_with("bad") {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, "no such host"));
+                }
+                Ok(vec![IpAddr::from([10, 0, 0, host.len() as u8])])
+            }
+        });
+        (cache, counts)
+    }
+
+    fn count(counts: &Mutex<HashMap<String, usize>>, host: &str) -> usize {
+        counts.lock().unwrap().get(host).copied().unwrap_or(0)
+    }
+
+    const LONG: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn repeated_lookups_hit_the_cache() {
+        let (cache, counts) = counting_cache(8, LONG, LONG, Duration::ZERO);
+        let ips = cache.lookup("a.test").unwrap();
+        assert_eq!(ips, vec![IpAddr::from([10, 0, 0, 6])]);
+        assert_eq!(cache.lookup("a.test").unwrap(), ips);
+        assert_eq!(count(&counts, "a.test"), 1);
+        assert_eq!(cache.len(), 1);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        cache.lookup("a.test").unwrap();
+        assert_eq!(count(&counts, "a.test"), 2);
+    }
+
+    #[test]
+    fn concurrent_lookups_resolve_once() {
+        let (cache, counts) = counting_cache(8, LONG, LONG, Duration::from_millis(50));
+        let barrier = Barrier::new(8);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    barrier.wait();
+                    assert_eq!(
+                        cache.lookup("a.test").unwrap(),
+                        vec![IpAddr::from([10, 0, 0, 6])]
+                    );
+                });
+            }
+        });
+        assert_eq!(count(&counts, "a.test"), 1);
+    }
+
+    #[test]
+    fn the_least_recently_used_host_is_evicted() {
+        let (cache, counts) = counting_cache(2, LONG, LONG, Duration::ZERO);
+        cache.lookup("a.test").unwrap();
+        cache.lookup("b.test").unwrap();
+        cache.lookup("a.test").unwrap();
+        cache.lookup("c.test").unwrap();
+        assert_eq!(cache.len(), 2);
+
+        cache.lookup("a.test").unwrap();
+        cache.lookup("c.test").unwrap();
+        assert_eq!(count(&counts, "a.test"), 1);
+        assert_eq!(count(&counts, "c.test"), 1);
+        cache.lookup("b.test").unwrap();
+        assert_eq!(count(&counts, "b.test"), 2);
+    }
+
+    #[test]
+    fn entries_expire_after_the_ttl() {
+        let ttl = Duration::from_millis(50);
+        let (cache, counts) = counting_cache(8, ttl, LONG, Duration::ZERO);
+        cache.lookup("a.test").unwrap();
+        cache.lookup("a.test").unwrap();
+        assert_eq!(count(&counts, "a.test"), 1);
+        thread::sleep(ttl * 2);
+        cache.lookup("a.test").unwrap();
+        assert_eq!(count(&counts, "a.test"), 2);
+    }
+
+    #[test]
+    fn failures_are_cached_for_the_negative_ttl() {
+        let negative_ttl = Duration::from_millis(50);
+        let (cache, counts) = counting_cache(8, LONG, negative_ttl, Duration::ZERO);
+        for _ in 0..2 {
+            let err = cache.lookup("bad.test").unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+            assert!(err.to_string().contains("no such host"));
+        }
+        assert_eq!(count(&counts, "bad.test"), 1);
+
+        thread::sleep(negative_ttl * 2);
+        assert!(cache.lookup("bad.test").is_err());
+        assert_eq!(count(&counts, "bad.test"), 2);
+        // Successes keep the longer TTL meanwhile.
+        cache.lookup("a.test").unwrap();
+        thread::sleep(negative_ttl * 2);
+        cache.lookup("a.test").unwrap();
+        assert_eq!(count(&counts, "a.test"), 1);
+    }
+}
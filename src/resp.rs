@@ -0,0 +1,297 @@
+use std::io;
+
+use crate::codec::Decoded;
+
+/// Default limit on the length of a bulk string, the same as Redis's `proto-max-bulk-len`.
+pub const MAX_BULK: usize = 512 * 1024 * 1024;
+/// Limit on how deeply arrays and maps may nest.
+pub const MAX_DEPTH: usize = 64;
+/// Limit on simple strings, errors and length lines, the same as Redis's limit on inline
+/// commands.
+const MAX_LINE: usize = 64 * 1024;
+
+/// A RESP2 or RESP3 value. RESP3 types beyond maps and null aren't supported and are
+/// rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RespValue {
+    SimpleString(Vec<u8>),
+    Error(Vec<u8>),
+    Integer(i64),
+    /// `None` for the RESP2 null bulk string, `$-1`.
+    BulkString(Option<Vec<u8>>),
+    /// `None` for the RESP2 null array, `*-1`.
+    Array(Option<Vec<RespValue>>),
+    /// Key-value pairs in the order received.
+    Map(Vec<(RespValue, RespValue)>),
+    /// The RESP3 null, `_`.
+    Null,
+}
+
+impl RespValue {
+    pub fn decode(buf: &[u8]) -> io::Result<Decoded<RespValue>> {
+        Self::decode_limited(buf, MAX_BULK)
+    }
+
+    /// Decodes a value, failing with `InvalidData` if any bulk string in it is longer than
+    /// `max_bulk`. Lengths are checked as soon as they arrive.
+    pub fn decode_limited(buf: &[u8], max_bulk: usize) -> io::Result<Decoded<RespValue>> {
+        match decode_at(buf, 0, max_bulk, 0)? {
+            Some((value, end)) => Ok(Decoded::Complete(value, end)),
+            None => Ok(Decoded::Incomplete),
+        }
+    }
+
+    /// Appends the encoded value to `out`, using the RESP2 forms for null bulk strings and
+    /// arrays.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            RespValue::SimpleString(s) => encode_line(out, b'+', s),
+            RespValue::Error(s) => encode_line(out, b'-', s),
+            RespValue::Integer(n) => encode_line(out, b':', n.to_string().as_bytes()),
+            RespValue::BulkString(None) => out.extend_from_slice(b"$-1\r\n"),
+            RespValue::BulkString(Some(s)) => {
+                encode_line(out, b'$', s.len().to_string().as_bytes());
+                out.extend_from_slice(s);
+                out.extend_from_slice(b"\r\n");
+            }
+            RespValue::Array(None) => out.extend_from_slice(b"*-1\r\n"),
+            RespValue::Array(Some(items)) => {
+                encode_line(out, b'*', items.len().to_string().as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+            RespValue::Map(pairs) => {
+                encode_line(out, b'%', pairs.len().to_string().as_bytes());
+                for (key, value) in pairs {
+                    key.encode(out);
+                    value.encode(out);
+                }
+            }
+            RespValue::Null => out.extend_from_slice(b"_\r\n"),
+        }
+    }
+}
+
+fn encode_line(out: &mut Vec<u8>, kind: u8, line: &[u8]) {
+    out.push(kind);
+    out.extend_from_slice(line);
+    out.extend_from_slice(b"\r\n");
+}
+
+/// Decodes the value starting at `start`, returning it and where it ends, or `None` if the
+/// buffer ends first.
+fn decode_at(
+    buf: &[u8],
+    start: usize,
+    max_bulk: usize,
+    depth: usize,
+) -> io::Result<Option<(RespValue, usize)>> {
+    let (kind, line, mut end) = match read_line(buf, start)? {
+        Some(read) => read,
+        None => return Ok(None),
+    };
+    let value = match kind {
+        b'+' => RespValue::SimpleString(line.to_vec()),
+        b'-' => RespValue::Error(line.to_vec()),
+        b':' => RespValue::Integer(parse_int(line)?),
+        b'_' if line.is_empty() => RespValue::Null,
+        b'$' => match parse_len(line)? {
+            None => RespValue::BulkString(None),
+            Some(len) if len > max_bulk => return Err(invalid("bulk string too large")),
+            Some(len) => {
+                let data = match buf.get(end..end + len + 2) {
+                    Some(data) => data,
+                    None => return Ok(None),
+                };
+                if !data.ends_with(b"\r\n") {
+                    return Err(invalid("bulk string not followed by CRLF"));
+                }
+                end += len + 2;
+                RespValue::BulkString(Some(data[..len].to_vec()))
+            }
+        },
+        b'*' | b'%' if depth == MAX_DEPTH => return Err(invalid("values nested too deeply")),
+        b'*' => match parse_len(line)? {
+            None => RespValue::Array(None),
+            Some(count) => {
+                // Not preallocated from `count`, which the peer controls.
+                let mut items = Vec::new();
+                for _ in 0..count {
+                    match decode_at(buf, end, max_bulk, depth + 1)? {
+                        Some((item, item_end)) => {
+                            items.push(item);
+                            end = item_end;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                RespValue::Array(Some(items))
+            }
+        },
+        b'%' => {
+            let count = parse_len(line)?.ok_or_else(|| invalid("negative map length"))?;
+            let mut pairs = Vec::new();
+            for _ in 0..count {
+                let (key, key_end) = match decode_at(buf, end, max_bulk, depth + 1)? {
+                    Some(key) => key,
+                    None => return Ok(None),
+                };
+                let (value, value_end) = match decode_at(buf, key_end, max_bulk, depth + 1)? {
+                    Some(value) => value,
+                    None => return Ok(None),
+                };
+                pairs.push((key, value));
+                end = value_end;
+            }
+            RespValue::Map(pairs)
+        }
+        _ => return Err(invalid("unsupported RESP type")),
+    };
+    Ok(Some((value, end)))
+}
+
+/// Reads the type byte and the rest of the line starting at `start`, returning them and
+/// where the line ends, past its CRLF.
+fn read_line(buf: &[u8], start: usize) -> io::Result<Option<(u8, &[u8], usize)>> {
+    let rest = &buf[start.min(buf.len())..];
+    let window = &rest[..rest.len().min(MAX_LINE + 3)];
+    match window.windows(2).position(|w| w == b"\r\n") {
+        Some(0) => Err(invalid("missing RESP type")),
+        Some(pos) => Ok(Some((rest[0], &rest[1..pos], start + pos + 2))),
+        None if window.len() == MAX_LINE + 3 => Err(invalid("line too long")),
+        None => Ok(None),
+    }
+}
+
+fn parse_int(line: &[u8]) -> io::Result<i64> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid("invalid integer"))
+}
+
+/// Parses a length, where `-1` stands for null.
+fn parse_len(line: &[u8]) -> io::Result<Option<usize>> {
+    match parse_int(line)? {
+        -1 => Ok(None),
+        len if len < 0 => Err(invalid("negative length")),
+        len => usize::try_from(len)
+            .map(Some)
+            .map_err(|_| invalid("length too large")),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bulk(s: &[u8]) -> RespValue {
+        RespValue::BulkString(Some(s.to_vec()))
+    }
+
+    fn encode(value: &RespValue) -> Vec<u8> {
+        let mut buf = Vec::new();
+        value.encode(&mut buf);
+        buf
+    }
+
+    fn sample() -> RespValue {
+        RespValue::Array(Some(vec![
+            RespValue::SimpleString(b"OK".to_vec()),
+            RespValue::Error(b"ERR unknown command".to_vec()),
+            RespValue::Integer(-42),
+            bulk(b"with\r\ninside"),
+            bulk(b""),
+            RespValue::BulkString(None),
+            RespValue::Array(None),
+            RespValue::Array(Some(Vec::new())),
+            RespValue::Map(vec![(bulk(b"key"), RespValue::Integer(1))]),
+            RespValue::Null,
+        ]))
+    }
+
+    #[test]
+    fn round_trips() {
+        let value = sample();
+        let buf = encode(&value);
+        assert_eq!(
+            RespValue::decode(&buf).unwrap(),
+            Decoded::Complete(value, buf.len())
+        );
+    }
+
+    #[test]
+    fn decodes_the_wire_format() {
+        let buf = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n+PONG\r\n";
+        assert_eq!(
+            RespValue::decode(buf).unwrap(),
+            Decoded::Complete(RespValue::Array(Some(vec![bulk(b"GET"), bulk(b"key")])), 22)
+        );
+        assert_eq!(
+            RespValue::decode(&buf[22..]).unwrap(),
+            Decoded::Complete(RespValue::SimpleString(b"PONG".to_vec()), 7)
+        );
+        assert_eq!(encode(&RespValue::BulkString(None)), b"$-1\r\n");
+        assert_eq!(encode(&RespValue::Null), b"_\r\n");
+    }
+
+    #[test]
+    fn waits_for_the_whole_value() {
+        let buf = encode(&sample());
+        for end in 0..buf.len() {
+            assert_eq!(RespValue::decode(&buf[..end]).unwrap(), Decoded::Incomplete);
+        }
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let buf = encode(&bulk(b"hello"));
+        assert!(RespValue::decode_limited(&buf, 5).is_ok());
+        // Rejected from the length alone, before the data arrives.
+        let err = RespValue::decode_limited(b"$6\r\n", 5).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut nested = Vec::new();
+        for _ in 0..MAX_DEPTH {
+            nested.extend_from_slice(b"*1\r\n");
+        }
+        nested.extend_from_slice(b":1\r\n");
+        assert!(matches!(
+            RespValue::decode(&nested).unwrap(),
+            Decoded::Complete(_, _)
+        ));
+        nested.splice(..0, *b"*1\r\n");
+        let err = RespValue::decode(&nested).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // A line without a CRLF fails once it can no longer fit.
+        let mut line = vec![b'+'; MAX_LINE];
+        assert_eq!(RespValue::decode(&line).unwrap(), Decoded::Incomplete);
+        line.extend_from_slice(b"+++");
+        let err = RespValue::decode(&line).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_malformed_values() {
+        let malformed: [&[u8]; 8] = [
+            b"\r\n",
+            b"#t\r\n",
+            b":12a\r\n",
+            b"$-2\r\n",
+            b"$3\r\nabcd\r\n",
+            b"*-5\r\n",
+            b"%-1\r\n",
+            b"_x\r\n",
+        ];
+        for buf in malformed {
+            let err = RespValue::decode(buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", buf);
+        }
+    }
+}
@@ -0,0 +1,526 @@
+use std::{
+    io::{self, IoSliceMut},
+    mem::{size_of, zeroed, MaybeUninit},
+    net::SocketAddr,
+    os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
+    ptr,
+};
+
+use libc::{
+    c_int, c_void, iovec, msghdr, sockaddr_storage, socklen_t, AF_INET, AF_INET6, EPROTONOSUPPORT,
+    ESOCKTNOSUPPORT, IPPROTO_SCTP, MSG_EOR, SOCK_SEQPACKET,
+};
+
+use crate::syscall;
+
+use super::net::{
+    cmsg::{CmsgBuilder, CmsgIter},
+    create_new_socket_with_protocol, setsockopt, sockaddr_bytes, socket_addr, to_socket_addr,
+    Socket,
+};
+
+// Not exported by libc, see linux/sctp.h.
+const SOL_SCTP: c_int = 132;
+const SCTP_SOCKOPT_BINDX_ADD: c_int = 100;
+const SCTP_RECVRCVINFO: c_int = 32;
+const SCTP_EVENT: c_int = 127;
+const SCTP_SNDINFO: c_int = 2;
+const SCTP_RCVINFO: c_int = 3;
+const MSG_NOTIFICATION: c_int = 0x8000;
+const SCTP_ASSOC_CHANGE: u16 = (1 << 15) + 1;
+const SCTP_SHUTDOWN_EVENT: u16 = (1 << 15) + 5;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SctpSndInfo {
+    sid: u16,
+    flags: u16,
+    ppid: u32,
+    context: u32,
+    assoc_id: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SctpRcvInfo {
+    sid: u16,
+    ssn: u16,
+    flags: u16,
+    ppid: u32,
+    tsn: u32,
+    cumtsn: u32,
+    context: u32,
+    assoc_id: i32,
+}
+
+#[repr(C)]
+struct SctpEvent {
+    assoc_id: i32,
+    kind: u16,
+    on: u8,
+}
+
+/// Laid out like the start of the kernel's `struct sctp_assoc_change`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SctpAssocChange {
+    kind: u16,
+    flags: u16,
+    length: u32,
+    state: u16,
+    error: u16,
+    outbound_streams: u16,
+    inbound_streams: u16,
+    assoc_id: i32,
+}
+
+/// Laid out like the kernel's `struct sctp_shutdown_event`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SctpShutdownEvent {
+    kind: u16,
+    flags: u16,
+    length: u32,
+    assoc_id: i32,
+}
+
+/// Notifications that can be subscribed to with [`SctpSocket::subscribe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SctpEventKind {
+    AssocChange,
+    Shutdown,
+}
+
+/// The new state reported by an association change notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssocState {
+    CommUp,
+    CommLost,
+    Restart,
+    ShutdownComplete,
+    CantStartAssoc,
+    Unknown(u16),
+}
+
+impl From<u16> for AssocState {
+    fn from(state: u16) -> AssocState {
+        match state {
+            0 => AssocState::CommUp,
+            1 => AssocState::CommLost,
+            2 => AssocState::Restart,
+            3 => AssocState::ShutdownComplete,
+            4 => AssocState::CantStartAssoc,
+            state => AssocState::Unknown(state),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SctpNotification {
+    AssocChange {
+        assoc_id: i32,
+        state: AssocState,
+        outbound_streams: u16,
+        inbound_streams: u16,
+    },
+    /// The peer has begun shutting the association down.
+    Shutdown { assoc_id: i32 },
+    /// A notification of a type not parsed here, or one cut short by a small buffer.
+    Other { kind: u16 },
+}
+
+/// What [`SctpSocket::recv`] received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SctpRecv {
+    Message {
+        len: usize,
+        from: SocketAddr,
+        stream: u16,
+        ppid: u32,
+        assoc_id: i32,
+        /// False if the message didn't fit in the buffer, in which case the next receive
+        /// returns the rest of it.
+        complete: bool,
+    },
+    Notification(SctpNotification),
+}
+
+/// A one-to-many (`SOCK_SEQPACKET`) SCTP socket: a single socket carries associations with
+/// any number of peers, each message naming the peer it is for or from. Associations are
+/// set up implicitly by the first message sent to a peer.
+pub struct SctpSocket {
+    socket: Socket,
+}
+
+impl SctpSocket {
+    /// Creates a socket bound to `addr`. Fails with `Unsupported` if the kernel lacks SCTP,
+    /// e.g. because the `sctp` module isn't loaded.
+    pub fn bind(addr: SocketAddr) -> io::Result<SctpSocket> {
+        let domain = match addr {
+            SocketAddr::V4(_) => AF_INET,
+            SocketAddr::V6(_) => AF_INET6,
+        };
+        let socket = match create_new_socket_with_protocol(domain, SOCK_SEQPACKET, IPPROTO_SCTP) {
+            Ok(socket) => SctpSocket { socket },
+            Err(err)
+                if matches!(
+                    err.raw_os_error(),
+                    Some(EPROTONOSUPPORT) | Some(ESOCKTNOSUPPORT)
+                ) =>
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "SCTP is not supported by the kernel",
+                ));
+            }
+            Err(err) => return Err(err),
+        };
+
+        let (raw_addr, raw_addr_length) = socket_addr(&addr);
+        syscall!(bind(socket.as_raw_fd(), raw_addr.as_ptr(), raw_addr_length))?;
+        setsockopt(socket.as_raw_fd(), SOL_SCTP, SCTP_RECVRCVINFO, 1 as c_int)?;
+        Ok(socket)
+    }
+
+    /// Adds more local addresses for multihoming. They must share the port of the address
+    /// passed to `bind`.
+    pub fn bindx(&self, addrs: &[SocketAddr]) -> io::Result<()> {
+        // The kernel takes the addresses packed back to back, each as long as its family
+        // needs.
+        let packed: Vec<u8> = addrs.iter().flat_map(sockaddr_bytes).collect();
+        syscall!(setsockopt(
+            self.as_raw_fd(),
+            SOL_SCTP,
+            SCTP_SOCKOPT_BINDX_ADD,
+            packed.as_ptr() as *const c_void,
+            packed.len() as socklen_t,
+        ))
+        .map(|_| ())
+    }
+
+    /// Accepts associations initiated by peers. Without it, only associations this socket
+    /// initiates are possible.
+    pub fn listen(&self, backlog: i32) -> io::Result<()> {
+        syscall!(listen(self.as_raw_fd(), backlog)).map(|_| ())
+    }
+
+    /// Turns delivery of a kind of notification through [`recv`](SctpSocket::recv) on or
+    /// off, for all associations. Requires Linux 5.0 or later.
+    pub fn subscribe(&self, kind: SctpEventKind, on: bool) -> io::Result<()> {
+        let kind = match kind {
+            SctpEventKind::AssocChange => SCTP_ASSOC_CHANGE,
+            SctpEventKind::Shutdown => SCTP_SHUTDOWN_EVENT,
+        };
+        let event = SctpEvent {
+            assoc_id: 0,
+            kind,
+            on: on as u8,
+        };
+        setsockopt(self.as_raw_fd(), SOL_SCTP, SCTP_EVENT, event)
+    }
+
+    /// Sends `buf` as one message to `addr` on stream `stream`, setting up an association
+    /// first if there is none. `ppid` is passed through to the peer untouched; by
+    /// convention it is in network byte order.
+    pub fn send_to(
+        &self,
+        buf: &[u8],
+        addr: SocketAddr,
+        stream: u16,
+        ppid: u32,
+    ) -> io::Result<usize> {
+        let (raw_addr, raw_addr_length) = socket_addr(&addr);
+        let mut iov = iovec {
+            iov_base: buf.as_ptr() as *mut c_void,
+            iov_len: buf.len(),
+        };
+        let info = SctpSndInfo {
+            sid: stream,
+            flags: 0,
+            ppid,
+            context: 0,
+            assoc_id: 0,
+        };
+        let mut control = CmsgBuilder::new();
+        unsafe { control.push_value(IPPROTO_SCTP, SCTP_SNDINFO, info) };
+
+        let mut msg: msghdr = unsafe { zeroed() };
+        msg.msg_name = raw_addr.as_ptr() as *mut c_void;
+        msg.msg_namelen = raw_addr_length;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_bytes().as_ptr() as *mut c_void;
+        msg.msg_controllen = control.as_bytes().len() as _;
+        syscall!(sendmsg(self.as_raw_fd(), &msg, 0)).map(|n| n as usize)
+    }
+
+    /// Receives one message or a notification.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<SctpRecv> {
+        let mut addr = MaybeUninit::<sockaddr_storage>::zeroed();
+        let mut control = [0u8; 64];
+        let mut iov = IoSliceMut::new(buf);
+
+        let mut msg: msghdr = unsafe { zeroed() };
+        msg.msg_name = addr.as_mut_ptr() as *mut c_void;
+        msg.msg_namelen = size_of::<sockaddr_storage>() as socklen_t;
+        msg.msg_iov = &mut iov as *mut IoSliceMut as *mut iovec;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = control.len() as _;
+
+        let n = syscall!(recvmsg(self.as_raw_fd(), &mut msg, 0))? as usize;
+        if msg.msg_flags & MSG_NOTIFICATION != 0 {
+            return Ok(SctpRecv::Notification(parse_notification(&buf[..n])));
+        }
+
+        let from = unsafe { to_socket_addr(addr.as_ptr()) }?;
+        let info = CmsgIter::new(&control[..msg.msg_controllen as usize])
+            .find(|cmsg| (cmsg.level, cmsg.kind) == (IPPROTO_SCTP, SCTP_RCVINFO))
+            .and_then(|cmsg| unsafe { cmsg.read::<SctpRcvInfo>() });
+        let info = info.ok_or_else(|| io::Error::other("missing SCTP_RCVINFO"))?;
+
+        Ok(SctpRecv::Message {
+            len: n,
+            from,
+            stream: info.sid,
+            ppid: info.ppid,
+            assoc_id: info.assoc_id,
+            complete: msg.msg_flags & MSG_EOR != 0,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        let mut addr = MaybeUninit::<sockaddr_storage>::zeroed();
+        let mut length = size_of::<sockaddr_storage>() as socklen_t;
+        syscall!(getsockname(
+            self.as_raw_fd(),
+            addr.as_mut_ptr() as *mut _,
+            &mut length
+        ))?;
+        unsafe { to_socket_addr(addr.as_ptr()) }
+    }
+}
+
+fn parse_notification(buf: &[u8]) -> SctpNotification {
+    if buf.len() < 2 {
+        return SctpNotification::Other { kind: 0 };
+    }
+    let kind = u16::from_ne_bytes([buf[0], buf[1]]);
+    match kind {
+        SCTP_ASSOC_CHANGE if buf.len() >= size_of::<SctpAssocChange>() => {
+            let change = unsafe { ptr::read_unaligned(buf.as_ptr() as *const SctpAssocChange) };
+            SctpNotification::AssocChange {
+                assoc_id: change.assoc_id,
+                state: AssocState::from(change.state),
+                outbound_streams: change.outbound_streams,
+                inbound_streams: change.inbound_streams,
+            }
+        }
+        SCTP_SHUTDOWN_EVENT if buf.len() >= size_of::<SctpShutdownEvent>() => {
+            let event = unsafe { ptr::read_unaligned(buf.as_ptr() as *const SctpShutdownEvent) };
+            SctpNotification::Shutdown {
+                assoc_id: event.assoc_id,
+            }
+        }
+        kind => SctpNotification::Other { kind },
+    }
+}
+
+impl AsRawFd for SctpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for SctpSocket {
+    fn into_raw_fd(self) -> RawFd {
+        self.socket.into_raw_fd()
+    }
+}
+
+impl FromRawFd for SctpSocket {
+    unsafe fn from_raw_fd(fd: RawFd) -> SctpSocket {
+        SctpSocket {
+            socket: Socket::from_raw_fd(fd),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Binds a socket to a free loopback port, or returns `None` if the kernel lacks SCTP.
+    fn socket() -> Option<SctpSocket> {
+        match SctpSocket::bind("127.0.0.1:0".parse().unwrap()) {
+            Ok(socket) => Some(socket),
+            Err(err) if err.kind() == io::ErrorKind::Unsupported => None,
+            Err(err) => panic!("bind failed: {err}"),
+        }
+    }
+
+    fn message(socket: &SctpSocket, buf: &mut [u8]) -> SctpRecv {
+        loop {
+            match socket.recv(buf).unwrap() {
+                SctpRecv::Notification(_) => continue,
+                message => return message,
+            }
+        }
+    }
+
+    #[test]
+    fn messages_carry_the_stream_and_ppid() {
+        let (Some(server), Some(client)) = (socket(), socket()) else {
+            return;
+        };
+        server.listen(8).unwrap();
+        let ppid = 42u32.to_be();
+        assert_eq!(
+            client
+                .send_to(b"hello", server.local_addr().unwrap(), 1, ppid)
+                .unwrap(),
+            5
+        );
+
+        let mut buf = [0; 16];
+        match message(&server, &mut buf) {
+            SctpRecv::Message {
+                len,
+                from,
+                stream,
+                ppid: got,
+                complete,
+                ..
+            } => {
+                assert_eq!(&buf[..len], b"hello");
+                assert_eq!(from, client.local_addr().unwrap());
+                assert_eq!((stream, got, complete), (1, ppid, true));
+            }
+            other => panic!("expected a message, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn a_message_larger_than_the_buffer_arrives_in_parts() {
+        let (Some(server), Some(client)) = (socket(), socket()) else {
+            return;
+        };
+        server.listen(8).unwrap();
+        client
+            .send_to(b"0123456789", server.local_addr().unwrap(), 0, 0)
+            .unwrap();
+
+        let mut buf = [0; 4];
+        assert!(matches!(
+            message(&server, &mut buf),
+            SctpRecv::Message {
+                len: 4,
+                complete: false,
+                ..
+            }
+        ));
+        let mut rest = [0; 16];
+        match message(&server, &mut rest) {
+            SctpRecv::Message { len, complete, .. } => {
+                assert_eq!(&rest[..len], b"456789");
+                assert!(complete);
+            }
+            other => panic!("expected a message, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn subscribed_sockets_see_the_association_come_up() {
+        let (Some(server), Some(client)) = (socket(), socket()) else {
+            return;
+        };
+        server.listen(8).unwrap();
+        server.subscribe(SctpEventKind::AssocChange, true).unwrap();
+        client
+            .send_to(b"x", server.local_addr().unwrap(), 0, 0)
+            .unwrap();
+
+        let mut buf = [0; 256];
+        assert!(matches!(
+            server.recv(&mut buf).unwrap(),
+            SctpRecv::Notification(SctpNotification::AssocChange {
+                state: AssocState::CommUp,
+                ..
+            })
+        ));
+        assert!(matches!(
+            server.recv(&mut buf).unwrap(),
+            SctpRecv::Message { len: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn bindx_rejects_a_different_port() {
+        let Some(socket) = socket() else {
+            return;
+        };
+        let port = socket.local_addr().unwrap().port();
+        let other: SocketAddr = format!("127.0.0.2:{}", port.wrapping_add(1))
+            .parse()
+            .unwrap();
+        assert!(socket.bindx(&[other]).is_err());
+    }
+
+    #[test]
+    fn notifications_are_parsed_by_kind() {
+        let change = SctpAssocChange {
+            kind: SCTP_ASSOC_CHANGE,
+            flags: 0,
+            length: size_of::<SctpAssocChange>() as u32,
+            state: 1,
+            error: 0,
+            outbound_streams: 10,
+            inbound_streams: 5,
+            assoc_id: 7,
+        };
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &change as *const SctpAssocChange as *const u8,
+                size_of::<SctpAssocChange>(),
+            )
+        };
+        assert_eq!(
+            parse_notification(bytes),
+            SctpNotification::AssocChange {
+                assoc_id: 7,
+                state: AssocState::CommLost,
+                outbound_streams: 10,
+                inbound_streams: 5,
+            }
+        );
+        // Cut short, only the kind can be read.
+        assert_eq!(
+            parse_notification(&bytes[..8]),
+            SctpNotification::Other {
+                kind: SCTP_ASSOC_CHANGE
+            }
+        );
+
+        let shutdown = SctpShutdownEvent {
+            kind: SCTP_SHUTDOWN_EVENT,
+            flags: 0,
+            length: size_of::<SctpShutdownEvent>() as u32,
+            assoc_id: 3,
+        };
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &shutdown as *const SctpShutdownEvent as *const u8,
+                size_of::<SctpShutdownEvent>(),
+            )
+        };
+        assert_eq!(
+            parse_notification(bytes),
+            SctpNotification::Shutdown { assoc_id: 3 }
+        );
+        assert_eq!(
+            parse_notification(&[1]),
+            SctpNotification::Other { kind: 0 }
+        );
+        assert_eq!(AssocState::from(9), AssocState::Unknown(9));
+    }
+}
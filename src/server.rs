@@ -0,0 +1,414 @@
+use std::{
+    io,
+    mem::{size_of, zeroed, MaybeUninit},
+    net::SocketAddr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    ptr,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use libc::{
+    c_void, signalfd_siginfo, sigset_t, EINVAL, POLLIN, SFD_CLOEXEC, SHUT_RDWR, SIGINT, SIGTERM,
+    SIG_BLOCK, SIG_SETMASK,
+};
+
+use crate::{
+    net::poll_fd,
+    syscall,
+    tcp::{AcceptBackoff, CloseMode, ConnLimiter, ListenerConfig, TcpListener, TcpStream},
+};
+
+type Handler = dyn Fn(TcpStream) + Send + Sync;
+type AcceptErrorHandler = dyn Fn(&io::Error, Duration) + Send + Sync;
+
+/// A ready-made TCP server: binds, accepts on `workers` threads and hands each connection
+/// to the `on_connection` handler until the process receives `SIGTERM` or `SIGINT`, then
+/// stops accepting and waits for the handlers in progress to return.
+///
+/// Each connection is handled on the worker thread that accepted it, so `workers` bounds
+/// how many are handled at once. Long-lived connections should be handed off elsewhere.
+pub struct Server {
+    addr: SocketAddr,
+    workers: usize,
+    reuseport: bool,
+    on_connection: Option<Arc<Handler>>,
+    on_shutdown: Option<Box<dyn FnOnce() + Send>>,
+    backoff: AcceptBackoff,
+    on_accept_error: Option<Arc<AcceptErrorHandler>>,
+    limiter: Option<ConnLimiter>,
+}
+
+impl Server {
+    /// Starts configuring a server on `addr`, with one worker per CPU.
+    pub fn bind(addr: SocketAddr) -> Server {
+        Server {
+            addr,
+            workers: thread::available_parallelism().map_or(1, |n| n.get()),
+            reuseport: false,
+            on_connection: None,
+            on_shutdown: None,
+            backoff: AcceptBackoff::default(),
+            on_accept_error: None,
+            limiter: None,
+        }
+    }
+
+    pub fn workers(mut self, workers: usize) -> Server {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Gives every worker its own listener in one `SO_REUSEPORT` group, letting the kernel
+    /// spread connections across them instead of all workers contending for one.
+    pub fn reuseport(mut self, reuseport: bool) -> Server {
+        self.reuseport = reuseport;
+        self
+    }
+
+    /// Called with each accepted connection, already set to `TCP_NODELAY`.
+    pub fn on_connection(mut self, handler: impl Fn(TcpStream) + Send + Sync + 'static) -> Server {
+        self.on_connection = Some(Arc::new(handler));
+        self
+    }
+
+    /// Called once all workers have finished, just before `run` returns.
+    pub fn on_shutdown(mut self, f: impl FnOnce() + Send + 'static) -> Server {
+        self.on_shutdown = Some(Box::new(f));
+        self
+    }
+
+    /// How long workers stop accepting when out of file descriptors or memory.
+    pub fn accept_backoff(mut self, backoff: AcceptBackoff) -> Server {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Called with each failed accept and how long the worker will pause before the next
+    /// one, zero unless the error calls for backing off.
+    pub fn on_accept_error(
+        mut self,
+        f: impl Fn(&io::Error, Duration) + Send + Sync + 'static,
+    ) -> Server {
+        self.on_accept_error = Some(Arc::new(f));
+        self
+    }
+
+    /// Resets connections from clients that already have as many open as `limiter` allows.
+    /// A connection counts until its handler returns.
+    pub fn conn_limiter(mut self, limiter: ConnLimiter) -> Server {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// Serves until `SIGTERM` or `SIGINT`. The signals are received through a `signalfd`,
+    /// which only works if they're blocked in every thread, so call this from the main
+    /// thread before starting any others; it blocks them for the threads it starts itself.
+    pub fn run(self) -> io::Result<()> {
+        let handler = self.on_connection.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no connection handler set")
+        })?;
+
+        let (signals, old_mask) = block_shutdown_signals()?;
+        let worker = Worker {
+            handler,
+            backoff: self.backoff,
+            on_accept_error: self.on_accept_error,
+            limiter: self.limiter,
+        };
+        let result = serve(self.addr, self.workers, self.reuseport, worker, &signals);
+        unsafe { libc::pthread_sigmask(SIG_SETMASK, &old_mask, ptr::null_mut()) };
+        result?;
+
+        if let Some(on_shutdown) = self.on_shutdown {
+            on_shutdown();
+        }
+        Ok(())
+    }
+}
+
+/// What every worker thread shares.
+struct Worker {
+    handler: Arc<Handler>,
+    backoff: AcceptBackoff,
+    on_accept_error: Option<Arc<AcceptErrorHandler>>,
+    limiter: Option<ConnLimiter>,
+}
+
+fn serve(
+    addr: SocketAddr,
+    workers: usize,
+    reuseport: bool,
+    worker: Worker,
+    signals: &OwnedFd,
+) -> io::Result<()> {
+    let config = ListenerConfig {
+        reuseport,
+        ..ListenerConfig::default()
+    };
+    let first = TcpListener::bind_config(addr, &config)?;
+    let mut listeners = vec![Arc::new(first)];
+    if reuseport {
+        // Binds the rest to the port the first one got, in case `addr` left it to the kernel.
+        let addr = listeners[0].local_addr()?;
+        for _ in 1..workers {
+            listeners.push(Arc::new(TcpListener::bind_config(addr, &config)?));
+        }
+    }
+
+    let worker = Arc::new(worker);
+    let threads: Vec<_> = (0..workers)
+        .map(|i| {
+            let listener = Arc::clone(&listeners[i % listeners.len()]);
+            let worker = Arc::clone(&worker);
+            thread::spawn(move || worker.accept_loop(&listener))
+        })
+        .collect();
+
+    let mut info = MaybeUninit::<signalfd_siginfo>::uninit();
+    let waited = loop {
+        match syscall!(read(
+            signals.as_raw_fd(),
+            info.as_mut_ptr() as *mut c_void,
+            size_of::<signalfd_siginfo>()
+        )) {
+            Ok(_) => break Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => break Err(err),
+        }
+    };
+
+    // Wakes the workers, whose next accept then fails with EINVAL.
+    for listener in &listeners {
+        let _ = syscall!(shutdown(listener.as_raw_fd(), SHUT_RDWR));
+    }
+    for thread in threads {
+        // A panicking handler only takes down its own worker.
+        let _ = thread.join();
+    }
+    waited
+}
+
+impl Worker {
+    fn accept_loop(&self, listener: &TcpListener) {
+        let mut failures = 0;
+        loop {
+            let err = match listener.accept() {
+                Ok((stream, addr)) => {
+                    failures = 0;
+                    let permit = match &self.limiter {
+                        Some(limiter) => match limiter.try_acquire(addr.ip()) {
+                            Some(permit) => Some(permit),
+                            None => {
+                                let _ = stream.reject(None, CloseMode::Reset);
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+                    let _ = stream.set_nodelay(true);
+                    (self.handler)(stream);
+                    drop(permit);
+                    continue;
+                }
+                Err(err) => err,
+            };
+            if err.kind() == io::ErrorKind::WouldBlock {
+                if poll_fd(listener.as_raw_fd(), POLLIN, None).is_err() {
+                    return;
+                }
+                continue;
+            }
+            // The listener was shut down.
+            if err.raw_os_error() == Some(EINVAL) {
+                return;
+            }
+
+            let delay = if AcceptBackoff::applies_to(&err) {
+                failures += 1;
+                self.backoff.delay(failures)
+            } else {
+                Duration::ZERO
+            };
+            if let Some(on_accept_error) = &self.on_accept_error {
+                on_accept_error(&err, delay);
+            }
+            if !delay.is_zero() {
+                // Waits without accept interest; a shutdown still ends the wait early,
+                // being reported as POLLHUP.
+                if poll_fd(listener.as_raw_fd(), 0, Some(delay)).is_err() {
+                    thread::sleep(delay);
+                }
+            }
+        }
+    }
+}
+
+/// Blocks `SIGTERM` and `SIGINT` for the calling thread, returning a `signalfd` for them and
+/// the previous signal mask.
+fn block_shutdown_signals() -> io::Result<(OwnedFd, sigset_t)> {
+    let mut set: sigset_t = unsafe { zeroed() };
+    let mut old_mask: sigset_t = unsafe { zeroed() };
+    let err = unsafe {
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, SIGTERM);
+        libc::sigaddset(&mut set, SIGINT);
+        libc::pthread_sigmask(SIG_BLOCK, &set, &mut old_mask)
+    };
+    if err != 0 {
+        return Err(io::Error::from_raw_os_error(err));
+    }
+
+    match syscall!(signalfd(-1, &set, SFD_CLOEXEC)) {
+        Ok(fd) => Ok((unsafe { OwnedFd::from_raw_fd(fd) }, old_mask)),
+        Err(err) => {
+            unsafe { libc::pthread_sigmask(SIG_SETMASK, &old_mask, ptr::null_mut()) };
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{self, Ipv4Addr},
+        os::unix::thread::JoinHandleExt,
+        sync::{mpsc, Mutex},
+        time::Instant,
+    };
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A free port on an address other tests don't bind.
+    fn free_addr() -> SocketAddr {
+        let addr = SocketAddr::from((Ipv4Addr::new(127, 0, 0, 4), 0));
+        TcpListener::bind(addr).unwrap().local_addr().unwrap()
+    }
+
+    /// Connects once the server is listening.
+    fn connect(addr: SocketAddr) -> net::TcpStream {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            match net::TcpStream::connect(addr) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+                    return stream;
+                }
+                Err(err) if Instant::now() < deadline => {
+                    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+                    thread::sleep(Duration::from_millis(5));
+                }
+                Err(err) => panic!("connect failed: {err}"),
+            }
+        }
+    }
+
+    /// Runs `server` on a thread of its own, with `SIGTERM` blocked from the start so that
+    /// it waits for the signalfd to read it however early it is sent.
+    fn spawn(server: Server) -> thread::JoinHandle<io::Result<()>> {
+        thread::spawn(move || {
+            unsafe {
+                let mut set: sigset_t = zeroed();
+                libc::sigemptyset(&mut set);
+                libc::sigaddset(&mut set, SIGTERM);
+                libc::pthread_sigmask(SIG_BLOCK, &set, ptr::null_mut());
+            }
+            server.run()
+        })
+    }
+
+    fn terminate(server: &thread::JoinHandle<io::Result<()>>) {
+        let err = unsafe { libc::pthread_kill(server.as_pthread_t(), SIGTERM) };
+        assert_eq!(err, 0);
+    }
+
+    #[test]
+    fn sigterm_lets_handlers_in_progress_finish() {
+        let addr = free_addr();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (started_tx, started) = mpsc::channel();
+        let started_tx = Mutex::new(started_tx);
+        let server = Server::bind(addr)
+            .workers(2)
+            .on_connection({
+                let events = Arc::clone(&events);
+                move |mut stream| {
+                    started_tx.lock().unwrap().send(()).unwrap();
+                    thread::sleep(Duration::from_millis(50));
+                    stream.write_all(b"bye").unwrap();
+                    events.lock().unwrap().push("handled");
+                }
+            })
+            .on_shutdown({
+                let events = Arc::clone(&events);
+                move || events.lock().unwrap().push("shut down")
+            });
+        let server = spawn(server);
+
+        let mut client = connect(addr);
+        started.recv_timeout(TIMEOUT).unwrap();
+        terminate(&server);
+        server.join().unwrap().unwrap();
+
+        assert_eq!(*events.lock().unwrap(), ["handled", "shut down"]);
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"bye");
+        assert_eq!(
+            net::TcpStream::connect(addr).unwrap_err().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+    }
+
+    #[test]
+    fn connections_over_the_limit_are_reset() {
+        let addr = free_addr();
+        let (release_tx, release) = mpsc::channel::<()>();
+        let release = Mutex::new(release);
+        let limiter = ConnLimiter::new(1);
+        let server = Server::bind(addr)
+            .workers(2)
+            .conn_limiter(limiter.clone())
+            .on_connection(move |mut stream| {
+                stream.write_all(b"hi").unwrap();
+                let _ = release.lock().unwrap().recv_timeout(TIMEOUT);
+            });
+        let server = spawn(server);
+
+        let mut first = connect(addr);
+        let mut buf = [0; 2];
+        first.read_exact(&mut buf).unwrap();
+        let client_ip = first.local_addr().unwrap().ip();
+        assert_eq!(limiter.active(client_ip), 1);
+
+        let mut second = connect(addr);
+        assert_eq!(
+            second.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+
+        // Once the first handler returns, its client's slot is free again.
+        release_tx.send(()).unwrap();
+        let deadline = Instant::now() + TIMEOUT;
+        while limiter.active(client_ip) > 0 {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut third = connect(addr);
+        third.read_exact(&mut buf).unwrap();
+        release_tx.send(()).unwrap();
+
+        terminate(&server);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn run_requires_a_connection_handler() {
+        let err = Server::bind(free_addr()).run().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,159 @@
+/// A fixed-capacity slab for connection state, for designs that don't allocate after
+/// startup. Entries live inline in an array of `N` slots and are keyed by slot index,
+/// convenient as an epoll token. Freed slots are reused, most recently freed first.
+pub struct StaticSlab<T, const N: usize> {
+    entries: [Entry<T>; N],
+    /// The first vacant slot, `N` when full.
+    next_free: usize,
+    len: usize,
+}
+
+enum Entry<T> {
+    Occupied(T),
+    /// Links to the next vacant slot.
+    Vacant(usize),
+}
+
+impl<T, const N: usize> StaticSlab<T, N> {
+    pub fn new() -> StaticSlab<T, N> {
+        StaticSlab {
+            entries: std::array::from_fn(|i| Entry::Vacant(i + 1)),
+            next_free: 0,
+            len: 0,
+        }
+    }
+
+    /// Stores `value` and returns its key, or hands `value` back if every slot is taken.
+    pub fn insert(&mut self, value: T) -> Result<usize, T> {
+        let key = self.next_free;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                if let Entry::Vacant(next) = *entry {
+                    self.next_free = next;
+                }
+                *entry = Entry::Occupied(value);
+                self.len += 1;
+                Ok(key)
+            }
+            None => Err(value),
+        }
+    }
+
+    pub fn get(&self, key: usize) -> Option<&T> {
+        match self.entries.get(key) {
+            Some(Entry::Occupied(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, key: usize) -> Option<&mut T> {
+        match self.entries.get_mut(key) {
+            Some(Entry::Occupied(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn remove(&mut self, key: usize) -> Option<T> {
+        let entry = self.entries.get_mut(key)?;
+        if let Entry::Vacant(_) = entry {
+            return None;
+        }
+        let entry = std::mem::replace(entry, Entry::Vacant(self.next_free));
+        self.next_free = key;
+        self.len -= 1;
+        match entry {
+            Entry::Occupied(value) => Some(value),
+            Entry::Vacant(_) => unreachable!(),
+        }
+    }
+
+    pub fn contains(&self, key: usize) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T, const N: usize> Default for StaticSlab<T, N> {
+    fn default() -> Self {
+        StaticSlab::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_fails_once_full() {
+        let mut slab = StaticSlab::<&str, 3>::new();
+        assert!(slab.is_empty());
+        assert_eq!(slab.capacity(), 3);
+        assert_eq!(slab.insert("a"), Ok(0));
+        assert_eq!(slab.insert("b"), Ok(1));
+        assert!(!slab.is_full());
+        assert_eq!(slab.insert("c"), Ok(2));
+        assert!(slab.is_full());
+        assert_eq!(slab.len(), 3);
+        assert_eq!(slab.insert("d"), Err("d"));
+        assert_eq!(slab.len(), 3);
+        assert_eq!(slab.get(1), Some(&"b"));
+        assert_eq!(slab.get(3), None);
+
+        let mut none = StaticSlab::<u8, 0>::new();
+        assert!(none.is_full());
+        assert_eq!(none.insert(1), Err(1));
+    }
+
+    #[test]
+    fn freed_keys_are_reused_most_recent_first() {
+        let mut slab = StaticSlab::<u32, 4>::new();
+        for value in 0..4 {
+            slab.insert(value).unwrap();
+        }
+        assert_eq!(slab.remove(1), Some(1));
+        assert_eq!(slab.remove(3), Some(3));
+        assert_eq!(slab.len(), 2);
+        assert!(!slab.is_full());
+
+        assert_eq!(slab.insert(30), Ok(3));
+        assert_eq!(slab.insert(10), Ok(1));
+        assert!(slab.is_full());
+        assert_eq!(slab.get(1), Some(&10));
+        assert_eq!(slab.get(3), Some(&30));
+    }
+
+    #[test]
+    fn removed_keys_are_gone() {
+        let mut slab = StaticSlab::<String, 2>::new();
+        let key = slab.insert("conn".to_string()).unwrap();
+        slab.get_mut(key).unwrap().push('!');
+        assert_eq!(slab.remove(key).as_deref(), Some("conn!"));
+
+        assert_eq!(slab.get(key), None);
+        assert_eq!(slab.get_mut(key), None);
+        assert!(!slab.contains(key));
+        // A second remove must not free the slot twice.
+        assert_eq!(slab.remove(key), None);
+        assert_eq!(slab.remove(7), None);
+        assert!(slab.is_empty());
+
+        assert_eq!(slab.insert("a".to_string()), Ok(key));
+        assert_eq!(slab.insert("b".to_string()), Ok(1));
+        assert_eq!(slab.insert("c".to_string()), Err("c".to_string()));
+    }
+}
@@ -1,20 +1,56 @@
 use std::{
+    collections::HashMap,
     io::{self, IoSlice, IoSliceMut, Read, Write},
     mem::{size_of, MaybeUninit},
-    net::{self, Shutdown, SocketAddr},
-    os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
+    net::{self, IpAddr, Shutdown, SocketAddr},
+    ops::RangeInclusive,
+    os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+    ptr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use libc::{
-    c_int, c_void, sockaddr_storage, socklen_t, AF_INET, AF_INET6, EINPROGRESS, SOCK_CLOEXEC,
-    SOCK_NONBLOCK, SOCK_STREAM, SOL_SOCKET, SO_REUSEADDR,
+    c_int, c_void, epoll_event, sock_filter, sock_fprog, sockaddr_storage, socklen_t, tcp_info,
+    Ioctl, AF_INET, AF_INET6, EADDRNOTAVAIL, EBADF, ECONNABORTED, ECONNRESET, EINPROGRESS, EINVAL,
+    EMFILE, ENFILE, ENOBUFS, ENOMEM, EOPNOTSUPP, EPERM, EPIPE, EPOLLOUT, EPOLL_CLOEXEC,
+    EPOLL_CTL_ADD, EPOLL_CTL_DEL, FIONREAD, IPPROTO_IPV6, IPPROTO_TCP, IPV6_V6ONLY, IP_FREEBIND,
+    MSG_DONTWAIT, MSG_FASTOPEN, MSG_NOSIGNAL, MSG_PEEK, POLLIN, POLLOUT, SOCK_CLOEXEC,
+    SOCK_NONBLOCK, SOCK_STREAM, SOL_IP, SOL_SOCKET, SO_ATTACH_REUSEPORT_CBPF, SO_BUSY_POLL,
+    SO_DONTROUTE, SO_LINGER, SO_PREFER_BUSY_POLL, SO_RCVBUF, SO_RCVLOWAT, SO_REUSEADDR,
+    SO_REUSEPORT, SO_SNDBUF, TCP_CORK, TCP_DEFER_ACCEPT, TCP_FASTOPEN, TCP_INFO, TCP_MAXSEG,
+    TCP_NOTSENT_LOWAT, TCP_QUEUE_SEQ, TCP_REPAIR, TCP_REPAIR_QUEUE, TCP_REPAIR_WINDOW, TCP_SYNCNT,
+    TCP_TIMESTAMP, TIOCOUTQ,
 };
 
-use crate::syscall;
+use crate::{breaker::CircuitBreaker, resolve::ResolverCache, syscall};
 
-use super::net::{create_new_socket, socket_addr, to_socket_addr};
+use super::net::{
+    cloexec, create_new_socket, getsockopt, mtu_discover, path_mtu, poll_fd, send, send_vectored,
+    set_cloexec, set_mtu_discover, setsockopt, socket_addr, timeout_ms, to_socket_addr, wait_until,
+    DropCleanup, MtuDiscover, RecvBuf, SockFilter, Socket, WriteQueue,
+};
+
+// Not exported by libc.
+const SO_INCOMING_CPU: c_int = 49;
+const SO_ATTACH_REUSEPORT_EBPF: c_int = 52;
+const SO_DETACH_REUSEPORT_BPF: c_int = 68;
+const TCP_RECV_QUEUE: c_int = 1;
+const TCP_SEND_QUEUE: c_int = 2;
+const TCPI_OPT_TIMESTAMPS: u8 = 1;
+const TCPI_OPT_SACK: u8 = 2;
+const TCPI_OPT_WSCALE: u8 = 4;
+const TCPI_OPT_SYN_DATA: u8 = 32;
 
-pub(crate) fn new_for_addr(addr: SocketAddr) -> io::Result<c_int> {
+/// Bounds for [`TcpStream::suggested_read_size`].
+const MIN_READ_SIZE: usize = 4 * 1024;
+const MAX_READ_SIZE: usize = 256 * 1024;
+
+pub(crate) fn new_for_addr(addr: SocketAddr) -> io::Result<Socket> {
     let domain = match addr {
         SocketAddr::V4(_) => AF_INET,
         SocketAddr::V6(_) => AF_INET6,
@@ -22,24 +58,498 @@ pub(crate) fn new_for_addr(addr: SocketAddr) -> io::Result<c_int> {
     create_new_socket(domain, SOCK_STREAM)
 }
 
+/// Orders addresses as RFC 8305 recommends: alternate between address families, starting
+/// with the family of the first address.
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_is_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return Vec::new(),
+    };
+    let (preferred, other): (Vec<_>, Vec<_>) =
+        addrs.iter().partition(|addr| addr.is_ipv6() == first_is_v6);
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b).copied()),
+        }
+    }
+    ordered
+}
+
+/// Snapshot of a listener's accept counters, see [`TcpListener::accept_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AcceptStats {
+    pub accepted: u64,
+    /// The process ran out of file descriptors.
+    pub emfile: u64,
+    /// The system ran out of file descriptors.
+    pub enfile: u64,
+    /// A connection was aborted before it could be accepted; `accept` retries these.
+    pub connaborted: u64,
+    pub other: u64,
+}
+
+/// How long to stop accepting after `accept` fails for lack of resources (`EMFILE`,
+/// `ENFILE`, `ENOBUFS`, `ENOMEM`): the listener stays readable until a connection is
+/// accepted, so retrying at once just spins. The pause doubles from `initial` with each
+/// consecutive failure, up to `max`.
+#[derive(Debug, Clone)]
+pub struct AcceptBackoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for AcceptBackoff {
+    fn default() -> Self {
+        AcceptBackoff {
+            initial: Duration::from_millis(5),
+            max: Duration::from_secs(1),
+        }
+    }
+}
+
+impl AcceptBackoff {
+    /// Whether `err` from `accept` calls for backing off. Aborted connections don't, since
+    /// `accept` retries them itself.
+    pub fn applies_to(err: &io::Error) -> bool {
+        matches!(err.raw_os_error(), Some(EMFILE | ENFILE | ENOBUFS | ENOMEM))
+    }
+
+    /// The pause after `failures` consecutive failures, zero for none.
+    pub fn delay(&self, failures: u32) -> Duration {
+        match failures {
+            0 => Duration::ZERO,
+            n => self
+                .initial
+                .saturating_mul(1 << (n - 1).min(31))
+                .min(self.max),
+        }
+    }
+}
+
+/// Caps the accept rate at `per_sec` connections a second, with bursts of up to `burst`, so
+/// a connection storm reaches the rest of the server at a steady pace. Connections over the
+/// cap aren't refused: they stay in the listen backlog until the budget allows them. The
+/// listener stays readable meanwhile, so when told to wait, pause its
+/// [`Registration`](crate::epoll::Registration) and resume it once the delay has passed.
+#[derive(Debug, Clone)]
+pub struct AcceptPacer {
+    per_sec: f64,
+    burst: f64,
+    budget: f64,
+    refilled: Instant,
+}
+
+/// The outcome of [`AcceptPacer::accept`].
+pub enum Paced {
+    Accepted(TcpStream, SocketAddr),
+    /// No connection is pending.
+    Empty,
+    /// Over the cap: accept nothing more until this much time has passed.
+    Wait(Duration),
+}
+
+impl AcceptPacer {
+    /// Starts with a full burst's worth of budget.
+    pub fn new(per_sec: u32, burst: u32) -> AcceptPacer {
+        assert!(
+            per_sec > 0 && burst > 0,
+            "accept rate and burst must be positive"
+        );
+        AcceptPacer {
+            per_sec: per_sec.into(),
+            burst: burst.into(),
+            budget: burst.into(),
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Accepts a connection if the budget allows; only a connection actually accepted uses
+    /// up budget.
+    pub fn accept(&mut self, listener: &TcpListener) -> io::Result<Paced> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.budget = (self.budget + elapsed * self.per_sec).min(self.burst);
+        self.refilled = now;
+        if self.budget < 1.0 {
+            return Ok(Paced::Wait(Duration::from_secs_f64(
+                (1.0 - self.budget) / self.per_sec,
+            )));
+        }
+        Ok(match listener.try_accept()? {
+            Some((stream, addr)) => {
+                self.budget -= 1.0;
+                Paced::Accepted(stream, addr)
+            }
+            None => Paced::Empty,
+        })
+    }
+}
+
+#[derive(Default)]
+struct AcceptCounters {
+    accepted: AtomicU64,
+    emfile: AtomicU64,
+    enfile: AtomicU64,
+    connaborted: AtomicU64,
+    other: AtomicU64,
+}
+
+/// Counts open connections so a graceful shutdown can wait for them to close. Clones share
+/// the same count.
+#[derive(Clone, Default)]
+pub struct DrainGuard {
+    state: Arc<DrainState>,
+}
+
+#[derive(Default)]
+struct DrainState {
+    active: Mutex<usize>,
+    drained: Condvar,
+}
+
+impl DrainGuard {
+    pub fn new() -> DrainGuard {
+        DrainGuard::default()
+    }
+
+    /// Counts a connection as active until the returned token is dropped, so keep the
+    /// token with the connection, e.g. right after `accept`.
+    pub fn track(&self) -> DrainToken {
+        *self.state.active.lock().unwrap() += 1;
+        DrainToken {
+            state: self.state.clone(),
+        }
+    }
+
+    pub fn active(&self) -> usize {
+        *self.state.active.lock().unwrap()
+    }
+
+    /// Waits until no tracked connections remain, returning `false` if `timeout` elapses
+    /// first.
+    pub fn wait_drained(&self, timeout: Duration) -> bool {
+        let active = self.state.active.lock().unwrap();
+        let (active, _) = self
+            .state
+            .drained
+            .wait_timeout_while(active, timeout, |active| *active > 0)
+            .unwrap();
+        *active == 0
+    }
+}
+
+pub struct DrainToken {
+    state: Arc<DrainState>,
+}
+
+impl Drop for DrainToken {
+    fn drop(&mut self) {
+        let mut active = self.state.active.lock().unwrap();
+        *active -= 1;
+        if *active == 0 {
+            self.state.drained.notify_all();
+        }
+    }
+}
+
+/// Caps how many connections each client IP may have open at once, to keep a single source
+/// from using up every connection slot. IPv6 clients are grouped by /64, the unit a single
+/// host is usually assigned, so rotating through its addresses doesn't get around the cap.
+/// Clones share the same counts.
+#[derive(Clone)]
+pub struct ConnLimiter {
+    state: Arc<LimiterState>,
+}
+
+struct LimiterState {
+    max_per_ip: usize,
+    /// Only clients with connections open have an entry.
+    active: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ConnLimiter {
+    pub fn new(max_per_ip: usize) -> ConnLimiter {
+        ConnLimiter {
+            state: Arc::new(LimiterState {
+                max_per_ip,
+                active: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Counts a connection from `ip` until the returned permit is dropped, or returns
+    /// `None` if `ip` is already at the cap.
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<ConnPermit> {
+        let key = limiter_key(ip);
+        let mut active = self.state.active.lock().unwrap();
+        let count = active.entry(key).or_insert(0);
+        if *count >= self.state.max_per_ip {
+            if *count == 0 {
+                active.remove(&key);
+            }
+            return None;
+        }
+        *count += 1;
+        Some(ConnPermit {
+            state: self.state.clone(),
+            key,
+        })
+    }
+
+    /// Connections open from `ip`'s group.
+    pub fn active(&self, ip: IpAddr) -> usize {
+        let active = self.state.active.lock().unwrap();
+        active.get(&limiter_key(ip)).copied().unwrap_or(0)
+    }
+
+    pub fn max_per_ip(&self) -> usize {
+        self.state.max_per_ip
+    }
+}
+
+/// IPv4 addresses as themselves, including IPv4-mapped ones, IPv6 addresses by /64.
+fn limiter_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6((u128::from(v6) & !(u64::MAX as u128)).into()),
+        },
+    }
+}
+
+/// A connection counted by a [`ConnLimiter`], released when dropped. Keep it with the
+/// connection.
+pub struct ConnPermit {
+    state: Arc<LimiterState>,
+    key: IpAddr,
+}
+
+impl Drop for ConnPermit {
+    fn drop(&mut self) {
+        let mut active = self.state.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Open connections and their per-connection state, keyed by epoll token, capped at
+/// `capacity`. At the cap a new connection evicts the one that has been idle longest,
+/// going by [`TcpStream::last_activity`], so reads and writes keep a connection fresh
+/// without any extra bookkeeping. Finding it scans the table, so this suits caps in the
+/// thousands rather than the millions.
+pub struct ConnTable<T> {
+    capacity: usize,
+    conns: HashMap<usize, (TcpStream, T)>,
+}
+
+impl<T> ConnTable<T> {
+    /// A `capacity` of 0 is treated as 1.
+    pub fn new(capacity: usize) -> ConnTable<T> {
+        ConnTable {
+            capacity: capacity.max(1),
+            conns: HashMap::new(),
+        }
+    }
+
+    /// Adds a connection already registered with `epoll` under `token`. At the cap, first
+    /// deregisters and closes the least recently active connection, returning its token and
+    /// state. Fails with `AlreadyExists`, handing nothing back, if `token` is taken.
+    pub fn insert(
+        &mut self,
+        epoll: &impl AsRawFd,
+        token: usize,
+        stream: TcpStream,
+        state: T,
+    ) -> io::Result<Option<(usize, T)>> {
+        if self.conns.contains_key(&token) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "token already in the table",
+            ));
+        }
+        let evicted = if self.conns.len() >= self.capacity {
+            // Dropping the evicted stream closes it.
+            self.least_recently_active().and_then(|oldest| {
+                self.remove(epoll, oldest)
+                    .map(|(_stream, state)| (oldest, state))
+            })
+        } else {
+            None
+        };
+        self.conns.insert(token, (stream, state));
+        Ok(evicted)
+    }
+
+    /// Deregisters the connection under `token` from `epoll` and hands it back, still open.
+    pub fn remove(&mut self, epoll: &impl AsRawFd, token: usize) -> Option<(TcpStream, T)> {
+        let (stream, state) = self.conns.remove(&token)?;
+        // Fails with ENOENT if it was never registered, which is fine; closing it would
+        // deregister it anyway unless the fd has been duplicated.
+        let _ = syscall!(epoll_ctl(
+            epoll.as_raw_fd(),
+            EPOLL_CTL_DEL,
+            stream.as_raw_fd(),
+            ptr::null_mut()
+        ));
+        Some((stream, state))
+    }
+
+    /// The token of the connection that has gone longest without reading or writing.
+    pub fn least_recently_active(&self) -> Option<usize> {
+        self.conns
+            .iter()
+            .min_by_key(|(_, (stream, _))| stream.last_activity())
+            .map(|(token, _)| *token)
+    }
+
+    pub fn get(&self, token: usize) -> Option<(&TcpStream, &T)> {
+        self.conns
+            .get(&token)
+            .map(|(stream, state)| (stream, state))
+    }
+
+    pub fn get_mut(&mut self, token: usize) -> Option<(&mut TcpStream, &mut T)> {
+        self.conns
+            .get_mut(&token)
+            .map(|(stream, state)| (stream, state))
+    }
+
+    pub fn contains(&self, token: usize) -> bool {
+        self.conns.contains_key(&token)
+    }
+
+    pub fn len(&self) -> usize {
+        self.conns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conns.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// Socket options applied by [`TcpListener::bind_config`] before the listener starts
+/// accepting. `None` leaves the system default in place.
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    pub reuseaddr: bool,
+    pub reuseport: bool,
+    /// Only applied to IPv6 addresses.
+    pub v6only: Option<bool>,
+    /// Allows binding to an address that isn't (yet) configured on any interface.
+    pub freebind: bool,
+    /// Don't wake the listener until data arrives on a new connection, waiting at most this long.
+    pub defer_accept: Option<Duration>,
+    /// Length of the queue of pending TCP Fast Open requests.
+    pub fastopen: Option<u32>,
+    pub backlog: c_int,
+    /// Close the listener in processes started with `exec`. Clear it only to hand the
+    /// listener to a new binary, see [`TcpListener::set_cloexec`].
+    pub cloexec: bool,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig {
+            reuseaddr: true,
+            reuseport: false,
+            v6only: None,
+            freebind: false,
+            defer_accept: None,
+            fastopen: None,
+            backlog: 1024,
+            cloexec: true,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+        }
+    }
+}
+
+/// A system-wide setting that [`TcpListener::set_synflood_protection`] can't change from
+/// the socket, with the value it should have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SysctlAdvice {
+    pub name: &'static str,
+    pub current: String,
+    pub recommended: &'static str,
+}
+
+/// Reads a sysctl through `/proc/sys`, `None` if it can't be read.
+fn read_sysctl(name: &str) -> Option<String> {
+    let path = format!("/proc/sys/{}", name.replace('.', "/"));
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_owned())
+}
+
+/// Labels a failed socket option so callers can tell which one the kernel rejected.
+fn option_error(option: &str, err: io::Error) -> io::Error {
+    io::Error::new(err.kind(), format!("failed to set {}: {}", option, err))
+}
+
 pub struct TcpListener {
+    // Declared first so it runs before `inner` closes the fd.
+    cleanup: DropCleanup,
     inner: net::TcpListener,
+    stats: AcceptCounters,
 }
 
 impl TcpListener {
     pub fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+        Self::bind_config(addr, &ListenerConfig::default())
+    }
+
+    pub fn bind_config(addr: SocketAddr, config: &ListenerConfig) -> io::Result<TcpListener> {
         let socket = new_for_addr(addr)?;
 
-        let listener = unsafe { TcpListener::from_raw_fd(socket) };
+        let listener = TcpListener::from_std(socket.into_std());
+        let set = |option: &str, level: c_int, name: c_int, value: c_int| {
+            setsockopt(listener.as_raw_fd(), level, name, value)
+                .map_err(|err| option_error(option, err))
+        };
 
-        let val: c_int = 1;
-        syscall!(setsockopt(
-            listener.as_raw_fd(),
-            SOL_SOCKET,
-            SO_REUSEADDR,
-            &val as *const c_int as *const c_void,
-            size_of::<c_int>() as socklen_t,
-        ))?;
+        if !config.cloexec {
+            listener
+                .set_cloexec(false)
+                .map_err(|err| option_error("FD_CLOEXEC", err))?;
+        }
+
+        // These decide whether (and how) the address can be bound, so they must precede bind.
+        if config.reuseaddr {
+            set("SO_REUSEADDR", SOL_SOCKET, SO_REUSEADDR, 1)?;
+        }
+        if config.reuseport {
+            set("SO_REUSEPORT", SOL_SOCKET, SO_REUSEPORT, 1)?;
+        }
+        if let (Some(v6only), SocketAddr::V6(_)) = (config.v6only, addr) {
+            set("IPV6_V6ONLY", IPPROTO_IPV6, IPV6_V6ONLY, v6only as c_int)?;
+        }
+        if config.freebind {
+            set("IP_FREEBIND", SOL_IP, IP_FREEBIND, 1)?;
+        }
+        // Accepted sockets inherit the buffer sizes, and the receive buffer determines the
+        // window scale advertised in the SYN-ACK, so they must be set before listen.
+        if let Some(size) = config.recv_buffer_size {
+            set("SO_RCVBUF", SOL_SOCKET, SO_RCVBUF, size as c_int)?;
+        }
+        if let Some(size) = config.send_buffer_size {
+            set("SO_SNDBUF", SOL_SOCKET, SO_SNDBUF, size as c_int)?;
+        }
 
         let (raw_addr, raw_addr_length) = socket_addr(&addr);
         syscall!(bind(
@@ -48,13 +558,91 @@ impl TcpListener {
             raw_addr_length
         ))?;
 
-        syscall!(listen(listener.as_raw_fd(), 1024))?;
+        if let Some(timeout) = config.defer_accept {
+            let secs = timeout.as_secs().min(c_int::MAX as u64) as c_int;
+            set("TCP_DEFER_ACCEPT", IPPROTO_TCP, TCP_DEFER_ACCEPT, secs)?;
+        }
+        if let Some(queue_length) = config.fastopen {
+            set(
+                "TCP_FASTOPEN",
+                IPPROTO_TCP,
+                TCP_FASTOPEN,
+                queue_length as c_int,
+            )?;
+        }
+
+        syscall!(listen(listener.as_raw_fd(), config.backlog))?;
 
         Ok(listener)
     }
 
     pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
-        let mut addr = MaybeUninit::uninit();
+        loop {
+            let err = match self.accept_once() {
+                Ok(accepted) => {
+                    self.stats.accepted.fetch_add(1, Ordering::Relaxed);
+                    return Ok(accepted);
+                }
+                Err(err) => err,
+            };
+            let counter = match err.raw_os_error() {
+                Some(ECONNABORTED) => {
+                    self.stats.connaborted.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                Some(EMFILE) => &self.stats.emfile,
+                Some(ENFILE) => &self.stats.enfile,
+                _ if err.kind() == io::ErrorKind::WouldBlock => return Err(err),
+                _ => &self.stats.other,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            return Err(err);
+        }
+    }
+
+    /// Like `accept`, but returns `Ok(None)` instead of a `WouldBlock` error when no
+    /// connection is pending.
+    pub fn try_accept(&self) -> io::Result<Option<(TcpStream, SocketAddr)>> {
+        would_block_to_none(self.accept())
+    }
+
+    /// The number of connections waiting to be accepted, for shedding load as the queue
+    /// grows. Read from `TCP_INFO`, where a listener reports its accept queue length as
+    /// `tcpi_unacked`; connections still mid-handshake (the SYN queue) aren't counted. A
+    /// snapshot that may be stale as soon as it is returned.
+    pub fn backlog_len(&self) -> io::Result<usize> {
+        tcp_info(self.as_raw_fd()).map(|info| info.tcpi_unacked as usize)
+    }
+
+    /// The accept queue's capacity, the `listen` backlog capped at `net.core.somaxconn`,
+    /// reported as `tcpi_sacked`.
+    pub fn backlog_capacity(&self) -> io::Result<usize> {
+        tcp_info(self.as_raw_fd()).map(|info| info.tcpi_sacked as usize)
+    }
+
+    /// Like `accept`, but first checks the listener for a pending asynchronous error
+    /// (`SO_ERROR`) and returns it instead, clearing it. Without one this behaves exactly
+    /// like `accept`.
+    pub fn accept_checked(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        if let Some(err) = self.take_error()? {
+            return Err(err);
+        }
+        self.accept()
+    }
+
+    pub fn accept_stats(&self) -> AcceptStats {
+        AcceptStats {
+            accepted: self.stats.accepted.load(Ordering::Relaxed),
+            emfile: self.stats.emfile.load(Ordering::Relaxed),
+            enfile: self.stats.enfile.load(Ordering::Relaxed),
+            connaborted: self.stats.connaborted.load(Ordering::Relaxed),
+            other: self.stats.other.load(Ordering::Relaxed),
+        }
+    }
+
+    fn accept_once(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        // Zeroed, since the kernel may fill in less than the family if anything at all.
+        let mut addr = MaybeUninit::<sockaddr_storage>::zeroed();
         let mut length = size_of::<sockaddr_storage>() as socklen_t;
         let stream = {
             syscall!(accept4(
@@ -65,10 +653,14 @@ impl TcpListener {
             ))
             .map(|socket| unsafe { net::TcpStream::from_raw_fd(socket) })
         }?;
-        match unsafe { to_socket_addr(addr.as_ptr()) } {
-            Ok(addr) => Ok((TcpStream::from_std(stream), addr)),
-            Err(e) => Err(e),
-        }
+        let stream = TcpStream::from_std(stream);
+        // Fall back to asking the socket when the address can't be parsed. If that fails
+        // too, returning the error drops the stream, which closes the accepted fd.
+        let addr = match unsafe { to_socket_addr(addr.as_ptr()) } {
+            Ok(addr) => addr,
+            Err(_) => stream.peer_addr()?,
+        };
+        Ok((stream, addr))
     }
 
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
@@ -87,143 +679,2928 @@ impl TcpListener {
         self.inner.take_error()
     }
 
-    pub fn from_std(listener: net::TcpListener) -> TcpListener {
-        Self::from(listener)
+    pub fn reuseaddr(&self) -> io::Result<bool> {
+        getsockopt::<c_int>(self.as_raw_fd(), SOL_SOCKET, SO_REUSEADDR).map(|val| val != 0)
     }
-}
 
-impl From<net::TcpListener> for TcpListener {
-    fn from(l: net::TcpListener) -> Self {
-        TcpListener { inner: l }
+    pub fn reuseport(&self) -> io::Result<bool> {
+        getsockopt::<c_int>(self.as_raw_fd(), SOL_SOCKET, SO_REUSEPORT).map(|val| val != 0)
     }
-}
 
-impl IntoRawFd for TcpListener {
-    fn into_raw_fd(self) -> RawFd {
-        self.inner.into_raw_fd()
+    pub fn only_v6(&self) -> io::Result<bool> {
+        getsockopt::<c_int>(self.as_raw_fd(), IPPROTO_IPV6, IPV6_V6ONLY).map(|val| val != 0)
     }
-}
 
-impl AsRawFd for TcpListener {
-    fn as_raw_fd(&self) -> RawFd {
-        self.inner.as_raw_fd()
+    pub fn freebind(&self) -> io::Result<bool> {
+        getsockopt::<c_int>(self.as_raw_fd(), SOL_IP, IP_FREEBIND).map(|val| val != 0)
     }
-}
 
-impl FromRawFd for TcpListener {
-    unsafe fn from_raw_fd(fd: RawFd) -> TcpListener {
-        TcpListener {
-            inner: net::TcpListener::from_raw_fd(fd),
-        }
+    /// The kernel rounds the timeout to its SYN-ACK retransmission schedule, so this may
+    /// differ from the configured value.
+    pub fn defer_accept(&self) -> io::Result<Duration> {
+        getsockopt::<c_int>(self.as_raw_fd(), IPPROTO_TCP, TCP_DEFER_ACCEPT)
+            .map(|secs| Duration::from_secs(secs as u64))
     }
-}
 
-pub struct TcpStream {
-    inner: net::TcpStream,
-}
+    pub fn fastopen(&self) -> io::Result<u32> {
+        getsockopt::<c_int>(self.as_raw_fd(), IPPROTO_TCP, TCP_FASTOPEN).map(|len| len as u32)
+    }
 
-impl TcpStream {
-    pub fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
-        let socket = new_for_addr(addr)?;
-        let stream = unsafe { TcpStream::from_raw_fd(socket) };
-        let (raw_addr, raw_addr_length) = socket_addr(&addr);
+    /// Hardens the listener against SYN floods with the settings a socket controls:
+    /// `TCP_DEFER_ACCEPT` keeps connections that never send data out of the accept queue,
+    /// and `TCP_SYNCNT` on a listener caps SYN-ACK retransmits at 2, so half-open
+    /// connections leave the SYN queue sooner than with the `net.ipv4.tcp_synack_retries`
+    /// default. Deferring accept suits only protocols where the client speaks first.
+    /// Disabling restores both defaults.
+    ///
+    /// SYN cookies, the main defence, are a sysctl. Returns the system-wide settings that
+    /// need changing, with privileges, for the protection to be effective; settings that
+    /// can't be read are skipped.
+    pub fn set_synflood_protection(&self, enabled: bool) -> io::Result<Vec<SysctlAdvice>> {
+        let fd = self.as_raw_fd();
+        let (defer_secs, synack_retries) = if enabled {
+            (5, 2)
+        } else {
+            // TCP_SYNCNT can't be reset to "unset", only to the sysctl's value.
+            let retries = read_sysctl("net.ipv4.tcp_synack_retries")
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(5);
+            (0, retries)
+        };
+        setsockopt(fd, IPPROTO_TCP, TCP_DEFER_ACCEPT, defer_secs as c_int)
+            .map_err(|err| option_error("TCP_DEFER_ACCEPT", err))?;
+        setsockopt(fd, IPPROTO_TCP, TCP_SYNCNT, synack_retries as c_int)
+            .map_err(|err| option_error("TCP_SYNCNT", err))?;
 
-        match syscall!(connect(
-            socket.as_raw_fd(),
-            raw_addr.as_ptr(),
-            raw_addr_length
-        )) {
-            Err(err) if err.raw_os_error() != Some(EINPROGRESS) => Err(err),
-            _ => Ok(()),
-        }?;
-        Ok(stream)
+        let mut advice = Vec::new();
+        if !enabled {
+            return Ok(advice);
+        }
+        if let Some(current) = read_sysctl("net.ipv4.tcp_syncookies") {
+            if current == "0" {
+                advice.push(SysctlAdvice {
+                    name: "net.ipv4.tcp_syncookies",
+                    current,
+                    recommended: "1",
+                });
+            }
+        }
+        if let Some(current) = read_sysctl("net.ipv4.tcp_max_syn_backlog") {
+            if current.parse::<u32>().is_ok_and(|backlog| backlog < 1024) {
+                advice.push(SysctlAdvice {
+                    name: "net.ipv4.tcp_max_syn_backlog",
+                    current,
+                    recommended: "1024",
+                });
+            }
+        }
+        Ok(advice)
     }
 
-    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
-        self.inner.peer_addr()
+    /// Controls whether the listener is closed when this process calls `exec`. Listeners
+    /// are created with it set; clearing it lets a re-executed server keep accepting on
+    /// the same socket. While it is clear, every program this process runs, including
+    /// from other threads, inherits the listener and can accept its connections.
+    pub fn set_cloexec(&self, cloexec: bool) -> io::Result<()> {
+        set_cloexec(self.as_raw_fd(), cloexec)
     }
 
-    pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.inner.local_addr()
+    pub fn cloexec(&self) -> io::Result<bool> {
+        cloexec(self.as_raw_fd())
     }
 
-    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
-        self.inner.shutdown(how)
+    /// The kernel doubles the requested size to leave room for bookkeeping overhead.
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        getsockopt::<c_int>(self.as_raw_fd(), SOL_SOCKET, SO_RCVBUF).map(|size| size as usize)
     }
 
-    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
-        self.inner.set_nodelay(nodelay)
+    /// The kernel doubles the requested size to leave room for bookkeeping overhead.
+    pub fn send_buffer_size(&self) -> io::Result<usize> {
+        getsockopt::<c_int>(self.as_raw_fd(), SOL_SOCKET, SO_SNDBUF).map(|size| size as usize)
     }
 
-    pub fn nodelay(&self) -> io::Result<bool> {
-        self.inner.nodelay()
+    /// Attaches a classic BPF program to this listener's `SO_REUSEPORT` group. For each
+    /// incoming connection the program returns the index of the socket in the group that
+    /// should receive it; an out of range index falls back to the default hashing.
+    pub fn attach_reuseport_cbpf(&self, program: &[SockFilter]) -> io::Result<()> {
+        let len = u16::try_from(program.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "BPF program is too long"))?;
+        let prog = sock_fprog {
+            len,
+            filter: program.as_ptr() as *mut sock_filter,
+        };
+        setsockopt(self.as_raw_fd(), SOL_SOCKET, SO_ATTACH_REUSEPORT_CBPF, prog)
+            .map_err(|err| option_error("SO_ATTACH_REUSEPORT_CBPF", err))
     }
 
-    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
-        self.inner.set_ttl(ttl)
+    /// Like [`attach_reuseport_cbpf`](TcpListener::attach_reuseport_cbpf), but with an
+    /// already loaded eBPF program, of type `BPF_PROG_TYPE_SOCKET_FILTER` returning the
+    /// socket index or `BPF_PROG_TYPE_SK_REUSEPORT` selecting a socket from a map. Loading
+    /// (and so verifying) the program is up to the caller; attaching fails with
+    /// `InvalidInput` if `prog_fd` isn't a program of either type.
+    pub fn attach_reuseport_ebpf(&self, prog_fd: RawFd) -> io::Result<()> {
+        setsockopt(
+            self.as_raw_fd(),
+            SOL_SOCKET,
+            SO_ATTACH_REUSEPORT_EBPF,
+            prog_fd as c_int,
+        )
+        .map_err(|err| match err.raw_os_error() {
+            Some(EINVAL | EBADF) => io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a socket filter or SO_REUSEPORT eBPF program",
+            ),
+            _ => option_error("SO_ATTACH_REUSEPORT_EBPF", err),
+        })
     }
 
-    pub fn ttl(&self) -> io::Result<u32> {
-        self.inner.ttl()
+    /// Removes a program attached with `attach_reuseport_cbpf` or `attach_reuseport_ebpf`,
+    /// going back to the default hashing.
+    pub fn detach_reuseport_bpf(&self) -> io::Result<()> {
+        setsockopt(
+            self.as_raw_fd(),
+            SOL_SOCKET,
+            SO_DETACH_REUSEPORT_BPF,
+            0 as c_int,
+        )
     }
 
-    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
-        self.inner.take_error()
+    /// Shuts the socket down when the listener is dropped, waking threads blocked in
+    /// `accept` and refusing new connections even if the fd is shared with another process.
+    /// Skipped if the fd is taken with `into_raw_fd`.
+    pub fn set_shutdown_on_drop(&mut self, shutdown: bool) {
+        self.cleanup.shutdown = shutdown.then(|| self.inner.as_raw_fd());
     }
 
-    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
-        self.inner.peek(buf)
+    pub fn from_std(listener: net::TcpListener) -> TcpListener {
+        Self::from(listener)
     }
 
-    pub fn from_std(stream: net::TcpStream) -> TcpStream {
-        Self::from(stream)
+    /// Hands the socket over to std without duplicating the fd. The std listener is still
+    /// non-blocking, and no shutdown on drop is performed.
+    pub fn into_std(mut self) -> net::TcpListener {
+        self.cleanup.disarm();
+        self.inner
     }
 }
 
-impl Read for TcpStream {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.inner.read(buf)
+impl From<net::TcpListener> for TcpListener {
+    fn from(l: net::TcpListener) -> Self {
+        TcpListener {
+            cleanup: DropCleanup::default(),
+            inner: l,
+            stats: AcceptCounters::default(),
+        }
     }
+}
 
-    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
-        self.inner.read_vectored(bufs)
+impl IntoRawFd for TcpListener {
+    fn into_raw_fd(self) -> RawFd {
+        self.into_std().into_raw_fd()
     }
 }
 
-impl Write for TcpStream {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.inner.write(buf)
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
     }
+}
 
-    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
-        self.inner.write_vectored(bufs)
+impl FromRawFd for TcpListener {
+    unsafe fn from_raw_fd(fd: RawFd) -> TcpListener {
+        TcpListener::from_std(net::TcpListener::from_raw_fd(fd))
     }
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.inner.flush()
-    }
+pub(crate) fn tcp_info(fd: c_int) -> io::Result<tcp_info> {
+    getsockopt(fd, IPPROTO_TCP, TCP_INFO)
 }
 
-impl From<net::TcpStream> for TcpStream {
-    fn from(s: net::TcpStream) -> Self {
-        TcpStream { inner: s }
-    }
+/// State of a TCP connection as reported by the kernel, see [`TcpStream::state`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
+    Established,
+    SynSent,
+    SynRecv,
+    FinWait1,
+    FinWait2,
+    TimeWait,
+    Close,
+    CloseWait,
+    LastAck,
+    Listen,
+    Closing,
+    NewSynRecv,
+    /// A state this crate doesn't know about yet.
+    Unknown(u8),
 }
 
-impl IntoRawFd for TcpStream {
-    fn into_raw_fd(self) -> RawFd {
-        self.inner.into_raw_fd()
+impl From<u8> for TcpState {
+    fn from(state: u8) -> Self {
+        // Values of the kernel's `TCP_ESTABLISHED`...`TCP_NEW_SYN_RECV` enum.
+        match state {
+            1 => TcpState::Established,
+            2 => TcpState::SynSent,
+            3 => TcpState::SynRecv,
+            4 => TcpState::FinWait1,
+            5 => TcpState::FinWait2,
+            6 => TcpState::TimeWait,
+            7 => TcpState::Close,
+            8 => TcpState::CloseWait,
+            9 => TcpState::LastAck,
+            10 => TcpState::Listen,
+            11 => TcpState::Closing,
+            12 => TcpState::NewSynRecv,
+            state => TcpState::Unknown(state),
+        }
     }
 }
 
-impl AsRawFd for TcpStream {
-    fn as_raw_fd(&self) -> RawFd {
-        self.inner.as_raw_fd()
+fn would_block_to_none<T>(res: io::Result<T>) -> io::Result<Option<T>> {
+    match res {
+        Ok(val) => Ok(Some(val)),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(err) => Err(err),
     }
 }
 
-impl FromRawFd for TcpStream {
-    unsafe fn from_raw_fd(fd: RawFd) -> TcpStream {
-        TcpStream::from_std(FromRawFd::from_raw_fd(fd))
+/// A snapshot of a connection from [`TcpStream::info`]. Fields the kernel couldn't report
+/// are `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub local_addr: Option<SocketAddr>,
+    pub peer_addr: Option<SocketAddr>,
+    pub nodelay: Option<bool>,
+    pub ttl: Option<u32>,
+    /// The maximum segment size currently in use.
+    pub mss: Option<u32>,
+    pub state: Option<TcpState>,
+    /// The smoothed round-trip time and its variance.
+    pub rtt: Option<Duration>,
+    pub rtt_var: Option<Duration>,
+    /// The congestion window, in segments.
+    pub snd_cwnd: Option<u32>,
+    /// Segments sent but not yet acknowledged.
+    pub unacked: Option<u32>,
+    /// Retransmitted segments over the lifetime of the connection.
+    pub total_retrans: Option<u32>,
+}
+
+/// How [`TcpStream::close_with`] ends a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseMode {
+    /// Abort the connection with a RST, discarding unsent data and skipping TIME_WAIT.
+    Reset,
+    /// Send a FIN after any queued data, then discard input until the peer closes its side
+    /// too, so late input can't turn the close into a RST. Waits at most the given time for
+    /// that before closing anyway.
+    Graceful(Duration),
+}
+
+/// Result of [`TcpStream::read_classify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadOutcome {
+    Data(usize),
+    /// The peer closed its side of the connection cleanly.
+    Eof,
+    /// The peer reset the connection.
+    Reset,
+}
+
+/// The queue that [`TcpStream::queue_seq`] and [`TcpStream::set_queue_seq`] act on while
+/// the socket is in repair mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepairQueue {
+    /// Bytes received but not yet read. Its sequence number is the next one expected from
+    /// the peer, i.e. one past the last queued byte.
+    Recv,
+    /// Bytes written but not yet acknowledged. Its sequence number is the one the next
+    /// write will get, i.e. one past the last queued byte.
+    Send,
+}
+
+/// Window state of a connection in repair mode, laid out like the kernel's
+/// `struct tcp_repair_window`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RepairWindow {
+    pub snd_wl1: u32,
+    pub snd_wnd: u32,
+    pub max_window: u32,
+    pub rcv_wnd: u32,
+    pub rcv_wup: u32,
+}
+
+/// A snapshot of an established connection taken with [`TcpStream::export_state`], from
+/// which [`import_state`](crate::net::import_state) rebuilds it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnState {
+    pub local_addr: SocketAddr,
+    pub peer_addr: SocketAddr,
+    /// The sequence number one past the end of `send_queue`.
+    pub send_seq: u32,
+    /// Bytes written but not yet acknowledged by the peer.
+    pub send_queue: Vec<u8>,
+    /// The sequence number one past the end of `recv_queue`.
+    pub recv_seq: u32,
+    /// Bytes received but not yet read.
+    pub recv_queue: Vec<u8>,
+    pub window: RepairWindow,
+    pub mss: u32,
+    /// The send and receive window scale, if window scaling was negotiated.
+    pub wscale: Option<(u8, u8)>,
+    pub sack: bool,
+    /// The current TCP timestamp, if timestamps were negotiated.
+    pub timestamp: Option<u32>,
+}
+
+/// Remembers destinations where TCP Fast Open didn't pay off, so that
+/// [`connect_fastopen`](TcpStream::connect_fastopen) connects to them normally for `ttl`
+/// rather than sending data with the SYN only to have it retransmitted. Holds at most
+/// `capacity` destinations, evicting the oldest failure when full.
+pub struct TfoCache {
+    ttl: Duration,
+    capacity: usize,
+    failures: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl TfoCache {
+    pub fn new(ttl: Duration, capacity: usize) -> TfoCache {
+        TfoCache {
+            ttl,
+            capacity,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether Fast Open should be attempted for `addr`, i.e. it hasn't failed within `ttl`.
+    pub fn should_try(&self, addr: SocketAddr) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        match failures.get(&addr) {
+            Some(failed_at) if failed_at.elapsed() < self.ttl => false,
+            Some(_) => {
+                failures.remove(&addr);
+                true
+            }
+            None => true,
+        }
+    }
+
+    pub fn record_failure(&self, addr: SocketAddr) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= self.capacity && !failures.contains_key(&addr) {
+            failures.retain(|_, failed_at| failed_at.elapsed() < self.ttl);
+            if failures.len() >= self.capacity {
+                let oldest = failures
+                    .iter()
+                    .min_by_key(|(_, failed_at)| **failed_at)
+                    .map(|(addr, _)| *addr);
+                if let Some(oldest) = oldest {
+                    failures.remove(&oldest);
+                }
+            }
+        }
+        failures.insert(addr, Instant::now());
+    }
+
+    pub fn record_success(&self, addr: SocketAddr) {
+        self.failures.lock().unwrap().remove(&addr);
+    }
+
+    /// Destinations currently recorded as failed, including expired ones not yet evicted.
+    pub fn len(&self) -> usize {
+        self.failures.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub enum ConnectState {
+    Pending,
+    Connected(TcpStream),
+    Failed(io::Error),
+}
+
+/// A connect in progress that can be checked without blocking, so an event loop can keep
+/// many in flight. Register its fd for writability and call [`poll`](ConnectFuture::poll)
+/// when it fires. Dropping it before the connect completes closes the socket.
+pub struct ConnectFuture {
+    stream: Option<TcpStream>,
+}
+
+impl ConnectFuture {
+    pub fn new(addr: SocketAddr) -> io::Result<ConnectFuture> {
+        TcpStream::connect(addr).map(|stream| ConnectFuture {
+            stream: Some(stream),
+        })
+    }
+
+    /// Checks whether the connect has finished. Once it has connected or failed, the
+    /// future is spent and polling again is an error.
+    pub fn poll(&mut self) -> io::Result<ConnectState> {
+        let stream = match &self.stream {
+            Some(stream) => stream,
+            None => {
+                return Err(io::Error::other("connect already completed"));
+            }
+        };
+        if poll_fd(stream.as_raw_fd(), POLLOUT, Some(Duration::ZERO))? == 0 {
+            return Ok(ConnectState::Pending);
+        }
+
+        let stream = self.stream.take().unwrap();
+        match stream.take_error() {
+            Ok(None) => Ok(ConnectState::Connected(stream)),
+            Ok(Some(err)) | Err(err) => Ok(ConnectState::Failed(err)),
+        }
+    }
+}
+
+impl AsRawFd for ConnectFuture {
+    /// Returns -1 once the connect has completed.
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_ref().map_or(-1, |stream| stream.as_raw_fd())
+    }
+}
+
+pub struct TcpStream {
+    inner: net::TcpStream,
+    connected_at: Instant,
+    /// When data last moved, in nanoseconds since `connected_at`.
+    last_activity: AtomicU64,
+}
+
+impl TcpStream {
+    pub fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = new_for_addr(addr)?;
+        let stream = TcpStream::from_std(socket.into_std());
+        stream.start_connect(addr)?;
+        Ok(stream)
+    }
+
+    /// Connects from a specific local address. A local port of 0 pins only the source IP
+    /// and lets the kernel pick the port.
+    pub fn connect_from(local: SocketAddr, remote: SocketAddr) -> io::Result<TcpStream> {
+        let socket = new_for_addr(remote)?;
+        let stream = TcpStream::from_std(socket.into_std());
+
+        if local.port() != 0 {
+            // Lets a pinned port be reused while an earlier connection is in TIME_WAIT.
+            setsockopt(stream.as_raw_fd(), SOL_SOCKET, SO_REUSEADDR, 1 as c_int)?;
+        }
+        let (raw_addr, raw_addr_length) = socket_addr(&local);
+        syscall!(bind(stream.as_raw_fd(), raw_addr.as_ptr(), raw_addr_length))?;
+
+        stream.start_connect(remote)?;
+        Ok(stream)
+    }
+
+    /// Connects from `local_ip` and the first free port in `ports`, e.g. for egress that a
+    /// firewall only lets out from a known range. Ports are tried in order, with
+    /// `SO_REUSEADDR` so ones in TIME_WAIT can be reused; fails with `AddrInUse` once every
+    /// port in the range is taken.
+    pub fn connect_from_range(
+        local_ip: IpAddr,
+        ports: RangeInclusive<u16>,
+        remote: SocketAddr,
+    ) -> io::Result<TcpStream> {
+        for port in ports {
+            let socket = new_for_addr(remote)?;
+            let stream = TcpStream::from_std(socket.into_std());
+            setsockopt(stream.as_raw_fd(), SOL_SOCKET, SO_REUSEADDR, 1 as c_int)?;
+
+            let (raw_addr, raw_addr_length) = socket_addr(&SocketAddr::new(local_ip, port));
+            match syscall!(bind(stream.as_raw_fd(), raw_addr.as_ptr(), raw_addr_length)) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::AddrInUse => continue,
+                Err(err) => return Err(err),
+            }
+            match stream.start_connect(remote) {
+                Ok(_) => return Ok(stream),
+                // With `SO_REUSEADDR` the bind can succeed on a port that already has a
+                // connection to `remote`, which connect then refuses.
+                Err(err) if err.raw_os_error() == Some(EADDRNOTAVAIL) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "every port in the range is in use",
+        ))
+    }
+
+    /// Like `connect`, but also returns whether the connection completed right away, in
+    /// which case there is no need to wait for writability before using it. Otherwise the
+    /// connect is in progress. Linux reports TCP connects as in progress even over loopback,
+    /// where they complete almost at once.
+    pub fn connect_nonblocking(addr: SocketAddr) -> io::Result<(TcpStream, bool)> {
+        let socket = new_for_addr(addr)?;
+        let stream = TcpStream::from_std(socket.into_std());
+        let connected = stream.start_connect(addr)?;
+        Ok((stream, connected))
+    }
+
+    /// Starts connecting, returning whether the connect already completed.
+    fn start_connect(&self, addr: SocketAddr) -> io::Result<bool> {
+        let (raw_addr, raw_addr_length) = socket_addr(&addr);
+        match syscall!(connect(
+            self.as_raw_fd(),
+            raw_addr.as_ptr(),
+            raw_addr_length
+        )) {
+            Ok(_) => Ok(true),
+            Err(err) if err.raw_os_error() == Some(EINPROGRESS) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Happy Eyeballs (RFC 8305) connect: starts a new attempt every `attempt_delay`, or
+    /// as soon as the previous one fails, alternating address families, and returns the
+    /// first connection to complete. Attempts still in flight are closed.
+    ///
+    /// There is no overall timeout: once every address has been tried, this waits for the
+    /// kernel to give up on each attempt that hasn't failed yet. For an address that drops
+    /// SYNs that takes as long as `net.ipv4.tcp_syn_retries` allows, over two minutes by
+    /// default.
+    pub fn connect_happy(addrs: &[SocketAddr], attempt_delay: Duration) -> io::Result<TcpStream> {
+        let addrs = interleave_families(addrs);
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no addresses to connect to",
+            ));
+        }
+
+        let epoll = syscall!(epoll_create1(EPOLL_CLOEXEC))?;
+        let epoll = unsafe { OwnedFd::from_raw_fd(epoll) };
+
+        // Indexed by the epoll token; `None` once an attempt has failed.
+        let mut attempts: Vec<Option<TcpStream>> = Vec::with_capacity(addrs.len());
+        let mut events: Vec<epoll_event> = vec![epoll_event { events: 0, u64: 0 }; addrs.len()];
+        let mut next_attempt_at = Instant::now();
+        let mut last_err = None;
+
+        loop {
+            let now = Instant::now();
+            if attempts.len() < addrs.len() && now >= next_attempt_at {
+                let token = attempts.len();
+                let attempt = TcpStream::connect(addrs[token]).and_then(|stream| {
+                    let mut event = epoll_event {
+                        events: EPOLLOUT as u32,
+                        u64: token as u64,
+                    };
+                    syscall!(epoll_ctl(
+                        epoll.as_raw_fd(),
+                        EPOLL_CTL_ADD,
+                        stream.as_raw_fd(),
+                        &mut event
+                    ))?;
+                    Ok(stream)
+                });
+                match attempt {
+                    Ok(stream) => {
+                        attempts.push(Some(stream));
+                        next_attempt_at = now + attempt_delay;
+                    }
+                    Err(err) => {
+                        attempts.push(None);
+                        last_err = Some(err);
+                    }
+                }
+                continue;
+            }
+
+            let pending = attempts.len() < addrs.len();
+            if !pending && attempts.iter().all(Option::is_none) {
+                return Err(last_err.unwrap());
+            }
+
+            let timeout = pending.then(|| next_attempt_at.saturating_duration_since(now));
+            let ready = match syscall!(epoll_wait(
+                epoll.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.len() as c_int,
+                timeout_ms(timeout),
+            )) {
+                Ok(ready) => ready as usize,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+
+            for event in &events[..ready] {
+                let stream = match attempts[event.u64 as usize].take() {
+                    Some(stream) => stream,
+                    None => continue,
+                };
+                match stream.take_error() {
+                    Ok(None) => return Ok(stream),
+                    Ok(Some(err)) | Err(err) => {
+                        last_err = Some(err);
+                        next_attempt_at = Instant::now();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Connects through `breaker`, failing fast with [`CircuitOpen`] while the circuit for
+    /// `addr` is open. Unlike `connect`, this waits for the connect to complete, up to the
+    /// breaker's `connect_timeout`, so that its outcome can be recorded. Failures on this
+    /// side, such as running out of fds, buffers or local ports, aren't held against `addr`.
+    ///
+    /// [`CircuitOpen`]: crate::breaker::CircuitOpen
+    pub fn connect_breaker(addr: SocketAddr, breaker: &CircuitBreaker) -> io::Result<TcpStream> {
+        breaker.check(addr)?;
+        let stream = match new_for_addr(addr) {
+            Ok(socket) => TcpStream::from_std(socket.into_std()),
+            Err(err) => {
+                breaker.cancel_probe(addr);
+                return Err(err);
+            }
+        };
+
+        let deadline = Instant::now() + breaker.config().connect_timeout;
+        let result = stream
+            .start_connect(addr)
+            .and_then(|_| stream.wait_connected(deadline));
+        match &result {
+            Ok(()) => breaker.record_success(addr),
+            Err(err) if matches!(err.raw_os_error(), Some(EADDRNOTAVAIL | ENOBUFS | ENOMEM)) => {
+                breaker.cancel_probe(addr)
+            }
+            Err(_) => breaker.record_failure(addr),
+        }
+        result.map(|()| stream)
+    }
+
+    #[cfg(feature = "proxy")]
+    pub(crate) fn connect_wait(addr: SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+        let deadline = Instant::now() + timeout;
+        let stream = TcpStream::connect(addr)?;
+        stream.wait_connected(deadline)?;
+        Ok(stream)
+    }
+
+    /// Blocks until a connect in progress completes or `deadline` passes.
+    fn wait_connected(&self, deadline: Instant) -> io::Result<()> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            if poll_fd(self.as_raw_fd(), POLLOUT, Some(remaining))? != 0 {
+                break;
+            }
+        }
+        match self.take_error()? {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Connects with TCP Fast Open, sending the start of `data` with the SYN if the kernel
+    /// holds a Fast Open cookie for `addr`, and blocks until connected, up to `timeout`.
+    /// Returns the stream and how many bytes of `data` were sent; the caller writes the
+    /// rest. Without a cookie the SYN only requests one and nothing is sent.
+    ///
+    /// Destinations `cache` holds as failed are connected to normally. A failure is
+    /// recorded when the peer doesn't acknowledge the SYN's data, or when Fast Open is
+    /// disabled locally (`net.ipv4.tcp_fastopen`).
+    pub fn connect_fastopen(
+        addr: SocketAddr,
+        data: &[u8],
+        cache: &TfoCache,
+        timeout: Duration,
+    ) -> io::Result<(TcpStream, usize)> {
+        let deadline = Instant::now() + timeout;
+        if !cache.should_try(addr) {
+            let stream = TcpStream::connect(addr)?;
+            stream.wait_connected(deadline)?;
+            return Ok((stream, 0));
+        }
+
+        let socket = new_for_addr(addr)?;
+        let stream = TcpStream::from_std(socket.into_std());
+        let (raw_addr, raw_addr_length) = socket_addr(&addr);
+        let sent = match syscall!(sendto(
+            stream.as_raw_fd(),
+            data.as_ptr() as *const c_void,
+            data.len(),
+            MSG_FASTOPEN | MSG_NOSIGNAL,
+            raw_addr.as_ptr(),
+            raw_addr_length
+        )) {
+            Ok(sent) => sent as usize,
+            Err(err) if err.raw_os_error() == Some(EINPROGRESS) => 0,
+            // Client-side Fast Open is turned off.
+            Err(err) if matches!(err.raw_os_error(), Some(EOPNOTSUPP | EPIPE)) => {
+                cache.record_failure(addr);
+                drop(stream);
+                let stream = TcpStream::connect(addr)?;
+                stream.wait_connected(deadline)?;
+                return Ok((stream, 0));
+            }
+            Err(err) => return Err(err),
+        };
+        stream.wait_connected(deadline)?;
+
+        if sent > 0 {
+            let info = tcp_info(stream.as_raw_fd())?;
+            if info.tcpi_options & TCPI_OPT_SYN_DATA != 0 {
+                cache.record_success(addr);
+            } else {
+                cache.record_failure(addr);
+            }
+        }
+        Ok((stream, sent))
+    }
+
+    /// Resolves `host` through `cache` and connects to one of its addresses with
+    /// [`connect_happy`](TcpStream::connect_happy), blocking until connected.
+    pub fn connect_host_cached(
+        host: &str,
+        port: u16,
+        cache: &ResolverCache,
+    ) -> io::Result<TcpStream> {
+        let addrs: Vec<_> = cache
+            .lookup(host)?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect();
+        TcpStream::connect_happy(&addrs, Duration::from_millis(250))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    pub fn nodelay(&self) -> io::Result<bool> {
+        self.inner.nodelay()
+    }
+
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        self.inner.ttl()
+    }
+
+    /// Only report the socket as writable once fewer than `lowat` bytes are waiting to be
+    /// sent, keeping less data queued in the kernel.
+    pub fn set_notsent_lowat(&self, lowat: u32) -> io::Result<()> {
+        setsockopt(
+            self.as_raw_fd(),
+            IPPROTO_TCP,
+            TCP_NOTSENT_LOWAT,
+            lowat as c_int,
+        )
+    }
+
+    pub fn notsent_lowat(&self) -> io::Result<u32> {
+        getsockopt::<c_int>(self.as_raw_fd(), IPPROTO_TCP, TCP_NOTSENT_LOWAT).map(|val| val as u32)
+    }
+
+    /// Only report the socket as readable once at least `lowat` bytes are buffered, so
+    /// servers with a known minimum message size aren't woken for partial frames. A read
+    /// still returns early at EOF or on an error. The send side counterpart, `SO_SNDLOWAT`,
+    /// is fixed at 1 on Linux; use [`set_notsent_lowat`](TcpStream::set_notsent_lowat)
+    /// instead.
+    pub fn set_recv_lowat(&self, lowat: u32) -> io::Result<()> {
+        let lowat = lowat.min(c_int::MAX as u32) as c_int;
+        setsockopt(self.as_raw_fd(), SOL_SOCKET, SO_RCVLOWAT, lowat)
+    }
+
+    pub fn recv_lowat(&self) -> io::Result<u32> {
+        getsockopt::<c_int>(self.as_raw_fd(), SOL_SOCKET, SO_RCVLOWAT).map(|val| val as u32)
+    }
+
+    /// Caps how many SYNs are sent before a connect gives up, shortening the time to fail
+    /// against unreachable hosts. Takes effect for retransmits still pending, so it can be
+    /// set right after `connect` returns.
+    pub fn set_syn_retries(&self, retries: u8) -> io::Result<()> {
+        setsockopt(self.as_raw_fd(), IPPROTO_TCP, TCP_SYNCNT, retries as c_int)
+    }
+
+    pub fn syn_retries(&self) -> io::Result<u8> {
+        getsockopt::<c_int>(self.as_raw_fd(), IPPROTO_TCP, TCP_SYNCNT).map(|val| val as u8)
+    }
+
+    /// Sends only to directly connected hosts, bypassing the routing table. For single
+    /// sends, pass `MSG_DONTROUTE` to [`send_with_flags`](TcpStream::send_with_flags).
+    pub fn set_dontroute(&self, dontroute: bool) -> io::Result<()> {
+        setsockopt(
+            self.as_raw_fd(),
+            SOL_SOCKET,
+            SO_DONTROUTE,
+            dontroute as c_int,
+        )
+    }
+
+    pub fn dontroute(&self) -> io::Result<bool> {
+        getsockopt::<c_int>(self.as_raw_fd(), SOL_SOCKET, SO_DONTROUTE).map(|val| val != 0)
+    }
+
+    /// The path MTU the kernel currently knows for this connection, from `IP_MTU` or
+    /// `IPV6_MTU`.
+    pub fn path_mtu(&self) -> io::Result<u32> {
+        path_mtu(self.as_raw_fd())
+    }
+
+    pub fn set_mtu_discover(&self, mode: MtuDiscover) -> io::Result<()> {
+        set_mtu_discover(self.as_raw_fd(), mode)
+    }
+
+    pub fn mtu_discover(&self) -> io::Result<MtuDiscover> {
+        mtu_discover(self.as_raw_fd())
+    }
+
+    /// Busy-polls the device queue for up to `timeout` (in whole microseconds) before a
+    /// blocking read sleeps, trading a spinning CPU for lower receive latency. Raising it
+    /// above its current value, initially the `net.core.busy_read` sysctl, requires
+    /// `CAP_NET_ADMIN`.
+    pub fn set_busy_poll(&self, timeout: Duration) -> io::Result<()> {
+        let usecs = timeout.as_micros().min(c_int::MAX as u128) as c_int;
+        setsockopt(self.as_raw_fd(), SOL_SOCKET, SO_BUSY_POLL, usecs)
+    }
+
+    pub fn busy_poll(&self) -> io::Result<Duration> {
+        getsockopt::<c_int>(self.as_raw_fd(), SOL_SOCKET, SO_BUSY_POLL)
+            .map(|usecs| Duration::from_micros(usecs as u64))
+    }
+
+    /// Prefers busy polling over interrupts for this socket when the device is set up for
+    /// it. Enabling it requires `CAP_NET_ADMIN`, and kernels before 5.11 fail with
+    /// `ENOPROTOOPT`.
+    pub fn set_prefer_busy_poll(&self, prefer: bool) -> io::Result<()> {
+        setsockopt(
+            self.as_raw_fd(),
+            SOL_SOCKET,
+            SO_PREFER_BUSY_POLL,
+            prefer as c_int,
+        )
+    }
+
+    pub fn prefer_busy_poll(&self) -> io::Result<bool> {
+        getsockopt::<c_int>(self.as_raw_fd(), SOL_SOCKET, SO_PREFER_BUSY_POLL).map(|val| val != 0)
+    }
+
+    /// Puts the socket into or takes it out of TCP repair mode (`TCP_REPAIR`), used to
+    /// checkpoint a connection and restore it elsewhere, e.g. in another process or on
+    /// another host that takes over the address.
+    ///
+    /// In repair mode the socket stops talking to the peer: `connect` sets it up as
+    /// established without a handshake, `close` sends no FIN or RST, and writes fill the
+    /// send queue without transmitting. Sequence numbers and queue contents can then be read
+    /// and set with [`set_repair_queue`](TcpStream::set_repair_queue) and
+    /// [`queue_seq`](TcpStream::queue_seq). Leaving repair mode resumes normal operation, and
+    /// a restored socket must match the peer's view of the connection exactly or it will be
+    /// reset.
+    ///
+    /// This requires `CAP_NET_ADMIN` in the socket's network namespace; without it this
+    /// fails with `PermissionDenied`.
+    pub fn repair_mode(&self, on: bool) -> io::Result<()> {
+        setsockopt(self.as_raw_fd(), IPPROTO_TCP, TCP_REPAIR, on as c_int).map_err(|err| {
+            if err.raw_os_error() == Some(EPERM) {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "TCP repair mode requires CAP_NET_ADMIN",
+                )
+            } else {
+                err
+            }
+        })
+    }
+
+    pub fn is_repair_mode(&self) -> io::Result<bool> {
+        getsockopt::<c_int>(self.as_raw_fd(), IPPROTO_TCP, TCP_REPAIR).map(|val| val != 0)
+    }
+
+    /// Selects the queue that `queue_seq`, `set_queue_seq`, reads and writes act on while in
+    /// repair mode. Reads then peek at the queue's contents and writes fill it.
+    pub fn set_repair_queue(&self, queue: RepairQueue) -> io::Result<()> {
+        let queue = match queue {
+            RepairQueue::Recv => TCP_RECV_QUEUE,
+            RepairQueue::Send => TCP_SEND_QUEUE,
+        };
+        setsockopt(self.as_raw_fd(), IPPROTO_TCP, TCP_REPAIR_QUEUE, queue)
+    }
+
+    /// The selected repair queue, `None` if none has been selected.
+    pub fn repair_queue(&self) -> io::Result<Option<RepairQueue>> {
+        let queue = getsockopt::<c_int>(self.as_raw_fd(), IPPROTO_TCP, TCP_REPAIR_QUEUE)?;
+        Ok(match queue {
+            TCP_RECV_QUEUE => Some(RepairQueue::Recv),
+            TCP_SEND_QUEUE => Some(RepairQueue::Send),
+            _ => None,
+        })
+    }
+
+    /// The sequence number of the selected repair queue, see [`RepairQueue`]. Requires
+    /// repair mode.
+    pub fn queue_seq(&self) -> io::Result<u32> {
+        getsockopt::<u32>(self.as_raw_fd(), IPPROTO_TCP, TCP_QUEUE_SEQ)
+    }
+
+    /// Sets the sequence number of the selected repair queue. This only works in repair
+    /// mode and before the socket is connected.
+    pub fn set_queue_seq(&self, seq: u32) -> io::Result<()> {
+        setsockopt(self.as_raw_fd(), IPPROTO_TCP, TCP_QUEUE_SEQ, seq)
+    }
+
+    /// Captures the connection's state so it can be rebuilt with
+    /// [`import_state`](crate::net::import_state), e.g. by a new process taking over from
+    /// this one.
+    ///
+    /// This puts the socket into repair mode and leaves it there, so that it can be dropped
+    /// without the peer noticing; call `repair_mode(false)` to keep using it instead. It
+    /// requires `CAP_NET_ADMIN`, see [`repair_mode`](TcpStream::repair_mode), and only
+    /// works on established connections.
+    pub fn export_state(&self) -> io::Result<ConnState> {
+        if self.state()? != TcpState::Established {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only established connections can be exported",
+            ));
+        }
+        self.repair_mode(true)?;
+
+        let fd = self.as_raw_fd();
+        self.set_repair_queue(RepairQueue::Send)?;
+        let send_seq = self.queue_seq()?;
+        let send_queue = self.peek_repair_queue(TIOCOUTQ)?;
+        self.set_repair_queue(RepairQueue::Recv)?;
+        let recv_seq = self.queue_seq()?;
+        let recv_queue = self.peek_repair_queue(FIONREAD)?;
+
+        let info = tcp_info(fd)?;
+        let wscale = (info.tcpi_options & TCPI_OPT_WSCALE != 0).then(|| {
+            let scales = info.tcpi_snd_rcv_wscale;
+            (scales & 0xf, scales >> 4)
+        });
+        let timestamp = if info.tcpi_options & TCPI_OPT_TIMESTAMPS != 0 {
+            Some(getsockopt::<u32>(fd, IPPROTO_TCP, TCP_TIMESTAMP)?)
+        } else {
+            None
+        };
+
+        Ok(ConnState {
+            local_addr: self.local_addr()?,
+            peer_addr: self.peer_addr()?,
+            send_seq,
+            send_queue,
+            recv_seq,
+            recv_queue,
+            window: getsockopt(fd, IPPROTO_TCP, TCP_REPAIR_WINDOW)?,
+            mss: getsockopt::<c_int>(fd, IPPROTO_TCP, TCP_MAXSEG)? as u32,
+            wscale,
+            sack: info.tcpi_options & TCPI_OPT_SACK != 0,
+            timestamp,
+        })
+    }
+
+    /// Copies the selected repair queue, whose length `request` reports.
+    fn peek_repair_queue(&self, request: Ioctl) -> io::Result<Vec<u8>> {
+        let mut len: c_int = 0;
+        syscall!(ioctl(self.as_raw_fd(), request, &mut len))?;
+        let mut buf = vec![0; len as usize];
+        if buf.is_empty() {
+            return Ok(buf);
+        }
+
+        let n = syscall!(recv(
+            self.as_raw_fd(),
+            buf.as_mut_ptr() as *mut c_void,
+            buf.len(),
+            MSG_PEEK | MSG_DONTWAIT,
+        ))? as usize;
+        if n != buf.len() {
+            return Err(io::Error::other("repair queue changed while being copied"));
+        }
+        Ok(buf)
+    }
+
+    /// The CPU that last processed packets for this connection, useful for keeping work on
+    /// the same core or NUMA node. This is only a hint and is -1 when unknown.
+    pub fn incoming_cpu(&self) -> io::Result<i32> {
+        getsockopt::<c_int>(self.as_raw_fd(), SOL_SOCKET, SO_INCOMING_CPU)
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
+
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.peek(buf)
+    }
+
+    /// Returns up to `n` of the first unread bytes without consuming them, e.g. to tell a
+    /// TLS handshake (a first byte of 0x16) from plaintext before picking a handler. Fewer
+    /// bytes are returned if no more have arrived yet.
+    pub fn sniff(&self, n: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; n];
+        let len = self.peek(&mut buf)?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    pub fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        let linger = libc::linger {
+            l_onoff: linger.is_some() as c_int,
+            l_linger: linger.map_or(0, |d| d.as_secs().min(c_int::MAX as u64) as c_int),
+        };
+        setsockopt(self.as_raw_fd(), SOL_SOCKET, SO_LINGER, linger)
+    }
+
+    pub fn linger(&self) -> io::Result<Option<Duration>> {
+        let linger: libc::linger = getsockopt(self.as_raw_fd(), SOL_SOCKET, SO_LINGER)?;
+        Ok((linger.l_onoff != 0).then(|| Duration::from_secs(linger.l_linger as u64)))
+    }
+
+    /// Bytes in the kernel send queue that the peer hasn't acknowledged yet (`TIOCOUTQ`).
+    pub fn send_queue_len(&self) -> io::Result<usize> {
+        let mut len: c_int = 0;
+        syscall!(ioctl(self.as_raw_fd(), TIOCOUTQ, &mut len))?;
+        Ok(len as usize)
+    }
+
+    /// Blocks until the peer has acknowledged everything written so far, failing with
+    /// `TimedOut` after `timeout`. Unlike `flush`, which only hands bytes to the kernel,
+    /// this means the data reached the peer's receive buffer. There is no readiness event
+    /// for the queue draining, so it is polled, backing off from 1ms to 50ms.
+    pub fn wait_sent(&self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut delay = Duration::from_millis(1);
+        while self.send_queue_len()? != 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            thread::sleep(delay.min(remaining));
+            delay = (delay * 2).min(Duration::from_millis(50));
+        }
+        Ok(())
+    }
+
+    /// Closes the connection as `mode` says. A graceful close that runs out of time fails
+    /// with `TimedOut`, closing the connection anyway.
+    pub fn close_with(self, mode: CloseMode) -> io::Result<()> {
+        match mode {
+            CloseMode::Reset => {
+                self.set_linger(Some(Duration::ZERO))?;
+                syscall!(close(self.into_raw_fd())).map(|_| ())
+            }
+            CloseMode::Graceful(timeout) => self.drain_and_close(Instant::now() + timeout),
+        }
+    }
+
+    /// Closes the connection without losing response bytes: writes out `queue`, shuts down
+    /// the write side, then discards input until the peer closes its side too, so unread
+    /// input can't make the close send a reset. Waits at most `timeout` overall and fails
+    /// with `TimedOut` if the peer is too slow, closing the connection anyway.
+    pub fn finish(mut self, queue: &mut WriteQueue, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        while !queue.flush_to(&mut self)? {
+            wait_until(self.as_raw_fd(), POLLOUT, deadline)?;
+        }
+        self.drain_and_close(deadline)
+    }
+
+    /// Shuts down the write side, then discards input until the peer closes its side or
+    /// `deadline` passes, and closes.
+    fn drain_and_close(mut self, deadline: Instant) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)?;
+        let mut buf = [0; 4096];
+        loop {
+            match self.inner.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    wait_until(self.as_raw_fd(), POLLIN, deadline)?
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        syscall!(close(self.into_raw_fd())).map(|_| ())
+    }
+
+    /// Turns away a connection, first writing `message` if there's room for it in the send
+    /// buffer. With [`CloseMode::Graceful`] the peer gets to read the message even if it
+    /// still has request bytes in flight, as long as it closes its side within the timeout;
+    /// a reset may discard the message.
+    pub fn reject(self, message: Option<&[u8]>, mode: CloseMode) -> io::Result<()> {
+        if let Some(message) = message {
+            let _ = send(self.as_raw_fd(), message, 0);
+        }
+        self.close_with(mode)
+    }
+
+    /// Gathers addresses, options and the main `TCP_INFO` figures in one call, for
+    /// dashboards and debugging endpoints.
+    pub fn info(&self) -> ConnectionInfo {
+        let info = tcp_info(self.as_raw_fd()).ok();
+        ConnectionInfo {
+            local_addr: self.local_addr().ok(),
+            peer_addr: self.peer_addr().ok(),
+            nodelay: self.nodelay().ok(),
+            ttl: self.ttl().ok(),
+            mss: getsockopt::<c_int>(self.as_raw_fd(), IPPROTO_TCP, TCP_MAXSEG)
+                .ok()
+                .map(|mss| mss as u32),
+            state: info.map(|info| TcpState::from(info.tcpi_state)),
+            rtt: info.map(|info| Duration::from_micros(info.tcpi_rtt as u64)),
+            rtt_var: info.map(|info| Duration::from_micros(info.tcpi_rttvar as u64)),
+            snd_cwnd: info.map(|info| info.tcpi_snd_cwnd),
+            unacked: info.map(|info| info.tcpi_unacked),
+            total_retrans: info.map(|info| info.tcpi_total_retrans),
+        }
+    }
+
+    pub fn state(&self) -> io::Result<TcpState> {
+        tcp_info(self.as_raw_fd()).map(|info| TcpState::from(info.tcpi_state))
+    }
+
+    /// How big a buffer to read this connection into, e.g. to pick a pool by buffer size:
+    /// about what arrives per round trip, so fast connections take fewer reads and slow
+    /// ones don't tie up memory. A power of two between 4 KiB and 256 KiB, worth asking
+    /// again now and then as the connection speeds up or slows down.
+    pub fn suggested_read_size(&self) -> io::Result<usize> {
+        let info = tcp_info(self.as_raw_fd())?;
+        // `tcpi_rcv_space` is the receive autotuning estimate of what the peer sends per
+        // round trip. Until the kernel has measured the receive RTT, the bandwidth-delay
+        // product of our own direction stands in for it.
+        let per_rtt = if info.tcpi_rcv_rtt > 0 {
+            info.tcpi_rcv_space as usize
+        } else {
+            info.tcpi_snd_cwnd as usize * info.tcpi_snd_mss as usize
+        };
+        Ok(per_rtt
+            .clamp(MIN_READ_SIZE, MAX_READ_SIZE)
+            .next_power_of_two()
+            .min(MAX_READ_SIZE))
+    }
+
+    /// Sends `buf` with the given `send(2)` flags, e.g. `MSG_MORE` to hold back a partial
+    /// segment until the next send. `MSG_NOSIGNAL` is always added, so writing to a closed
+    /// connection fails with `EPIPE` instead of raising `SIGPIPE`.
+    pub fn send_with_flags(&self, buf: &[u8], flags: c_int) -> io::Result<usize> {
+        let result = send(self.as_raw_fd(), buf, flags);
+        self.record_activity(&result);
+        result
+    }
+
+    /// Sends `headers` followed by `count` bytes of `file` starting at `*offset`, the body
+    /// going out zero-copy with `sendfile(2)`. `TCP_CORK` holds the headers back so they
+    /// share the first segment with the start of the body.
+    ///
+    /// Returns the number of bytes sent, headers included, and advances `*offset` past the
+    /// body bytes sent. It stops early once the socket would block, or if the file ends
+    /// before `count` bytes; `WouldBlock` is only returned if nothing was sent. To resume,
+    /// call again with `headers` advanced by what was sent of them (see
+    /// `IoSlice::advance_slices`) and `count` reduced by what was sent of the body.
+    pub fn send_file_with_headers(
+        &self,
+        headers: &[IoSlice<'_>],
+        file: &impl AsRawFd,
+        offset: &mut i64,
+        count: usize,
+    ) -> io::Result<usize> {
+        let fd = self.as_raw_fd();
+        let cork = count > 0 && headers.iter().any(|header| !header.is_empty());
+        if cork {
+            setsockopt(fd, IPPROTO_TCP, TCP_CORK, 1 as c_int)?;
+        }
+        let result = self.send_headers_then_file(headers, file.as_raw_fd(), offset, count);
+        self.record_activity(&result);
+        if cork {
+            // Uncorking sends whatever is still held back, also when stopping early. It's
+            // best-effort so a failure can't hide how much was sent; corked data goes out
+            // after 200ms regardless.
+            let _ = setsockopt(fd, IPPROTO_TCP, TCP_CORK, 0 as c_int);
+        }
+        result
+    }
+
+    fn send_headers_then_file(
+        &self,
+        headers: &[IoSlice<'_>],
+        file: RawFd,
+        offset: &mut i64,
+        count: usize,
+    ) -> io::Result<usize> {
+        let mut sent = 0;
+        let mut headers = headers;
+        // How much of `headers[0]` has gone out, which is then sent on its own since the
+        // caller's slices can't be advanced in place.
+        let mut partial = 0;
+        while let Some(first) = headers.first() {
+            let result = if partial > 0 {
+                send(self.as_raw_fd(), &first[partial..], 0)
+            } else {
+                send_vectored(self.as_raw_fd(), headers, 0)
+            };
+            match result {
+                Ok(n) => {
+                    sent += n;
+                    let mut n = partial + n;
+                    partial = 0;
+                    while let Some(first) = headers.first() {
+                        if n < first.len() {
+                            partial = n;
+                            break;
+                        }
+                        n -= first.len();
+                        headers = &headers[1..];
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock && sent > 0 => return Ok(sent),
+                Err(err) => return Err(err),
+            }
+        }
+
+        let mut remaining = count;
+        while remaining > 0 {
+            match syscall!(sendfile(self.as_raw_fd(), file, offset, remaining)) {
+                // The file ended early.
+                Ok(0) => break,
+                Ok(n) => {
+                    sent += n as usize;
+                    remaining -= n as usize;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock && sent > 0 => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Like `read`, but returns `Ok(None)` instead of a `WouldBlock` error. EOF is still
+    /// reported as `Ok(Some(0))`.
+    pub fn try_read(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        would_block_to_none(self.read(buf))
+    }
+
+    /// Like `write`, but returns `Ok(None)` instead of a `WouldBlock` error.
+    pub fn try_write(&mut self, buf: &[u8]) -> io::Result<Option<usize>> {
+        would_block_to_none(self.write(buf))
+    }
+
+    /// Like `read_exact`, but waits for readability instead of failing with `WouldBlock`,
+    /// giving up with `TimedOut` once `timeout` has elapsed.
+    pub fn read_exact_timeout(&mut self, mut buf: &mut [u8], timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        while !buf.is_empty() {
+            match self.inner.read(buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ))
+                }
+                Ok(n) => {
+                    self.record_activity(&Ok(n));
+                    let tmp = buf;
+                    buf = &mut tmp[n..];
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(io::ErrorKind::TimedOut.into());
+                    }
+                    poll_fd(self.as_raw_fd(), POLLIN, Some(remaining))?;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Performs a single read, waiting at most `timeout` for data and failing with
+    /// `TimedOut` after that. Unlike a timeout set with `SO_RCVTIMEO`, this leaves the
+    /// socket's options untouched, so it is safe on a shared stream.
+    pub fn read_timeout_once(&self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match (&self.inner).read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(io::ErrorKind::TimedOut.into());
+                    }
+                    poll_fd(self.as_raw_fd(), POLLIN, Some(remaining))?;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                result => {
+                    self.record_activity(&result);
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// Receives into the unfilled part of `buf` and returns the number of bytes read, with 0
+    /// meaning EOF. Fails with `InvalidInput` if `buf` is full; consume or clear some of it
+    /// first.
+    pub fn recv_into(&self, buf: &mut RecvBuf) -> io::Result<usize> {
+        if buf.is_full() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "receive buffer is full",
+            ));
+        }
+        let (ptr, len) = buf.unfilled_ptr();
+        let n = syscall!(recv(self.as_raw_fd(), ptr, len, 0))? as usize;
+        unsafe { buf.assume_filled(n) };
+        self.record_activity(&Ok(n));
+        Ok(n)
+    }
+
+    /// Reads like `read`, but reports a clean close and a connection reset as outcomes rather
+    /// than as `Ok(0)` and an error. An empty `buf` is reported as `Eof`.
+    pub fn read_classify(&mut self, buf: &mut [u8]) -> io::Result<ReadOutcome> {
+        let result = self.inner.read(buf);
+        self.record_activity(&result);
+        match result {
+            Ok(0) => Ok(ReadOutcome::Eof),
+            Ok(n) => Ok(ReadOutcome::Data(n)),
+            Err(err) if matches!(err.raw_os_error(), Some(ECONNRESET) | Some(EPIPE)) => {
+                Ok(ReadOutcome::Reset)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// When this stream was created, by `connect`, `accept` or converting a std stream.
+    pub fn connected_at(&self) -> Instant {
+        self.connected_at
+    }
+
+    /// When data was last read or written through any of this stream's methods, or
+    /// `connected_at` if none has been yet. Reads at EOF and failed transfers don't count,
+    /// nor does I/O done on the raw fd. Compare against `Instant::now()` for idle timeouts.
+    pub fn last_activity(&self) -> Instant {
+        self.connected_at + Duration::from_nanos(self.last_activity.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn record_activity(&self, result: &io::Result<usize>) {
+        if matches!(result, Ok(n) if *n > 0) {
+            let since = self.connected_at.elapsed().as_nanos() as u64;
+            self.last_activity.store(since, Ordering::Relaxed);
+        }
+    }
+
+    pub fn from_std(stream: net::TcpStream) -> TcpStream {
+        Self::from(stream)
+    }
+
+    /// Hands the socket over to std without duplicating the fd. The std stream is still
+    /// non-blocking, so its reads and writes can fail with `WouldBlock`.
+    pub fn into_std(self) -> net::TcpStream {
+        self.inner
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.inner.read(buf);
+        self.record_activity(&result);
+        result
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        let result = self.inner.read_vectored(bufs);
+        self.record_activity(&result);
+        result
+    }
+}
+
+/// Unlike std, which only suppresses `SIGPIPE` for some of its write paths, every write
+/// passes `MSG_NOSIGNAL`: writing to a closed connection always fails with `EPIPE`.
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = send(self.as_raw_fd(), buf, 0);
+        self.record_activity(&result);
+        result
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let result = send_vectored(self.as_raw_fd(), bufs, 0);
+        self.record_activity(&result);
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl From<net::TcpStream> for TcpStream {
+    fn from(s: net::TcpStream) -> Self {
+        TcpStream {
+            inner: s,
+            connected_at: Instant::now(),
+            last_activity: AtomicU64::new(0),
+        }
+    }
+}
+
+impl IntoRawFd for TcpStream {
+    fn into_raw_fd(self) -> RawFd {
+        self.inner.into_raw_fd()
+    }
+}
+
+impl AsRawFd for TcpStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl FromRawFd for TcpStream {
+    unsafe fn from_raw_fd(fd: RawFd) -> TcpStream {
+        TcpStream::from_std(FromRawFd::from_raw_fd(fd))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn listener() -> TcpListener {
+        TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    fn accept(listener: &TcpListener) -> TcpStream {
+        poll_fd(listener.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        listener.accept().unwrap().0
+    }
+
+    /// A connected client and server stream over loopback.
+    fn pair() -> (TcpStream, TcpStream) {
+        let listener = listener();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = accept(&listener);
+        poll_fd(client.as_raw_fd(), POLLOUT, Some(TIMEOUT)).unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn accept_stats_count_only_accepted_connections() {
+        let listener = listener();
+        assert_eq!(listener.accept_stats(), AcceptStats::default());
+
+        // Nothing pending is not a failure.
+        let err = listener.accept().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(listener.accept_stats(), AcceptStats::default());
+
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let _server = accept(&listener);
+        assert_eq!(
+            listener.accept_stats(),
+            AcceptStats {
+                accepted: 1,
+                ..AcceptStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn bind_config_applies_the_options() {
+        // Binding a specific IPv6 address makes the socket IPv6-only regardless, so these
+        // use the unspecified address.
+        let config = ListenerConfig {
+            reuseaddr: true,
+            reuseport: true,
+            v6only: Some(true),
+            backlog: 7,
+            recv_buffer_size: Some(64 * 1024),
+            ..ListenerConfig::default()
+        };
+        let listener = TcpListener::bind_config("[::]:0".parse().unwrap(), &config).unwrap();
+        assert!(listener.reuseaddr().unwrap());
+        assert!(listener.reuseport().unwrap());
+        assert!(listener.only_v6().unwrap());
+        assert_eq!(listener.backlog_capacity().unwrap(), 7);
+        assert_eq!(listener.recv_buffer_size().unwrap(), 2 * 64 * 1024);
+
+        let config = ListenerConfig {
+            reuseaddr: false,
+            v6only: Some(false),
+            ..ListenerConfig::default()
+        };
+        let listener = TcpListener::bind_config("[::]:0".parse().unwrap(), &config).unwrap();
+        assert!(!listener.reuseaddr().unwrap());
+        assert!(!listener.reuseport().unwrap());
+        assert!(!listener.only_v6().unwrap());
+    }
+
+    #[test]
+    fn shutdown_on_drop_stops_a_shared_listener() {
+        for shutdown in [false, true] {
+            let mut listener = listener();
+            let addr = listener.local_addr().unwrap();
+            listener.set_shutdown_on_drop(shutdown);
+            let shared = syscall!(dup(listener.as_raw_fd())).unwrap();
+            let shared = unsafe { OwnedFd::from_raw_fd(shared) };
+            drop(listener);
+
+            let client = TcpStream::connect(addr).unwrap();
+            poll_fd(client.as_raw_fd(), POLLOUT, Some(TIMEOUT)).unwrap();
+            let refused = matches!(
+                client.take_error().unwrap(),
+                Some(err) if err.kind() == io::ErrorKind::ConnectionRefused
+            );
+            assert_eq!(refused, shutdown);
+            drop(shared);
+        }
+    }
+
+    #[test]
+    fn transfers_update_last_activity() {
+        let (client, server) = pair();
+        let (_idle_client, idle_server) = pair();
+        thread::sleep(Duration::from_millis(10));
+
+        assert_eq!(client.send_with_flags(b"ping", 0).unwrap(), 4);
+        let mut buf = [0; 16];
+        assert_eq!(server.read_timeout_once(&mut buf, TIMEOUT).unwrap(), 4);
+        assert!(client.last_activity() > client.connected_at());
+        let read_at = server.last_activity();
+        assert!(read_at > server.connected_at());
+
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(client.send_with_flags(b"pong", 0).unwrap(), 4);
+        let mut recv_buf = RecvBuf::with_capacity(16);
+        poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        assert_eq!(server.recv_into(&mut recv_buf).unwrap(), 4);
+        assert!(server.last_activity() > read_at);
+
+        assert_eq!(idle_server.last_activity(), idle_server.connected_at());
+    }
+
+    #[test]
+    fn recv_into_accounts_for_successive_fills() {
+        let (client, server) = pair();
+        let mut buf = RecvBuf::with_capacity(8);
+
+        client.send_with_flags(b"hello", 0).unwrap();
+        poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        assert_eq!(server.recv_into(&mut buf).unwrap(), 5);
+        assert_eq!(buf.init_len(), 5);
+
+        // Only the unfilled part is offered to the kernel, so the rest waits for room.
+        client.send_with_flags(b"world", 0).unwrap();
+        poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        assert_eq!(server.recv_into(&mut buf).unwrap(), 3);
+        assert_eq!(buf.filled(), b"hellowor");
+        let err = server.recv_into(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        buf.consume(5);
+        assert_eq!(server.recv_into(&mut buf).unwrap(), 2);
+        assert_eq!(buf.filled(), b"world");
+        assert_eq!(buf.init_len(), 8);
+
+        drop(client);
+        poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        assert_eq!(server.recv_into(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn close_with_reset_resets_the_peer() {
+        let (client, server) = pair();
+        server.close_with(CloseMode::Reset).unwrap();
+        let err = client.read_timeout_once(&mut [0; 1], TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn close_with_graceful_survives_late_input() {
+        let (mut client, server) = pair();
+        client.write_all(b"request").unwrap();
+        let closing = thread::spawn(move || server.close_with(CloseMode::Graceful(TIMEOUT)));
+
+        assert_eq!(client.read_timeout_once(&mut [0; 1], TIMEOUT).unwrap(), 0);
+        // Input that arrives while the server drains doesn't turn the close into a reset.
+        client.write_all(b"more of the request").unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(client.read_timeout_once(&mut [0; 1], TIMEOUT).unwrap(), 0);
+        drop(client);
+        closing.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn close_with_graceful_times_out_on_a_peer_that_stays_open() {
+        let (_client, server) = pair();
+        let err = server
+            .close_with(CloseMode::Graceful(Duration::from_millis(50)))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn reject_lets_the_client_read_the_message() {
+        let (mut client, server) = pair();
+        client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        let rejecting = thread::spawn(move || {
+            server.reject(Some(b"busy, try later"), CloseMode::Graceful(TIMEOUT))
+        });
+
+        // Sent after the reject started, like the rest of a request being uploaded.
+        client.write_all(b"Host: example.com\r\n\r\n").unwrap();
+        let mut message = [0; 15];
+        client.read_exact_timeout(&mut message, TIMEOUT).unwrap();
+        assert_eq!(&message, b"busy, try later");
+        assert_eq!(client.read_timeout_once(&mut [0; 1], TIMEOUT).unwrap(), 0);
+        drop(client);
+        rejecting.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn send_file_with_headers_sends_the_whole_stream() {
+        let header = b"HTTP/1.1 200 OK\r\nContent-Length: 1000000\r\n\r\n";
+        let body: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+        let path = std::env::temp_dir().join(format!("rio-sendfile-{}", std::process::id()));
+        std::fs::write(&path, &body).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let (client, server) = pair();
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut buf = [0; 65536];
+            loop {
+                match client.read_timeout_once(&mut buf, TIMEOUT).unwrap() {
+                    0 => return received,
+                    n => received.extend_from_slice(&buf[..n]),
+                }
+            }
+        });
+
+        // Resumes after `WouldBlock` in either phase, as a server would.
+        let mut header_sent = 0;
+        let mut offset = 0;
+        let mut remaining = body.len();
+        while header_sent < header.len() || remaining > 0 {
+            let headers = [IoSlice::new(&header[header_sent..])];
+            match server.send_file_with_headers(&headers, &file, &mut offset, remaining) {
+                Ok(n) => {
+                    let of_header = n.min(header.len() - header_sent);
+                    header_sent += of_header;
+                    remaining -= n - of_header;
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    poll_fd(server.as_raw_fd(), POLLOUT, Some(TIMEOUT)).unwrap();
+                }
+                Err(err) => panic!("{err}"),
+            }
+        }
+        assert_eq!(offset, body.len() as i64);
+        drop(server);
+
+        let received = reader.join().unwrap();
+        assert_eq!(&received[..header.len()], header);
+        assert!(received[header.len()..] == body[..]);
+    }
+
+    /// An address nothing listens on.
+    fn closed_addr() -> SocketAddr {
+        listener().local_addr().unwrap()
+    }
+
+    #[test]
+    fn connect_happy_falls_back_after_a_refused_address() {
+        let listener = listener();
+        let addr = listener.local_addr().unwrap();
+        let started = Instant::now();
+        let stream = TcpStream::connect_happy(&[closed_addr(), addr], TIMEOUT).unwrap();
+        // The refusal starts the next attempt without waiting out the delay.
+        assert!(started.elapsed() < TIMEOUT);
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+
+        let err = TcpStream::connect_happy(&[closed_addr()], TIMEOUT)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn connect_happy_staggers_attempts() {
+        // With a backlog of zero the accept queue holds one connection, and SYNs beyond
+        // that are dropped, so connects to `stalled` hang.
+        let stalled = listener();
+        syscall!(listen(stalled.as_raw_fd(), 0)).unwrap();
+        let stalled_addr = stalled.local_addr().unwrap();
+        let _queued = [
+            TcpStream::connect(stalled_addr).unwrap(),
+            TcpStream::connect(stalled_addr).unwrap(),
+        ];
+        let open = listener();
+        let addr = open.local_addr().unwrap();
+
+        let delay = Duration::from_millis(100);
+        let started = Instant::now();
+        let stream = TcpStream::connect_happy(&[stalled_addr, addr], delay).unwrap();
+        let elapsed = started.elapsed();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        // Started after the delay, and won long before the stalled SYN is retransmitted.
+        assert!(elapsed >= delay && elapsed < Duration::from_secs(1));
+
+        // Without a stall, the first address wins before the second is tried.
+        let other = listener();
+        let stream =
+            TcpStream::connect_happy(&[addr, other.local_addr().unwrap()], TIMEOUT).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        assert_eq!(
+            other.accept().err().unwrap().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn connect_happy_races_ipv6_and_ipv4() {
+        let v6 = TcpListener::bind("[::1]:0".parse().unwrap()).unwrap();
+        let v4 = listener();
+        let v6_addr = v6.local_addr().unwrap();
+        let v4_addr = v4.local_addr().unwrap();
+
+        // Families alternate, starting with the first address's.
+        let stream = TcpStream::connect_happy(&[v6_addr, v4_addr], TIMEOUT).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), v6_addr);
+        let stream = TcpStream::connect_happy(&[v4_addr, v6_addr], TIMEOUT).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), v4_addr);
+
+        // The other family wins when the preferred one is refused.
+        drop(v6);
+        let stream = TcpStream::connect_happy(&[v6_addr, v4_addr], TIMEOUT).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), v4_addr);
+    }
+
+    #[test]
+    fn connect_happy_closes_the_losers() {
+        let listeners = [listener(), listener()];
+        let addrs = [
+            listeners[0].local_addr().unwrap(),
+            listeners[1].local_addr().unwrap(),
+        ];
+        // No delay, so both attempts are in flight when one completes.
+        let mut stream = TcpStream::connect_happy(&addrs, Duration::ZERO).unwrap();
+        let peer = stream.peer_addr().unwrap();
+        let winner = addrs.iter().position(|&addr| addr == peer).unwrap();
+        stream.write_all(b"x").unwrap();
+
+        let mut buf = [0; 1];
+        let server = accept(&listeners[winner]);
+        assert_eq!(server.read_timeout_once(&mut buf, TIMEOUT).unwrap(), 1);
+        let loser = accept(&listeners[1 - winner]);
+        assert_eq!(loser.read_timeout_once(&mut buf, TIMEOUT).unwrap(), 0);
+    }
+
+    fn register(epoll: &OwnedFd, stream: &TcpStream, token: usize) {
+        let mut event = epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: token as u64,
+        };
+        syscall!(epoll_ctl(
+            epoll.as_raw_fd(),
+            EPOLL_CTL_ADD,
+            stream.as_raw_fd(),
+            &mut event
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn conn_table_evicts_least_recently_active() {
+        let epoll = syscall!(epoll_create1(EPOLL_CLOEXEC)).unwrap();
+        let epoll = unsafe { OwnedFd::from_raw_fd(epoll) };
+        let mut table = ConnTable::new(2);
+
+        let (client0, server0) = pair();
+        thread::sleep(Duration::from_millis(10));
+        let (client1, server1) = pair();
+        let (_client2, server2) = pair();
+        register(&epoll, &server0, 0);
+        register(&epoll, &server1, 1);
+        register(&epoll, &server2, 2);
+        assert!(table.insert(&epoll, 0, server0, "a").unwrap().is_none());
+        assert!(table.insert(&epoll, 1, server1, "b").unwrap().is_none());
+        assert_eq!(table.least_recently_active(), Some(0));
+
+        // Activity makes the older connection the fresher one.
+        thread::sleep(Duration::from_millis(10));
+        client0.send_with_flags(b"x", 0).unwrap();
+        let (server0, _) = table.get_mut(0).unwrap();
+        server0.read_timeout_once(&mut [0; 1], TIMEOUT).unwrap();
+        assert_eq!(table.least_recently_active(), Some(1));
+
+        assert_eq!(
+            table.insert(&epoll, 2, server2, "c").unwrap(),
+            Some((1, "b"))
+        );
+        assert_eq!(table.len(), 2);
+        assert!(table.contains(0) && table.contains(2) && !table.contains(1));
+        assert_eq!(
+            table.insert(&epoll, 2, pair().1, "d").unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+
+        // The evicted connection was closed, so its peer reads EOF.
+        let mut buf = [0; 1];
+        assert_eq!(client1.read_timeout_once(&mut buf, TIMEOUT).unwrap(), 0);
+
+        // Only the remaining connections are still registered.
+        client0.send_with_flags(b"y", 0).unwrap();
+        let mut events = [epoll_event { events: 0, u64: 0 }; 4];
+        let n = syscall!(epoll_wait(epoll.as_raw_fd(), events.as_mut_ptr(), 4, 5000)).unwrap();
+        let tokens: Vec<u64> = events[..n as usize].iter().map(|event| event.u64).collect();
+        assert_eq!(tokens, vec![0]);
+    }
+
+    #[test]
+    fn read_exact_timeout_waits_for_every_byte() {
+        let (client, mut server) = pair();
+        let writer = thread::spawn(move || {
+            client.send_with_flags(b"hel", 0).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            client.send_with_flags(b"lo", 0).unwrap();
+            client
+        });
+        let mut buf = [0; 5];
+        server.read_exact_timeout(&mut buf, TIMEOUT).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let client = writer.join().unwrap();
+        client.send_with_flags(b"ab", 0).unwrap();
+        let mut buf = [0; 4];
+        let err = server
+            .read_exact_timeout(&mut buf, Duration::from_millis(50))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        drop(client);
+        let err = server.read_exact_timeout(&mut buf, TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn read_classify_tells_eof_from_reset() {
+        let (mut client, server) = pair();
+        server.send_with_flags(b"hi", 0).unwrap();
+        poll_fd(client.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(
+            client.read_classify(&mut buf).unwrap(),
+            ReadOutcome::Data(2)
+        );
+        drop(server);
+        poll_fd(client.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        assert_eq!(client.read_classify(&mut buf).unwrap(), ReadOutcome::Eof);
+
+        let (mut client, server) = pair();
+        server.close_with(CloseMode::Reset).unwrap();
+        poll_fd(client.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        assert_eq!(client.read_classify(&mut buf).unwrap(), ReadOutcome::Reset);
+
+        let (mut client, _server) = pair();
+        let err = client.read_classify(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn state_follows_the_connection() {
+        let listener = listener();
+        assert_eq!(tcp_info(listener.as_raw_fd()).unwrap().tcpi_state, 10);
+
+        let (client, server) = pair();
+        assert_eq!(client.state().unwrap(), TcpState::Established);
+        assert_eq!(server.state().unwrap(), TcpState::Established);
+
+        client.shutdown(Shutdown::Write).unwrap();
+        poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        assert_eq!(server.state().unwrap(), TcpState::CloseWait);
+        // FIN_WAIT2 once the FIN is acknowledged, which may be delayed.
+        assert!(matches!(
+            client.state().unwrap(),
+            TcpState::FinWait1 | TcpState::FinWait2
+        ));
+
+        assert_eq!(TcpState::from(200), TcpState::Unknown(200));
+    }
+
+    #[test]
+    fn try_accept_reports_an_empty_queue_as_none() {
+        let listener = listener();
+        assert!(listener.try_accept().unwrap().is_none());
+
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        poll_fd(listener.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        let (_server, addr) = listener.try_accept().unwrap().unwrap();
+        assert_eq!(addr, client.local_addr().unwrap());
+        assert!(listener.try_accept().unwrap().is_none());
+    }
+
+    #[test]
+    fn try_read_and_try_write_report_would_block_as_none() {
+        let (mut client, mut server) = pair();
+        let mut buf = [0; 8];
+        assert_eq!(server.try_read(&mut buf).unwrap(), None);
+
+        assert_eq!(client.try_write(b"ping").unwrap(), Some(4));
+        poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        assert_eq!(server.try_read(&mut buf).unwrap(), Some(4));
+        assert_eq!(&buf[..4], b"ping");
+
+        // With nobody reading, the buffers fill up until writes would block.
+        let chunk = [0; 64 * 1024];
+        while client.try_write(&chunk).unwrap().is_some() {}
+        assert_eq!(client.try_write(&chunk).unwrap(), None);
+
+        // Draining what was sent ends at EOF, which is `Some(0)`.
+        drop(client);
+        let mut buf = [0; 64 * 1024];
+        loop {
+            match server.try_read(&mut buf).unwrap() {
+                Some(0) => break,
+                Some(_) => {}
+                None => {
+                    poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn connect_from_binds_the_local_address() {
+        let listener = listener();
+        let addr = listener.local_addr().unwrap();
+
+        let local_ip: IpAddr = [127, 0, 0, 2].into();
+        let client = TcpStream::connect_from(SocketAddr::new(local_ip, 0), addr).unwrap();
+        let server = accept(&listener);
+        assert_eq!(client.local_addr().unwrap().ip(), local_ip);
+        assert_eq!(server.peer_addr().unwrap(), client.local_addr().unwrap());
+
+        let local = closed_addr();
+        let client = TcpStream::connect_from(local, addr).unwrap();
+        let server = accept(&listener);
+        assert_eq!(client.local_addr().unwrap(), local);
+        assert_eq!(server.peer_addr().unwrap(), local);
+    }
+
+    #[test]
+    fn notsent_lowat_round_trips() {
+        let (client, _server) = pair();
+        client.set_notsent_lowat(16 * 1024).unwrap();
+        assert_eq!(client.notsent_lowat().unwrap(), 16 * 1024);
+        client.set_notsent_lowat(0).unwrap();
+        assert_eq!(client.notsent_lowat().unwrap(), 0);
+    }
+
+    #[test]
+    fn send_with_flags_passes_the_flags_through() {
+        let (client, server) = pair();
+        // `MSG_MORE` holds the first part back until the rest is sent.
+        assert_eq!(client.send_with_flags(b"head", libc::MSG_MORE).unwrap(), 4);
+        assert_eq!(client.send_with_flags(b"tail", 0).unwrap(), 4);
+        let mut buf = [0; 8];
+        assert_eq!(server.read_timeout_once(&mut buf, TIMEOUT).unwrap(), 8);
+        assert_eq!(&buf, b"headtail");
+
+        // `MSG_OOB` sends urgent data, which the peer reads separately.
+        client.send_with_flags(b"!", libc::MSG_OOB).unwrap();
+        poll_fd(server.as_raw_fd(), libc::POLLPRI, Some(TIMEOUT)).unwrap();
+        let n = syscall!(recv(
+            server.as_raw_fd(),
+            buf.as_mut_ptr() as *mut c_void,
+            buf.len(),
+            libc::MSG_OOB
+        ))
+        .unwrap();
+        assert_eq!(&buf[..n as usize], b"!");
+    }
+
+    #[test]
+    fn writes_to_a_closed_connection_raise_no_sigpipe() {
+        // Rust ignores SIGPIPE, which hides it being raised. Blocked, it stays pending on
+        // the thread that raised it instead, so the writes run on a thread of their own.
+        thread::spawn(|| {
+            let mut set = unsafe { std::mem::zeroed::<libc::sigset_t>() };
+            unsafe {
+                libc::sigemptyset(&mut set);
+                libc::sigaddset(&mut set, libc::SIGPIPE);
+                libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+            }
+
+            let (mut client, server) = pair();
+            server.close_with(CloseMode::Reset).unwrap();
+            poll_fd(client.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+            for write in 0..3 {
+                let err = loop {
+                    let result = match write {
+                        0 => client.write(b"x"),
+                        1 => client.write_vectored(&[IoSlice::new(b"x")]),
+                        _ => client.send_with_flags(b"x", 0),
+                    };
+                    if let Err(err) = result {
+                        break err;
+                    }
+                };
+                assert!(matches!(
+                    err.kind(),
+                    io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset
+                ));
+            }
+
+            let mut pending = unsafe { std::mem::zeroed::<libc::sigset_t>() };
+            unsafe { libc::sigpending(&mut pending) };
+            assert_eq!(unsafe { libc::sigismember(&pending, libc::SIGPIPE) }, 0);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn incoming_cpu_is_a_cpu_once_packets_arrive() {
+        let (client, server) = pair();
+        client.send_with_flags(b"x", 0).unwrap();
+        server.read_timeout_once(&mut [0; 1], TIMEOUT).unwrap();
+        let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) } as i32;
+        let cpu = server.incoming_cpu().unwrap();
+        assert!((-1..cpus).contains(&cpu), "{cpu} of {cpus}");
+    }
+
+    /// Listeners sharing one port through `SO_REUSEPORT`, in the order they joined the group.
+    fn reuseport_group() -> [TcpListener; 2] {
+        let config = ListenerConfig {
+            reuseport: true,
+            ..ListenerConfig::default()
+        };
+        let first = TcpListener::bind_config("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+        let second = TcpListener::bind_config(first.local_addr().unwrap(), &config).unwrap();
+        [first, second]
+    }
+
+    #[test]
+    fn reuseport_cbpf_picks_the_listener() {
+        let group = reuseport_group();
+        let addr = group[0].local_addr().unwrap();
+        // `ret #1`: every connection goes to the second socket in the group.
+        const BPF_RET_K: u16 = 0x06;
+        group[0]
+            .attach_reuseport_cbpf(&[SockFilter::new(BPF_RET_K, 0, 0, 1)])
+            .unwrap();
+
+        let _clients: Vec<_> = (0..8).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for _ in 0..8 {
+            accept(&group[1]);
+        }
+        assert!(group[0].try_accept().unwrap().is_none());
+    }
+
+    #[test]
+    fn syn_retries_round_trip() {
+        let stream = TcpStream::connect(closed_addr()).unwrap();
+        stream.set_syn_retries(2).unwrap();
+        assert_eq!(stream.syn_retries().unwrap(), 2);
+        // The kernel caps it at 127.
+        let err = stream.set_syn_retries(128).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn into_std_keeps_the_socket_open_and_non_blocking() {
+        let mut listener = listener();
+        // Skipped once the socket belongs to std.
+        listener.set_shutdown_on_drop(true);
+        let fd = listener.as_raw_fd();
+        let listener = listener.into_std();
+        assert_eq!(listener.as_raw_fd(), fd);
+        assert_eq!(
+            listener.accept().err().unwrap().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        poll_fd(fd, POLLIN, Some(TIMEOUT)).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let fd = client.as_raw_fd();
+        let mut client = client.into_std();
+        assert_eq!(client.as_raw_fd(), fd);
+        let err = client.read(&mut [0; 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        (&server).write_all(b"x").unwrap();
+        poll_fd(fd, POLLIN, Some(TIMEOUT)).unwrap();
+        assert_eq!(client.read(&mut [0; 1]).unwrap(), 1);
+    }
+
+    #[test]
+    fn sniff_peeks_without_consuming() {
+        let (client, server) = pair();
+        client.send_with_flags(&[0x16, 0x03, 0x01], 0).unwrap();
+        poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        assert_eq!(server.sniff(1).unwrap(), [0x16]);
+        // Fewer bytes than asked for have arrived.
+        assert_eq!(server.sniff(5).unwrap(), [0x16, 0x03, 0x01]);
+
+        let mut buf = [0; 8];
+        assert_eq!(server.read_timeout_once(&mut buf, TIMEOUT).unwrap(), 3);
+        let err = server.sniff(1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    /// Polls `future` until the connect completes.
+    fn finish_connect(future: &mut ConnectFuture) -> ConnectState {
+        loop {
+            match future.poll().unwrap() {
+                ConnectState::Pending => {
+                    poll_fd(future.as_raw_fd(), POLLOUT, Some(TIMEOUT)).unwrap();
+                }
+                state => return state,
+            }
+        }
+    }
+
+    #[test]
+    fn connect_future_reports_the_outcome_once() {
+        let open = listener();
+        let addr = open.local_addr().unwrap();
+        let mut future = ConnectFuture::new(addr).unwrap();
+        assert!(future.as_raw_fd() >= 0);
+        let stream = match finish_connect(&mut future) {
+            ConnectState::Connected(stream) => stream,
+            _ => panic!("connect to a listener failed"),
+        };
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        assert_eq!(future.as_raw_fd(), -1);
+        assert!(future.poll().is_err());
+
+        let mut future = ConnectFuture::new(closed_addr()).unwrap();
+        match finish_connect(&mut future) {
+            ConnectState::Failed(err) => assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused),
+            _ => panic!("connect to a closed port succeeded"),
+        }
+        assert!(future.poll().is_err());
+
+        // A listener with a full accept queue drops the SYN, so the connect stays pending.
+        let stalled = listener();
+        syscall!(listen(stalled.as_raw_fd(), 0)).unwrap();
+        let stalled_addr = stalled.local_addr().unwrap();
+        let _queued = [
+            TcpStream::connect(stalled_addr).unwrap(),
+            TcpStream::connect(stalled_addr).unwrap(),
+        ];
+        let mut future = ConnectFuture::new(stalled_addr).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert!(matches!(future.poll().unwrap(), ConnectState::Pending));
+    }
+
+    #[test]
+    fn read_timeout_once_returns_what_arrives_or_times_out() {
+        let (client, server) = pair();
+        let mut buf = [0; 16];
+        let started = Instant::now();
+        let err = server
+            .read_timeout_once(&mut buf, Duration::from_millis(50))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        // A single read: it returns as soon as something arrives.
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            client.send_with_flags(b"abc", 0).unwrap();
+            client
+        });
+        assert_eq!(server.read_timeout_once(&mut buf, TIMEOUT).unwrap(), 3);
+        assert_eq!(&buf[..3], b"abc");
+        // The socket's own timeouts are left alone.
+        let std_timeout: libc::timeval =
+            getsockopt(server.as_raw_fd(), SOL_SOCKET, libc::SO_RCVTIMEO).unwrap();
+        assert_eq!((std_timeout.tv_sec, std_timeout.tv_usec), (0, 0));
+
+        drop(writer.join().unwrap());
+        assert_eq!(server.read_timeout_once(&mut buf, TIMEOUT).unwrap(), 0);
+    }
+
+    #[test]
+    fn cloexec_can_be_cleared_for_exec_handoff() {
+        let listener = listener();
+        assert!(listener.cloexec().unwrap());
+        listener.set_cloexec(false).unwrap();
+        assert!(!listener.cloexec().unwrap());
+        listener.set_cloexec(true).unwrap();
+        assert!(listener.cloexec().unwrap());
+
+        let config = ListenerConfig {
+            cloexec: false,
+            ..ListenerConfig::default()
+        };
+        let inheritable =
+            TcpListener::bind_config("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+        assert!(!inheritable.cloexec().unwrap());
+    }
+
+    #[test]
+    fn dontroute_round_trips() {
+        let (client, server) = pair();
+        assert!(!client.dontroute().unwrap());
+        client.set_dontroute(true).unwrap();
+        assert!(client.dontroute().unwrap());
+        // Loopback is directly connected, so traffic still flows.
+        client.send_with_flags(b"x", 0).unwrap();
+        assert_eq!(server.read_timeout_once(&mut [0; 1], TIMEOUT).unwrap(), 1);
+        client.set_dontroute(false).unwrap();
+        assert!(!client.dontroute().unwrap());
+    }
+
+    #[test]
+    fn drain_guard_waits_for_tracked_connections() {
+        let guard = DrainGuard::new();
+        assert!(guard.wait_drained(Duration::ZERO));
+
+        let first = guard.track();
+        let second = guard.clone().track();
+        assert_eq!(guard.active(), 2);
+        assert!(!guard.wait_drained(Duration::from_millis(20)));
+
+        drop(first);
+        assert_eq!(guard.active(), 1);
+        let closer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(second);
+        });
+        assert!(guard.wait_drained(TIMEOUT));
+        assert_eq!(guard.active(), 0);
+        closer.join().unwrap();
+    }
+
+    #[test]
+    fn finish_delivers_the_queue_before_closing() {
+        let (client, server) = pair();
+        let mut queue = WriteQueue::new();
+        let body: Vec<u8> = (0..4_000_000).map(|i| (i % 251) as u8).collect();
+        queue.push_owned(b"HTTP/1.1 200 OK\r\n\r\n".to_vec());
+        queue.push_owned(body.clone());
+        let finishing = thread::spawn(move || server.finish(&mut queue, TIMEOUT));
+
+        let mut received = Vec::new();
+        let mut buf = [0; 65536];
+        loop {
+            match client.read_timeout_once(&mut buf, TIMEOUT).unwrap() {
+                0 => break,
+                n => received.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert!(received[19..] == body[..]);
+        drop(client);
+        finishing.join().unwrap().unwrap();
+
+        let (_client, server) = pair();
+        let mut queue = WriteQueue::new();
+        queue.push_owned(b"bye".to_vec());
+        let err = server
+            .finish(&mut queue, Duration::from_millis(50))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn busy_poll_round_trips() {
+        // Raising it past `net.core.busy_read` needs CAP_NET_ADMIN.
+        let (client, _server) = pair();
+        match client.set_busy_poll(Duration::from_micros(50)) {
+            Ok(()) => assert_eq!(client.busy_poll().unwrap(), Duration::from_micros(50)),
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::PermissionDenied),
+        }
+        client.set_busy_poll(Duration::ZERO).unwrap();
+        assert_eq!(client.busy_poll().unwrap(), Duration::ZERO);
+
+        match client.set_prefer_busy_poll(true) {
+            Ok(()) => assert!(client.prefer_busy_poll().unwrap()),
+            Err(err) => assert!(matches!(
+                err.raw_os_error(),
+                Some(EPERM | libc::ENOPROTOOPT)
+            )),
+        }
+        client.set_prefer_busy_poll(false).unwrap();
+        assert!(!client.prefer_busy_poll().unwrap());
+    }
+
+    #[test]
+    fn repair_queue_and_sequence_round_trip() {
+        let stream = TcpStream::from_std(new_for_addr(closed_addr()).unwrap().into_std());
+        match stream.repair_mode(true) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => return,
+            Err(err) => panic!("{err}"),
+        }
+        assert!(stream.is_repair_mode().unwrap());
+        assert_eq!(stream.repair_queue().unwrap(), None);
+
+        stream.set_repair_queue(RepairQueue::Send).unwrap();
+        assert_eq!(stream.repair_queue().unwrap(), Some(RepairQueue::Send));
+        stream.set_queue_seq(1000).unwrap();
+        assert_eq!(stream.queue_seq().unwrap(), 1000);
+        stream.set_repair_queue(RepairQueue::Recv).unwrap();
+        assert_eq!(stream.repair_queue().unwrap(), Some(RepairQueue::Recv));
+        stream.set_queue_seq(2000).unwrap();
+        assert_eq!(stream.queue_seq().unwrap(), 2000);
+
+        stream.repair_mode(false).unwrap();
+        assert!(!stream.is_repair_mode().unwrap());
+    }
+
+    #[test]
+    fn recv_lowat_holds_readiness_until_enough_arrives() {
+        let (client, server) = pair();
+        assert_eq!(server.recv_lowat().unwrap(), 1);
+        server.set_recv_lowat(8).unwrap();
+        assert_eq!(server.recv_lowat().unwrap(), 8);
+
+        client.send_with_flags(b"1234", 0).unwrap();
+        assert_eq!(
+            poll_fd(server.as_raw_fd(), POLLIN, Some(Duration::from_millis(50))).unwrap(),
+            0
+        );
+        client.send_with_flags(b"5678", 0).unwrap();
+        assert_ne!(
+            poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap(),
+            0
+        );
+        let mut buf = [0; 16];
+        assert_eq!(server.read_timeout_once(&mut buf, TIMEOUT).unwrap(), 8);
+    }
+
+    #[test]
+    fn accept_reports_the_peer_address() {
+        for bind in ["127.0.0.1:0", "[::1]:0"] {
+            let listener = TcpListener::bind(bind.parse().unwrap()).unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            poll_fd(listener.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+            let (server, addr) = listener.accept().unwrap();
+            assert_eq!(addr, client.local_addr().unwrap());
+            assert_eq!(addr, server.peer_addr().unwrap());
+        }
+    }
+
+    #[test]
+    fn accept_closes_connections_without_an_inet_address() {
+        // Wrapping a Unix listener gives accepted addresses neither parsing nor
+        // `peer_addr` can turn into a `SocketAddr`.
+        let path = std::env::temp_dir().join(format!("rio-accept-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let listener = unsafe { TcpListener::from_raw_fd(unix.into_raw_fd()) };
+        let mut client = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let err = listener.accept().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(listener.accept_stats().other, 1);
+        // The accepted fd was closed rather than leaked.
+        assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn connect_nonblocking_returns_before_the_handshake() {
+        let open = listener();
+        let addr = open.local_addr().unwrap();
+        let (client, connected) = TcpStream::connect_nonblocking(addr).unwrap();
+        let flags = syscall!(fcntl(client.as_raw_fd(), libc::F_GETFL)).unwrap();
+        assert_ne!(flags & libc::O_NONBLOCK, 0);
+        if !connected {
+            poll_fd(client.as_raw_fd(), POLLOUT, Some(TIMEOUT)).unwrap();
+        }
+        assert!(client.take_error().unwrap().is_none());
+        assert_eq!(client.peer_addr().unwrap(), addr);
+        let server = accept(&open);
+        assert_eq!(server.peer_addr().unwrap(), client.local_addr().unwrap());
+
+        // A refused connect is only reported once the attempt fails.
+        let (client, connected) = TcpStream::connect_nonblocking(closed_addr()).unwrap();
+        assert!(!connected);
+        poll_fd(client.as_raw_fd(), POLLOUT, Some(TIMEOUT)).unwrap();
+        assert_eq!(
+            client.take_error().unwrap().unwrap().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+    }
+
+    #[test]
+    fn backlog_len_counts_connections_waiting_to_be_accepted() {
+        let config = ListenerConfig {
+            backlog: 16,
+            ..ListenerConfig::default()
+        };
+        let open = TcpListener::bind_config("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+        assert_eq!(open.backlog_capacity().unwrap(), 16);
+        assert_eq!(open.backlog_len().unwrap(), 0);
+
+        let addr = open.local_addr().unwrap();
+        let clients: Vec<_> = (0..3).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for client in &clients {
+            poll_fd(client.as_raw_fd(), POLLOUT, Some(TIMEOUT)).unwrap();
+        }
+        // The client side completes before the server queues the connection.
+        let deadline = Instant::now() + TIMEOUT;
+        while open.backlog_len().unwrap() < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(open.backlog_len().unwrap(), 3);
+
+        accept(&open);
+        assert_eq!(open.backlog_len().unwrap(), 2);
+        assert_eq!(open.backlog_capacity().unwrap(), 16);
+    }
+
+    #[test]
+    fn synflood_protection_defers_accept_until_data_arrives() {
+        let open = listener();
+        let advice = open.set_synflood_protection(true).unwrap();
+        let fd = open.as_raw_fd();
+        assert!(open.defer_accept().unwrap() >= Duration::from_secs(5));
+        assert_eq!(getsockopt::<c_int>(fd, IPPROTO_TCP, TCP_SYNCNT).unwrap(), 2);
+        for SysctlAdvice { name, current, .. } in &advice {
+            assert_eq!(read_sysctl(name).as_ref(), Some(current));
+        }
+
+        let mut client = TcpStream::connect(open.local_addr().unwrap()).unwrap();
+        poll_fd(client.as_raw_fd(), POLLOUT, Some(TIMEOUT)).unwrap();
+        assert!(open.try_accept().unwrap().is_none());
+        client.write_all(b"GET").unwrap();
+        let mut server = accept(&open);
+        let mut buf = [0; 3];
+        server.read_exact(&mut buf).unwrap();
+
+        assert!(open.set_synflood_protection(false).unwrap().is_empty());
+        assert_eq!(open.defer_accept().unwrap(), Duration::ZERO);
+        let retries = read_sysctl("net.ipv4.tcp_synack_retries").map_or(5, |v| v.parse().unwrap());
+        assert_eq!(
+            getsockopt::<c_int>(fd, IPPROTO_TCP, TCP_SYNCNT).unwrap(),
+            retries
+        );
+    }
+
+    #[test]
+    fn info_reflects_the_connection() {
+        let (mut client, mut server) = pair();
+        client.set_nodelay(true).unwrap();
+        client.set_ttl(42).unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+
+        let info = client.info();
+        assert_eq!(info.local_addr, Some(client.local_addr().unwrap()));
+        assert_eq!(info.peer_addr, Some(server.local_addr().unwrap()));
+        assert_eq!(info.nodelay, Some(true));
+        assert_eq!(info.ttl, Some(42));
+        assert_eq!(info.state, Some(TcpState::Established));
+        assert!(info.mss.is_some_and(|mss| mss > 0));
+        assert!(info.rtt.is_some() && info.rtt_var.is_some());
+        assert!(info.snd_cwnd.is_some_and(|cwnd| cwnd > 0));
+        assert!(info.unacked.is_some());
+        assert_eq!(info.total_retrans, Some(0));
+        assert_eq!(server.info().nodelay, Some(false));
+    }
+
+    #[test]
+    fn accept_checked_returns_a_pending_error_first() {
+        // A socket whose connect was refused keeps the error in SO_ERROR through being
+        // disconnected and turned into a listener.
+        let (stream, _) = TcpStream::connect_nonblocking(closed_addr()).unwrap();
+        poll_fd(stream.as_raw_fd(), POLLOUT, Some(TIMEOUT)).unwrap();
+        let mut unspec: libc::sockaddr = unsafe { std::mem::zeroed() };
+        unspec.sa_family = libc::AF_UNSPEC as libc::sa_family_t;
+        syscall!(connect(
+            stream.as_raw_fd(),
+            &unspec,
+            size_of::<libc::sockaddr>() as socklen_t
+        ))
+        .unwrap();
+        syscall!(listen(stream.as_raw_fd(), 8)).unwrap();
+        let open = unsafe { TcpListener::from_raw_fd(stream.into_raw_fd()) };
+
+        let client = TcpStream::connect(open.local_addr().unwrap()).unwrap();
+        poll_fd(open.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        assert_eq!(
+            open.accept_checked().err().unwrap().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+        // Reporting the error cleared it, and the connection is still queued.
+        let (_, addr) = open.accept_checked().unwrap();
+        assert_eq!(addr, client.local_addr().unwrap());
+        assert_eq!(
+            open.accept_checked().err().unwrap().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn tfo_cache_remembers_failures_until_they_expire() {
+        let cache = TfoCache::new(Duration::from_millis(50), 8);
+        let (a, b): (SocketAddr, SocketAddr) = (
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+        );
+        assert!(cache.should_try(a) && cache.is_empty());
+
+        cache.record_failure(a);
+        assert!(!cache.should_try(a));
+        assert!(cache.should_try(b));
+        assert_eq!(cache.len(), 1);
+
+        cache.record_failure(b);
+        cache.record_success(b);
+        assert!(cache.should_try(b));
+        assert_eq!(cache.len(), 1);
+
+        thread::sleep(Duration::from_millis(60));
+        assert!(cache.should_try(a));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn tfo_cache_evicts_the_oldest_failure_when_full() {
+        let cache = TfoCache::new(TIMEOUT, 2);
+        let addrs: Vec<SocketAddr> = (1..=3)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+            .collect();
+        for &addr in &addrs {
+            cache.record_failure(addr);
+            // Keeps the failure times distinct.
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(cache.len(), 2);
+        assert!(cache.should_try(addrs[0]));
+        assert!(!cache.should_try(addrs[1]) && !cache.should_try(addrs[2]));
+
+        // Recording a failure again refreshes it without evicting anything.
+        cache.record_failure(addrs[1]);
+        assert_eq!(cache.len(), 2);
+        cache.record_failure(addrs[0]);
+        assert!(!cache.should_try(addrs[1]));
+        assert!(cache.should_try(addrs[2]));
+
+        let disabled = TfoCache::new(TIMEOUT, 0);
+        disabled.record_failure(addrs[0]);
+        assert!(disabled.should_try(addrs[0]) && disabled.is_empty());
+    }
+
+    #[test]
+    fn wait_sent_waits_for_the_peer_to_acknowledge() {
+        let (mut client, mut server) = pair();
+        client.write_all(b"ping").unwrap();
+        client.wait_sent(TIMEOUT).unwrap();
+        assert_eq!(client.send_queue_len().unwrap(), 0);
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+
+        // Fill the peer's receive buffer so that the rest can't be sent.
+        let chunk = [0; 64 * 1024];
+        let mut written = 0;
+        loop {
+            match client.write(&chunk) {
+                Ok(n) => written += n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => panic!("write failed: {err}"),
+            }
+        }
+        assert!(client.send_queue_len().unwrap() > 0);
+        assert_eq!(
+            client
+                .wait_sent(Duration::from_millis(20))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::TimedOut
+        );
+
+        let reader = thread::spawn(move || {
+            let mut buf = vec![0; 64 * 1024];
+            let mut read = 0;
+            while read < written {
+                match server.read(&mut buf) {
+                    Ok(n) => read += n,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+                    }
+                    Err(err) => panic!("read failed: {err}"),
+                }
+            }
+        });
+        client.wait_sent(TIMEOUT).unwrap();
+        assert_eq!(client.send_queue_len().unwrap(), 0);
+        reader.join().unwrap();
+    }
+
+    /// Loads an eBPF socket filter that returns `value`, or `None` without the privileges
+    /// to load one.
+    fn load_ebpf_returning(value: i32) -> Option<OwnedFd> {
+        // `struct bpf_insn`, with the registers packed into one byte.
+        #[repr(C)]
+        struct Insn {
+            code: u8,
+            regs: u8,
+            off: i16,
+            imm: i32,
+        }
+        // The start of `union bpf_attr` as `BPF_PROG_LOAD` reads it.
+        #[repr(C)]
+        struct ProgLoad {
+            prog_type: u32,
+            insn_cnt: u32,
+            insns: u64,
+            license: u64,
+            rest: [u8; 96],
+        }
+        const BPF_PROG_LOAD: c_int = 5;
+        const BPF_PROG_TYPE_SOCKET_FILTER: u32 = 1;
+        // `r0 = value; exit`.
+        let insns = [
+            Insn {
+                code: 0xb7,
+                regs: 0,
+                off: 0,
+                imm: value,
+            },
+            Insn {
+                code: 0x95,
+                regs: 0,
+                off: 0,
+                imm: 0,
+            },
+        ];
+        let attr = ProgLoad {
+            prog_type: BPF_PROG_TYPE_SOCKET_FILTER,
+            insn_cnt: insns.len() as u32,
+            insns: insns.as_ptr() as u64,
+            license: c"GPL".as_ptr() as u64,
+            rest: [0; 96],
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_bpf,
+                BPF_PROG_LOAD,
+                &attr as *const ProgLoad,
+                size_of::<ProgLoad>(),
+            )
+        };
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "{err}");
+            return None;
+        }
+        Some(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+    }
+
+    #[test]
+    fn reuseport_ebpf_picks_the_listener_until_detached() {
+        let group = reuseport_group();
+        let addr = group[0].local_addr().unwrap();
+        let Some(prog) = load_ebpf_returning(1) else {
+            return;
+        };
+        group[0].attach_reuseport_ebpf(prog.as_raw_fd()).unwrap();
+
+        let mut clients: Vec<_> = (0..8).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for _ in 0..8 {
+            accept(&group[1]);
+        }
+        assert!(group[0].try_accept().unwrap().is_none());
+
+        // Back to hashing, which spreads connections over both listeners.
+        group[0].detach_reuseport_bpf().unwrap();
+        clients.extend((0..32).map(|_| TcpStream::connect(addr).unwrap()));
+        let mut accepted = [0; 2];
+        let deadline = Instant::now() + TIMEOUT;
+        while accepted.iter().sum::<usize>() < 32 {
+            assert!(Instant::now() < deadline, "{accepted:?}");
+            for (listener, count) in group.iter().zip(&mut accepted) {
+                if listener.try_accept().unwrap().is_some() {
+                    *count += 1;
+                }
+            }
+        }
+        assert!(accepted.iter().all(|&count| count > 0), "{accepted:?}");
+        assert!(group[0].detach_reuseport_bpf().is_err());
+    }
+
+    #[test]
+    fn reuseport_ebpf_rejects_fds_that_are_not_programs() {
+        let group = reuseport_group();
+        for fd in [group[1].as_raw_fd(), -1] {
+            let err = group[0].attach_reuseport_ebpf(fd).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn accept_backoff_doubles_up_to_the_max() {
+        let backoff = AcceptBackoff {
+            initial: Duration::from_millis(5),
+            max: Duration::from_millis(100),
+        };
+        let delays: Vec<_> = (0..7).map(|failures| backoff.delay(failures)).collect();
+        assert_eq!(
+            delays,
+            [0, 5, 10, 20, 40, 80, 100].map(Duration::from_millis)
+        );
+        // No overflow however long the failures go on.
+        assert_eq!(backoff.delay(u32::MAX), backoff.max);
+
+        assert!(AcceptBackoff::applies_to(&io::Error::from_raw_os_error(
+            EMFILE
+        )));
+        assert!(AcceptBackoff::applies_to(&io::Error::from_raw_os_error(
+            ENOBUFS
+        )));
+        assert!(!AcceptBackoff::applies_to(&io::Error::from_raw_os_error(
+            libc::ECONNABORTED
+        )));
+    }
+
+    #[test]
+    fn conn_limiter_counts_per_client_until_permits_drop() {
+        let limiter = ConnLimiter::new(2);
+        let a: IpAddr = [10, 0, 0, 1].into();
+        let b: IpAddr = [10, 0, 0, 2].into();
+        let first = limiter.try_acquire(a).unwrap();
+        let second = limiter.try_acquire(a).unwrap();
+        assert!(limiter.try_acquire(a).is_none());
+        assert_eq!(limiter.active(a), 2);
+        // Other clients have their own count, shared by clones.
+        let _other = limiter.clone().try_acquire(b).unwrap();
+        assert_eq!(limiter.active(b), 1);
+
+        drop(first);
+        assert_eq!(limiter.active(a), 1);
+        let _third = limiter.try_acquire(a).unwrap();
+        drop(second);
+        assert_eq!(limiter.active(a), 1);
+
+        // IPv6 clients count by /64, and IPv4-mapped ones as IPv4.
+        let host: IpAddr = "2001:db8::1".parse().unwrap();
+        let neighbour: IpAddr = "2001:db8::ffff:2".parse().unwrap();
+        let _v6 = limiter.try_acquire(host).unwrap();
+        let _v6 = limiter.try_acquire(neighbour).unwrap();
+        assert!(limiter
+            .try_acquire("2001:db8::3".parse().unwrap())
+            .is_none());
+        assert!(limiter
+            .try_acquire("2001:db8:0:1::1".parse().unwrap())
+            .is_some());
+        let mapped: IpAddr = "::ffff:10.0.0.1".parse().unwrap();
+        assert_eq!(limiter.active(mapped), 1);
+
+        let closed = ConnLimiter::new(0);
+        assert!(closed.try_acquire(a).is_none());
+        assert_eq!(closed.active(a), 0);
+    }
+
+    #[test]
+    fn suggested_read_size_is_a_bounded_power_of_two() {
+        let check = |stream: &TcpStream| {
+            let size = stream.suggested_read_size().unwrap();
+            assert!(size.is_power_of_two(), "{size}");
+            assert!((MIN_READ_SIZE..=MAX_READ_SIZE).contains(&size), "{size}");
+        };
+        let (mut client, mut server) = pair();
+        check(&client);
+        check(&server);
+
+        // Once data has flowed the receiver measures its own RTT and estimate.
+        let chunk = [0; 64 * 1024];
+        let mut buf = [0; 64 * 1024];
+        let mut received = 0;
+        while received < 8 * 1024 * 1024 {
+            client.try_write(&chunk).unwrap();
+            poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+            while let Some(n) = server.try_read(&mut buf).unwrap() {
+                received += n;
+            }
+        }
+        check(&client);
+        check(&server);
+    }
+
+    #[test]
+    fn connect_from_range_takes_the_first_free_port() {
+        let open = listener();
+        let addr = open.local_addr().unwrap();
+        // An address and ports of its own, below the ephemeral range.
+        let local_ip: IpAddr = [127, 0, 0, 5].into();
+        let ports = 29000..=29002;
+        let _taken = TcpListener::bind(SocketAddr::new(local_ip, 29000)).unwrap();
+
+        let first = TcpStream::connect_from_range(local_ip, ports.clone(), addr).unwrap();
+        assert_eq!(
+            first.local_addr().unwrap(),
+            SocketAddr::new(local_ip, 29001)
+        );
+        let second = TcpStream::connect_from_range(local_ip, ports.clone(), addr).unwrap();
+        assert_eq!(
+            second.local_addr().unwrap(),
+            SocketAddr::new(local_ip, 29002)
+        );
+        assert_eq!(
+            accept(&open).peer_addr().unwrap(),
+            first.local_addr().unwrap()
+        );
+        assert_eq!(
+            accept(&open).peer_addr().unwrap(),
+            second.local_addr().unwrap()
+        );
+
+        // Both ports can be bound again, but already have a connection to `addr`.
+        let err = TcpStream::connect_from_range(local_ip, ports, addr)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        // To another destination the same ports are free.
+        let other = listener();
+        let third =
+            TcpStream::connect_from_range(local_ip, 29001..=29002, other.local_addr().unwrap())
+                .unwrap();
+        assert_eq!(
+            third.local_addr().unwrap(),
+            SocketAddr::new(local_ip, 29001)
+        );
+    }
+
+    #[test]
+    fn path_mtu_and_discovery_mode_for_tcp() {
+        let lo_mtu: u32 = std::fs::read_to_string("/sys/class/net/lo/mtu")
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        let (client, server) = pair();
+        // IPv4 caps the MTU at the largest packet it can describe.
+        assert_eq!(client.path_mtu().unwrap(), lo_mtu.min(65535));
+        assert_eq!(server.path_mtu().unwrap(), lo_mtu.min(65535));
+
+        assert_eq!(client.mtu_discover().unwrap(), MtuDiscover::Want);
+        for mode in [
+            MtuDiscover::Dont,
+            MtuDiscover::Do,
+            MtuDiscover::Probe,
+            MtuDiscover::Want,
+        ] {
+            client.set_mtu_discover(mode).unwrap();
+            assert_eq!(client.mtu_discover().unwrap(), mode);
+        }
+    }
+
+    #[test]
+    fn accept_pacer_holds_a_storm_to_the_cap() {
+        let open = listener();
+        let addr = open.local_addr().unwrap();
+        let clients: Vec<_> = (0..40).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        let mut pacer = AcceptPacer::new(50, 5);
+        let start = Instant::now();
+        let mut accepted = Vec::new();
+        while accepted.len() < clients.len() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            match pacer.accept(&open).unwrap() {
+                Paced::Accepted(..) => accepted.push(start.elapsed()),
+                Paced::Empty => thread::sleep(Duration::from_millis(1)),
+                Paced::Wait(delay) => thread::sleep(delay),
+            }
+        }
+        // The burst goes at once, then one every 20ms; nothing is dropped along the way.
+        assert!(accepted[4] < Duration::from_millis(100));
+        for (n, at) in accepted.iter().enumerate().skip(5) {
+            let earliest = Duration::from_millis(20 * (n as u64 - 4));
+            assert!(*at >= earliest - Duration::from_millis(2), "{n} at {at:?}");
+        }
+        assert_eq!(open.backlog_len().unwrap(), 0);
     }
 }
@@ -0,0 +1,310 @@
+use std::{
+    io::{self, Read, Write},
+    ops::{Deref, DerefMut},
+    os::fd::{AsRawFd, RawFd},
+};
+
+use rustls::{ConnectionCommon, SideData};
+
+use crate::tcp::TcpStream;
+
+/// A TLS session over a non-blocking [`TcpStream`], for either side of the connection
+/// (`rustls::ServerConnection` or `rustls::ClientConnection`).
+///
+/// The handshake is driven by the first reads and writes, or explicitly with
+/// [`complete_handshake`](TlsStream::complete_handshake). Any operation may fail with
+/// `WouldBlock`; wait for readability if [`wants_read`](TlsStream::wants_read) and for
+/// writability if [`wants_write`](TlsStream::wants_write), then retry.
+pub struct TlsStream<C> {
+    sock: TcpStream,
+    conn: C,
+}
+
+impl<C, S> TlsStream<C>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    pub fn new(sock: TcpStream, conn: C) -> TlsStream<C> {
+        TlsStream { sock, conn }
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    pub fn get_mut(&mut self) -> &mut TcpStream {
+        &mut self.sock
+    }
+
+    pub fn connection(&self) -> &C {
+        &self.conn
+    }
+
+    pub fn connection_mut(&mut self) -> &mut C {
+        &mut self.conn
+    }
+
+    pub fn into_parts(self) -> (TcpStream, C) {
+        (self.sock, self.conn)
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        self.conn.is_handshaking()
+    }
+
+    pub fn wants_read(&self) -> bool {
+        self.conn.wants_read()
+    }
+
+    pub fn wants_write(&self) -> bool {
+        self.conn.wants_write()
+    }
+
+    /// Exchanges handshake messages until the handshake is done.
+    pub fn complete_handshake(&mut self) -> io::Result<()> {
+        while self.conn.is_handshaking() {
+            self.write_tls()?;
+            if self.conn.is_handshaking() && self.conn.wants_read() && self.read_tls()? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed during the TLS handshake",
+                ));
+            }
+        }
+        // The last flight, e.g. a TLS 1.3 client's Finished, may still be queued.
+        self.write_tls()
+    }
+
+    /// Queues a close_notify alert and tries to send it. The socket itself stays open.
+    pub fn send_close_notify(&mut self) -> io::Result<()> {
+        self.conn.send_close_notify();
+        self.write_tls()
+    }
+
+    /// Sends queued TLS records until there are none left.
+    fn write_tls(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.sock)?;
+        }
+        Ok(())
+    }
+
+    /// Like `write_tls`, but leaves records the socket can't take yet queued.
+    fn try_write_tls(&mut self) -> io::Result<()> {
+        match self.write_tls() {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Reads TLS records from the socket and processes them, returning 0 at EOF.
+    fn read_tls(&mut self) -> io::Result<usize> {
+        let n = self.conn.read_tls(&mut self.sock)?;
+        if let Err(err) = self.conn.process_new_packets() {
+            // Best effort to tell the peer why the connection is being abandoned.
+            let _ = self.write_tls();
+            return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+        }
+        Ok(n)
+    }
+}
+
+/// Reading returns 0 once the peer has sent close_notify, and fails with `UnexpectedEof`
+/// if the connection closes without one.
+impl<C, S> Read for TlsStream<C>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            // Handshake messages or alerts may need to go out before the peer sends more.
+            self.try_write_tls()?;
+            self.read_tls()?;
+        }
+    }
+}
+
+/// Writes are encrypted into rustls' buffer and sent as far as the socket allows;
+/// `flush` finishes the handshake if needed and sends everything buffered.
+impl<C, S> Write for TlsStream<C>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut n = self.conn.writer().write(buf)?;
+        if n == 0 && !buf.is_empty() {
+            // rustls' buffer is full; make room before accepting more. Data written during
+            // the handshake can only be sent once the handshake completes.
+            self.complete_handshake()?;
+            n = self.conn.writer().write(buf)?;
+        }
+        self.try_write_tls()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.complete_handshake()?;
+        self.conn.writer().flush()?;
+        self.write_tls()
+    }
+}
+
+impl<C> AsRawFd for TlsStream<C> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use libc::POLLIN;
+    use rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+        ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection,
+    };
+
+    use super::*;
+    use crate::{net::poll_fd, tcp::TcpListener};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    type Pair = (TlsStream<ClientConnection>, TlsStream<ServerConnection>);
+
+    /// A client and server over loopback, with a self-signed certificate for "localhost"
+    /// that the client trusts. Nothing has been exchanged yet.
+    fn tls_pair() -> Pair {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert_der = CertificateDer::from(cert.cert.der().to_vec());
+        let key_der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()));
+
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key_der)
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert_der).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        poll_fd(listener.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let name = ServerName::try_from("localhost").unwrap();
+        let client_conn = ClientConnection::new(Arc::new(client_config), name).unwrap();
+        let server_conn = ServerConnection::new(Arc::new(server_config)).unwrap();
+        (
+            TlsStream::new(client, client_conn),
+            TlsStream::new(server, server_conn),
+        )
+    }
+
+    /// Retries `op` on `WouldBlock` once `stream` is readable.
+    fn retry<T, R>(stream: &mut T, mut op: impl FnMut(&mut T) -> io::Result<R>) -> R
+    where
+        T: AsRawFd,
+    {
+        loop {
+            match op(stream) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    poll_fd(stream.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+                }
+                result => return result.unwrap(),
+            }
+        }
+    }
+
+    #[test]
+    fn handshake_resumes_after_would_block() {
+        let (mut client, mut server) = tls_pair();
+
+        // Each side sends its flight, then has to wait for the other's.
+        let err = client.complete_handshake().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(client.is_handshaking() && client.wants_read());
+        poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        let err = server.complete_handshake().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(server.is_handshaking());
+
+        retry(&mut client, TlsStream::complete_handshake);
+        assert!(!client.is_handshaking());
+        retry(&mut server, TlsStream::complete_handshake);
+        assert!(!server.is_handshaking());
+
+        client.write_all(b"ping").unwrap();
+        client.flush().unwrap();
+        let mut buf = [0; 4];
+        retry(&mut server, |server| server.read_exact(&mut buf));
+        assert_eq!(&buf, b"ping");
+
+        server.write_all(b"pong").unwrap();
+        server.flush().unwrap();
+        retry(&mut client, |client| client.read_exact(&mut buf));
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[test]
+    fn data_written_during_the_handshake_is_delivered() {
+        let (mut client, mut server) = tls_pair();
+        // Buffered until the handshake completes.
+        assert_eq!(client.write(b"early").unwrap(), 5);
+
+        let mut buf = [0; 5];
+        std::thread::scope(|scope| {
+            scope.spawn(|| retry(&mut client, TlsStream::flush));
+            retry(&mut server, |server| server.read_exact(&mut buf));
+        });
+        assert_eq!(&buf, b"early");
+    }
+
+    #[test]
+    fn close_notify_ends_the_stream_cleanly() {
+        let (mut client, mut server) = tls_pair();
+        std::thread::scope(|scope| {
+            scope.spawn(|| retry(&mut client, TlsStream::complete_handshake));
+            retry(&mut server, TlsStream::complete_handshake);
+        });
+
+        client.write_all(b"bye").unwrap();
+        client.send_close_notify().unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(retry(&mut server, |server| server.read(&mut buf)), 3);
+        assert_eq!(retry(&mut server, |server| server.read(&mut buf)), 0);
+        // The socket is still open for the peer to close_notify back.
+        server.send_close_notify().unwrap();
+        assert_eq!(retry(&mut client, |client| client.read(&mut buf)), 0);
+
+        // Without close_notify, the end of the connection is reported as truncation.
+        let (mut client, mut server) = tls_pair();
+        std::thread::scope(|scope| {
+            scope.spawn(|| retry(&mut client, TlsStream::complete_handshake));
+            retry(&mut server, TlsStream::complete_handshake);
+        });
+        // Dropping the client would reset the connection over its unread session tickets.
+        client
+            .get_ref()
+            .shutdown(std::net::Shutdown::Write)
+            .unwrap();
+        let err = loop {
+            match server.read(&mut buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+                }
+                result => break result.unwrap_err(),
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,712 @@
+use std::{
+    io::{self, IoSlice, IoSliceMut},
+    mem::{size_of, zeroed, MaybeUninit},
+    net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
+    ptr,
+};
+
+use libc::{
+    c_int, c_uint, c_void, in6_addr, in6_pktinfo, in_addr, in_pktinfo, iovec, mmsghdr, msghdr,
+    sa_family_t, sockaddr, sockaddr_storage, socklen_t, AF_INET, AF_INET6, AF_UNSPEC, IPPROTO_IP,
+    IPPROTO_IPV6, IPV6_PKTINFO, IPV6_RECVPKTINFO, IP_PKTINFO, MSG_PEEK, MSG_TRUNC, MSG_WAITFORONE,
+    POLLIN, SOCK_DGRAM, SOL_SOCKET, SO_RXQ_OVFL,
+};
+
+use crate::syscall;
+
+use super::net::{
+    cmsg::{CmsgBuilder, CmsgIter},
+    create_new_socket, getsockopt, mtu_discover, path_mtu, poll_fd, set_mtu_discover, setsockopt,
+    socket_addr, to_socket_addr, MtuDiscover, Socket,
+};
+
+fn new_for_addr(addr: SocketAddr) -> io::Result<Socket> {
+    let domain = match addr {
+        SocketAddr::V4(_) => AF_INET,
+        SocketAddr::V6(_) => AF_INET6,
+    };
+    create_new_socket(domain, SOCK_DGRAM)
+}
+
+pub struct UdpSocket {
+    inner: net::UdpSocket,
+}
+
+impl UdpSocket {
+    pub fn bind(addr: SocketAddr) -> io::Result<UdpSocket> {
+        let socket = new_for_addr(addr)?;
+        let udp = UdpSocket::from_std(socket.into_std());
+
+        let (raw_addr, raw_addr_length) = socket_addr(&addr);
+        syscall!(bind(udp.as_raw_fd(), raw_addr.as_ptr(), raw_addr_length))?;
+
+        Ok(udp)
+    }
+
+    pub fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.inner.connect(addr)
+    }
+
+    /// Dissolves the association made by `connect`, so the socket receives from any source
+    /// again and `send` fails with `EDESTADDRREQ` (not `ENOTCONN`, as for TCP). A local
+    /// address picked by `connect`, and a port the kernel picked, whether at `connect` or
+    /// when binding to port 0, are released as well; bind a specific port to keep it.
+    pub fn disconnect(&self) -> io::Result<()> {
+        let mut addr: sockaddr = unsafe { zeroed() };
+        addr.sa_family = AF_UNSPEC as sa_family_t;
+        syscall!(connect(
+            self.as_raw_fd(),
+            &addr,
+            size_of::<sockaddr>() as socklen_t,
+        ))
+        .map(|_| ())
+    }
+
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.send(buf)
+    }
+
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.inner.send_to(buf, addr)
+    }
+
+    /// Sends `bufs` as a single datagram to `addr`, passing `flags` (e.g. `MSG_CONFIRM`)
+    /// through to `sendmsg`.
+    pub fn send_to_vectored(
+        &self,
+        bufs: &[IoSlice<'_>],
+        addr: SocketAddr,
+        flags: c_int,
+    ) -> io::Result<usize> {
+        let (raw_addr, raw_addr_length) = socket_addr(&addr);
+        let mut msg: msghdr = unsafe { zeroed() };
+        msg.msg_name = raw_addr.as_ptr() as *mut c_void;
+        msg.msg_namelen = raw_addr_length;
+        // `IoSlice` is guaranteed to be ABI compatible with `iovec`.
+        msg.msg_iov = bufs.as_ptr() as *mut iovec;
+        msg.msg_iovlen = bufs.len() as _;
+        syscall!(sendmsg(self.as_raw_fd(), &msg, flags)).map(|n| n as usize)
+    }
+
+    /// Enables receiving the destination address of each datagram, see
+    /// [`recv_from_with_dst`](UdpSocket::recv_from_with_dst).
+    pub fn set_pktinfo(&self, pktinfo: bool) -> io::Result<()> {
+        let (level, name) = self.pktinfo_option()?;
+        setsockopt(self.as_raw_fd(), level, name, pktinfo as c_int)
+    }
+
+    pub fn pktinfo(&self) -> io::Result<bool> {
+        let (level, name) = self.pktinfo_option()?;
+        getsockopt::<c_int>(self.as_raw_fd(), level, name).map(|val| val != 0)
+    }
+
+    fn pktinfo_option(&self) -> io::Result<(c_int, c_int)> {
+        match self.local_addr()? {
+            SocketAddr::V4(_) => Ok((IPPROTO_IP, IP_PKTINFO)),
+            SocketAddr::V6(_) => Ok((IPPROTO_IPV6, IPV6_RECVPKTINFO)),
+        }
+    }
+
+    /// Receives a datagram along with the local address it was sent to, which tells a
+    /// socket bound to a wildcard address which of the host's addresses to reply from.
+    /// Requires [`set_pktinfo`](UdpSocket::set_pktinfo).
+    pub fn recv_from_with_dst(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, IpAddr)> {
+        let mut addr = MaybeUninit::<sockaddr_storage>::zeroed();
+        let mut control = [0u8; 128];
+        let mut iov = IoSliceMut::new(buf);
+
+        let mut msg: msghdr = unsafe { zeroed() };
+        msg.msg_name = addr.as_mut_ptr() as *mut c_void;
+        msg.msg_namelen = size_of::<sockaddr_storage>() as socklen_t;
+        msg.msg_iov = &mut iov as *mut IoSliceMut as *mut iovec;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = control.len() as _;
+
+        let n = syscall!(recvmsg(self.as_raw_fd(), &mut msg, 0))? as usize;
+        let addr = unsafe { to_socket_addr(addr.as_ptr()) }?;
+
+        let mut dst = None;
+        for cmsg in CmsgIter::new(&control[..msg.msg_controllen as usize]) {
+            match (cmsg.level, cmsg.kind) {
+                (IPPROTO_IP, IP_PKTINFO) => {
+                    if let Some(info) = unsafe { cmsg.read::<in_pktinfo>() } {
+                        let ip = Ipv4Addr::from(info.ipi_addr.s_addr.to_ne_bytes());
+                        dst = Some(IpAddr::V4(ip));
+                    }
+                }
+                (IPPROTO_IPV6, IPV6_PKTINFO) => {
+                    if let Some(info) = unsafe { cmsg.read::<in6_pktinfo>() } {
+                        dst = Some(IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        match dst {
+            Some(dst) => Ok((n, addr, dst)),
+            None => Err(io::Error::other(
+                "no packet info received, enable it with set_pktinfo",
+            )),
+        }
+    }
+
+    /// Sends a datagram to `addr` with `src` as its source address, typically the
+    /// destination reported by [`recv_from_with_dst`](UdpSocket::recv_from_with_dst).
+    pub fn send_to_from(&self, buf: &[u8], addr: SocketAddr, src: IpAddr) -> io::Result<usize> {
+        let (raw_addr, raw_addr_length) = socket_addr(&addr);
+        let iov = IoSlice::new(buf);
+
+        let mut control = CmsgBuilder::new();
+        match src {
+            IpAddr::V4(ip) => {
+                let info = in_pktinfo {
+                    ipi_ifindex: 0,
+                    ipi_spec_dst: in_addr {
+                        s_addr: u32::from_ne_bytes(ip.octets()),
+                    },
+                    ipi_addr: in_addr { s_addr: 0 },
+                };
+                unsafe { control.push_value(IPPROTO_IP, IP_PKTINFO, info) };
+            }
+            IpAddr::V6(ip) => {
+                let info = in6_pktinfo {
+                    ipi6_addr: in6_addr {
+                        s6_addr: ip.octets(),
+                    },
+                    ipi6_ifindex: 0,
+                };
+                unsafe { control.push_value(IPPROTO_IPV6, IPV6_PKTINFO, info) };
+            }
+        }
+
+        let mut msg: msghdr = unsafe { zeroed() };
+        msg.msg_name = raw_addr.as_ptr() as *mut c_void;
+        msg.msg_namelen = raw_addr_length;
+        msg.msg_iov = &iov as *const IoSlice as *mut iovec;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_bytes().as_ptr() as *mut c_void;
+        msg.msg_controllen = control.as_bytes().len() as _;
+
+        syscall!(sendmsg(self.as_raw_fd(), &msg, 0)).map(|n| n as usize)
+    }
+
+    /// Like `recv_from`, but also reports whether the datagram was truncated because it
+    /// didn't fit in `buf`.
+    pub fn recv_from_full(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, bool)> {
+        let mut addr = MaybeUninit::<sockaddr_storage>::zeroed();
+        let mut iov = IoSliceMut::new(buf);
+
+        let mut msg: msghdr = unsafe { zeroed() };
+        msg.msg_name = addr.as_mut_ptr() as *mut c_void;
+        msg.msg_namelen = size_of::<sockaddr_storage>() as socklen_t;
+        msg.msg_iov = &mut iov as *mut IoSliceMut as *mut iovec;
+        msg.msg_iovlen = 1;
+
+        let n = syscall!(recvmsg(self.as_raw_fd(), &mut msg, 0))? as usize;
+        let addr = unsafe { to_socket_addr(addr.as_ptr()) }?;
+        Ok((n, addr, msg.msg_flags & MSG_TRUNC != 0))
+    }
+
+    /// Receives up to `bufs.len()` datagrams with a single `recvmmsg(2)`, one per buffer,
+    /// returning the length and source of each in order. Datagrams too long for their
+    /// buffer are truncated.
+    ///
+    /// With `wait_for_one`, waits until at least one datagram arrives, then takes whatever
+    /// else is queued without waiting further (`MSG_WAITFORONE`). Without it, this fails
+    /// with `WouldBlock` if nothing is queued.
+    pub fn recv_mmsg(
+        &self,
+        bufs: &mut [&mut [u8]],
+        wait_for_one: bool,
+    ) -> io::Result<Vec<(usize, SocketAddr)>> {
+        let mut addrs = vec![MaybeUninit::<sockaddr_storage>::zeroed(); bufs.len()];
+        let mut iovs: Vec<IoSliceMut<'_>> =
+            bufs.iter_mut().map(|buf| IoSliceMut::new(buf)).collect();
+        let mut msgs: Vec<mmsghdr> = iovs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .map(|(iov, addr)| {
+                let mut msg: mmsghdr = unsafe { zeroed() };
+                msg.msg_hdr.msg_name = addr.as_mut_ptr() as *mut c_void;
+                msg.msg_hdr.msg_namelen = size_of::<sockaddr_storage>() as socklen_t;
+                msg.msg_hdr.msg_iov = iov as *mut IoSliceMut as *mut iovec;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg
+            })
+            .collect();
+
+        let flags = if wait_for_one { MSG_WAITFORONE } else { 0 };
+        let received = loop {
+            match syscall!(recvmmsg(
+                self.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as c_uint,
+                flags,
+                ptr::null_mut(),
+            )) {
+                Ok(received) => break received as usize,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                // The socket is non-blocking, so the wait for the first datagram is done
+                // here.
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock && wait_for_one => {
+                    poll_fd(self.as_raw_fd(), POLLIN, None)?;
+                }
+                Err(err) => return Err(err),
+            }
+        };
+
+        msgs[..received]
+            .iter()
+            .zip(&addrs)
+            .map(|(msg, addr)| {
+                let addr = unsafe { to_socket_addr(addr.as_ptr()) }?;
+                Ok((msg.msg_len as usize, addr))
+            })
+            .collect()
+    }
+
+    /// Enables reporting how many datagrams the kernel has dropped because the receive
+    /// queue was full, see [`recv_from_with_drops`](UdpSocket::recv_from_with_drops).
+    pub fn set_rxq_ovfl(&self, rxq_ovfl: bool) -> io::Result<()> {
+        setsockopt(self.as_raw_fd(), SOL_SOCKET, SO_RXQ_OVFL, rxq_ovfl as c_int)
+    }
+
+    pub fn rxq_ovfl(&self) -> io::Result<bool> {
+        getsockopt::<c_int>(self.as_raw_fd(), SOL_SOCKET, SO_RXQ_OVFL).map(|val| val != 0)
+    }
+
+    /// Like `recv_from`, but also returns the total number of datagrams dropped because the
+    /// receive queue overflowed, as of when this datagram was queued. Requires
+    /// [`set_rxq_ovfl`](UdpSocket::set_rxq_ovfl).
+    pub fn recv_from_with_drops(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, u32)> {
+        let mut addr = MaybeUninit::<sockaddr_storage>::zeroed();
+        let mut control = [0u8; 32];
+        let mut iov = IoSliceMut::new(buf);
+
+        let mut msg: msghdr = unsafe { zeroed() };
+        msg.msg_name = addr.as_mut_ptr() as *mut c_void;
+        msg.msg_namelen = size_of::<sockaddr_storage>() as socklen_t;
+        msg.msg_iov = &mut iov as *mut IoSliceMut as *mut iovec;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = control.len() as _;
+
+        let n = syscall!(recvmsg(self.as_raw_fd(), &mut msg, 0))? as usize;
+        let addr = unsafe { to_socket_addr(addr.as_ptr()) }?;
+
+        // The kernel leaves the counter out while it is still zero.
+        let mut drops = 0;
+        for cmsg in CmsgIter::new(&control[..msg.msg_controllen as usize]) {
+            if (cmsg.level, cmsg.kind) == (SOL_SOCKET, SO_RXQ_OVFL) {
+                drops = unsafe { cmsg.read::<u32>() }.unwrap_or(0);
+            }
+        }
+        Ok((n, addr, drops))
+    }
+
+    /// Returns the full size of the next datagram without consuming it, so a buffer large
+    /// enough for it can be allocated.
+    pub fn peek_len(&self) -> io::Result<usize> {
+        syscall!(recv(
+            self.as_raw_fd(),
+            ptr::null_mut(),
+            0,
+            MSG_PEEK | MSG_TRUNC
+        ))
+        .map(|n| n as usize)
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.recv(buf)
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.inner.recv_from(buf)
+    }
+
+    pub fn peek_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.inner.peek_from(buf)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn set_broadcast(&self, broadcast: bool) -> io::Result<()> {
+        self.inner.set_broadcast(broadcast)
+    }
+
+    pub fn broadcast(&self) -> io::Result<bool> {
+        self.inner.broadcast()
+    }
+
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        self.inner.ttl()
+    }
+
+    /// The path MTU the kernel currently knows for the connected peer, from `IP_MTU` or
+    /// `IPV6_MTU`; fails with `ENOTCONN` on an unconnected socket. Datagrams larger than
+    /// it fail with `EMSGSIZE` under [`MtuDiscover::Do`].
+    pub fn path_mtu(&self) -> io::Result<u32> {
+        path_mtu(self.as_raw_fd())
+    }
+
+    pub fn set_mtu_discover(&self, mode: MtuDiscover) -> io::Result<()> {
+        set_mtu_discover(self.as_raw_fd(), mode)
+    }
+
+    pub fn mtu_discover(&self) -> io::Result<MtuDiscover> {
+        mtu_discover(self.as_raw_fd())
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
+
+    pub fn from_std(socket: net::UdpSocket) -> UdpSocket {
+        Self::from(socket)
+    }
+}
+
+impl From<net::UdpSocket> for UdpSocket {
+    fn from(s: net::UdpSocket) -> Self {
+        UdpSocket { inner: s }
+    }
+}
+
+impl IntoRawFd for UdpSocket {
+    fn into_raw_fd(self) -> RawFd {
+        self.inner.into_raw_fd()
+    }
+}
+
+impl AsRawFd for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl FromRawFd for UdpSocket {
+    unsafe fn from_raw_fd(fd: RawFd) -> UdpSocket {
+        UdpSocket::from_std(FromRawFd::from_raw_fd(fd))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn socket() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    fn wait_readable(socket: &UdpSocket) {
+        poll_fd(socket.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+    }
+
+    #[test]
+    fn send_to_vectored_sends_one_datagram() {
+        let (sender, receiver) = (socket(), socket());
+        let to = receiver.local_addr().unwrap();
+        let bufs = [
+            IoSlice::new(b"hello "),
+            IoSlice::new(b""),
+            IoSlice::new(b"world"),
+        ];
+        assert_eq!(sender.send_to_vectored(&bufs, to, 0).unwrap(), 11);
+        sender.send_to(b"second", to).unwrap();
+
+        let mut buf = [0; 32];
+        wait_readable(&receiver);
+        let (n, from) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"hello world");
+        assert_eq!(from, sender.local_addr().unwrap());
+        let (n, _) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"second");
+        let err = receiver.recv_from(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn pktinfo_reports_and_sets_the_local_address() {
+        let server = UdpSocket::bind("0.0.0.0:0".parse().unwrap()).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let client = socket();
+        let mut buf = [0; 16];
+
+        // Without packet info the destination can't be told.
+        client
+            .send_to(b"a", SocketAddr::new([127, 0, 0, 1].into(), port))
+            .unwrap();
+        wait_readable(&server);
+        assert!(server.recv_from_with_dst(&mut buf).is_err());
+
+        assert!(!server.pktinfo().unwrap());
+        server.set_pktinfo(true).unwrap();
+        assert!(server.pktinfo().unwrap());
+        let dst: IpAddr = [127, 0, 0, 2].into();
+        client.send_to(b"ping", SocketAddr::new(dst, port)).unwrap();
+        wait_readable(&server);
+        let (n, from, to) = server.recv_from_with_dst(&mut buf).unwrap();
+        assert_eq!(
+            (&buf[..n], from, to),
+            (&b"ping"[..], client.local_addr().unwrap(), dst)
+        );
+
+        // Replying from the address the request went to.
+        server.send_to_from(b"pong", from, to).unwrap();
+        wait_readable(&client);
+        let (n, reply_from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"pong");
+        assert_eq!(reply_from, SocketAddr::new(dst, port));
+    }
+
+    #[test]
+    fn pktinfo_works_for_ipv6() {
+        let server = UdpSocket::bind("[::]:0".parse().unwrap()).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let client = UdpSocket::bind("[::1]:0".parse().unwrap()).unwrap();
+        server.set_pktinfo(true).unwrap();
+        assert!(server.pktinfo().unwrap());
+
+        let dst = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        client.send_to(b"ping", SocketAddr::new(dst, port)).unwrap();
+        let mut buf = [0; 16];
+        wait_readable(&server);
+        let (n, from, to) = server.recv_from_with_dst(&mut buf).unwrap();
+        assert_eq!((&buf[..n], to), (&b"ping"[..], dst));
+
+        server.send_to_from(b"pong", from, to).unwrap();
+        wait_readable(&client);
+        let (n, reply_from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(
+            (&buf[..n], reply_from),
+            (&b"pong"[..], SocketAddr::new(dst, port))
+        );
+    }
+
+    #[test]
+    fn truncation_is_reported_and_peek_len_sees_the_full_size() {
+        let (sender, receiver) = (socket(), socket());
+        let to = receiver.local_addr().unwrap();
+        sender.send_to(&[7; 100], to).unwrap();
+        sender.send_to(b"short", to).unwrap();
+
+        wait_readable(&receiver);
+        assert_eq!(receiver.peek_len().unwrap(), 100);
+        assert_eq!(receiver.peek_len().unwrap(), 100);
+        let mut buf = [0; 10];
+        let (n, from, truncated) = receiver.recv_from_full(&mut buf).unwrap();
+        assert_eq!(
+            (n, from, truncated),
+            (10, sender.local_addr().unwrap(), true)
+        );
+
+        assert_eq!(receiver.peek_len().unwrap(), 5);
+        let (n, _, truncated) = receiver.recv_from_full(&mut buf).unwrap();
+        assert_eq!((&buf[..n], truncated), (&b"short"[..], false));
+        let err = receiver.peek_len().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn rxq_ovfl_reports_dropped_datagrams() {
+        let (sender, receiver) = (socket(), socket());
+        let to = receiver.local_addr().unwrap();
+        assert!(!receiver.rxq_ovfl().unwrap());
+        receiver.set_rxq_ovfl(true).unwrap();
+        assert!(receiver.rxq_ovfl().unwrap());
+        setsockopt(
+            receiver.as_raw_fd(),
+            SOL_SOCKET,
+            libc::SO_RCVBUF,
+            4096 as c_int,
+        )
+        .unwrap();
+
+        let mut buf = [0; 1024];
+        sender.send_to(b"first", to).unwrap();
+        wait_readable(&receiver);
+        let (n, _, drops) = receiver.recv_from_with_drops(&mut buf).unwrap();
+        assert_eq!((&buf[..n], drops), (&b"first"[..], 0));
+
+        // Far more than the receive buffer holds.
+        for _ in 0..100 {
+            sender.send_to(&[0; 1000], to).unwrap();
+        }
+        while receiver.recv_from_with_drops(&mut buf).is_ok() {}
+
+        sender.send_to(b"last", to).unwrap();
+        wait_readable(&receiver);
+        let (n, _, drops) = receiver.recv_from_with_drops(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"last");
+        assert!(drops > 0 && drops < 100, "{drops}");
+    }
+
+    #[test]
+    fn disconnect_accepts_any_source_again() {
+        let (peer, other) = (socket(), socket());
+        // Binding a specific port keeps it across the disconnect.
+        let port = socket().local_addr().unwrap().port();
+        let udp = UdpSocket::bind(SocketAddr::new([127, 0, 0, 1].into(), port)).unwrap();
+        let addr = udp.local_addr().unwrap();
+        udp.connect(peer.local_addr().unwrap()).unwrap();
+        assert_eq!(udp.peer_addr().unwrap(), peer.local_addr().unwrap());
+        assert_eq!(udp.send(b"x").unwrap(), 1);
+
+        // While connected, datagrams from anyone else are dropped.
+        other.send_to(b"dropped", addr).unwrap();
+        peer.send_to(b"kept", addr).unwrap();
+        let mut buf = [0; 16];
+        wait_readable(&udp);
+        assert_eq!(udp.recv(&mut buf).unwrap(), 4);
+        assert_eq!(
+            udp.recv(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        udp.disconnect().unwrap();
+        assert_eq!(
+            udp.peer_addr().unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+        assert_eq!(
+            udp.send(b"x").unwrap_err().raw_os_error(),
+            Some(libc::EDESTADDRREQ)
+        );
+        other.send_to(b"welcome", addr).unwrap();
+        wait_readable(&udp);
+        let (n, from) = udp.recv_from(&mut buf).unwrap();
+        assert_eq!(
+            (&buf[..n], from),
+            (&b"welcome"[..], other.local_addr().unwrap())
+        );
+        assert_eq!(udp.local_addr().unwrap(), addr);
+
+        // A port the kernel picked is given up.
+        let udp = socket();
+        udp.connect(peer.local_addr().unwrap()).unwrap();
+        udp.disconnect().unwrap();
+        assert_eq!(udp.local_addr().unwrap().port(), 0);
+    }
+
+    #[test]
+    fn recv_mmsg_takes_at_most_one_datagram_per_buffer() {
+        let (receiver, sender) = (socket(), socket());
+        let addr = receiver.local_addr().unwrap();
+        let from = sender.local_addr().unwrap();
+        for len in 1..=5 {
+            sender.send_to(&vec![len as u8; len], addr).unwrap();
+        }
+        wait_readable(&receiver);
+
+        let mut storage = [[0u8; 8]; 3];
+        let mut bufs: Vec<&mut [u8]> = storage.iter_mut().map(|buf| &mut buf[..]).collect();
+        assert_eq!(
+            receiver.recv_mmsg(&mut bufs, false).unwrap(),
+            [(1, from), (2, from), (3, from)]
+        );
+        assert_eq!(&storage[2][..3], [3, 3, 3]);
+
+        // Only two are left, and the last is cut to its buffer.
+        let [mut first, mut second, mut third] = [[0u8; 8]; 3];
+        let mut bufs: Vec<&mut [u8]> = vec![&mut first, &mut second[..2], &mut third];
+        assert_eq!(
+            receiver.recv_mmsg(&mut bufs, false).unwrap(),
+            [(4, from), (2, from)]
+        );
+
+        assert_eq!(
+            receiver.recv_mmsg(&mut bufs, false).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn recv_mmsg_can_wait_for_the_first_datagram() {
+        let receiver = socket();
+        let addr = receiver.local_addr().unwrap();
+        let sender = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            socket().send_to(b"late", addr).unwrap();
+        });
+
+        let mut storage = [[0u8; 8]; 4];
+        let mut bufs: Vec<&mut [u8]> = storage.iter_mut().map(|buf| &mut buf[..]).collect();
+        let received = receiver.recv_mmsg(&mut bufs, true).unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0, 4);
+        assert_eq!(&storage[0][..4], b"late");
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn path_mtu_and_discovery_mode_for_udp() {
+        let lo_mtu: u32 = std::fs::read_to_string("/sys/class/net/lo/mtu")
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        for addr in ["127.0.0.1:0", "[::1]:0"] {
+            let (a, b) = (
+                UdpSocket::bind(addr.parse().unwrap()).unwrap(),
+                UdpSocket::bind(addr.parse().unwrap()).unwrap(),
+            );
+            assert_eq!(
+                a.path_mtu().unwrap_err().raw_os_error(),
+                Some(libc::ENOTCONN)
+            );
+            a.connect(b.local_addr().unwrap()).unwrap();
+            // IPv4 caps the MTU at the largest packet it can describe.
+            let expected = if addr.starts_with('[') {
+                lo_mtu
+            } else {
+                lo_mtu.min(65535)
+            };
+            assert_eq!(a.path_mtu().unwrap(), expected);
+
+            assert_eq!(a.mtu_discover().unwrap(), MtuDiscover::Want);
+            for mode in [
+                MtuDiscover::Do,
+                MtuDiscover::Probe,
+                MtuDiscover::Dont,
+                MtuDiscover::Want,
+            ] {
+                a.set_mtu_discover(mode).unwrap();
+                assert_eq!(a.mtu_discover().unwrap(), mode);
+            }
+
+            // Under `Do` a datagram filling the path MTU is sent whole.
+            a.set_mtu_discover(MtuDiscover::Do).unwrap();
+            let headers = if addr.starts_with('[') { 48 } else { 28 };
+            let fits = expected as usize - headers;
+            assert_eq!(a.send(&vec![0; fits]).unwrap(), fits);
+            // Only IPv6 allows a UDP datagram larger than loopback's MTU, which `Do`
+            // refuses and `Want` fragments.
+            if addr.starts_with('[') {
+                let too_big = vec![0; fits + 1];
+                assert_eq!(
+                    a.send(&too_big).unwrap_err().raw_os_error(),
+                    Some(libc::EMSGSIZE)
+                );
+                a.set_mtu_discover(MtuDiscover::Want).unwrap();
+                assert_eq!(a.send(&too_big).unwrap(), fits + 1);
+            }
+        }
+    }
+}
@@ -0,0 +1,462 @@
+use std::{
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    mem::{size_of, zeroed},
+    net::Shutdown,
+    os::{
+        fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+        unix::{
+            ffi::OsStrExt,
+            net::{self, SocketAddr},
+        },
+    },
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use libc::{
+    c_int, c_void, gid_t, iovec, msghdr, pid_t, sa_family_t, sockaddr_un, socklen_t, ucred, uid_t,
+    AF_UNIX, EINPROGRESS, MSG_CMSG_CLOEXEC, MSG_CTRUNC, MSG_NOSIGNAL, SCM_RIGHTS, SOCK_CLOEXEC,
+    SOCK_NONBLOCK, SOCK_STREAM, SOL_SOCKET, SO_PEERCRED,
+};
+
+use crate::syscall;
+
+use super::net::{
+    cmsg::{cmsg_space, CmsgBuilder, CmsgIter},
+    create_new_socket, getsockopt, send, send_vectored, DropCleanup,
+};
+
+// Not exported by libc.
+const SCM_MAX_FD: usize = 253;
+
+/// Converts a filesystem path into the system representation of a Unix socket address.
+pub(crate) fn unix_socket_addr(path: &Path) -> io::Result<(sockaddr_un, socklen_t)> {
+    let mut addr: sockaddr_un = unsafe { zeroed() };
+    addr.sun_family = AF_UNIX as sa_family_t;
+
+    let bytes = path.as_os_str().as_bytes();
+    // Leave room for the terminating nul.
+    if bytes.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "path too long for a Unix socket address",
+        ));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(bytes) {
+        *dst = *src as _;
+    }
+
+    let base = &addr as *const _ as usize;
+    let path_offset = addr.sun_path.as_ptr() as usize - base;
+    let length = path_offset + bytes.len() + 1;
+    Ok((addr, length as socklen_t))
+}
+
+/// Credentials of the process on the other end of a Unix socket, as of when it connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UCred {
+    pub pid: pid_t,
+    pub uid: uid_t,
+    pub gid: gid_t,
+}
+
+pub(crate) fn peer_cred(fd: c_int) -> io::Result<UCred> {
+    let cred: ucred = getsockopt(fd, SOL_SOCKET, SO_PEERCRED)?;
+    Ok(UCred {
+        pid: cred.pid,
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
+
+pub struct UnixListener {
+    // Declared first so it runs before `inner` closes the fd.
+    cleanup: DropCleanup,
+    inner: net::UnixListener,
+    /// The filesystem path this listener was bound to by `bind`.
+    path: Option<PathBuf>,
+}
+
+impl UnixListener {
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+        let socket = create_new_socket(AF_UNIX, SOCK_STREAM)?;
+        let mut listener = UnixListener::from_std(socket.into_std());
+
+        let (raw_addr, raw_addr_length) = unix_socket_addr(path.as_ref())?;
+        syscall!(bind(
+            listener.as_raw_fd(),
+            &raw_addr as *const _ as *const _,
+            raw_addr_length
+        ))?;
+
+        // Only record the path once bind has created it, so a failed bind can never
+        // remove a file belonging to someone else.
+        listener.path = Some(path.as_ref().to_path_buf());
+
+        syscall!(listen(listener.as_raw_fd(), 1024))?;
+
+        Ok(listener)
+    }
+
+    pub fn accept(&self) -> io::Result<(UnixStream, SocketAddr)> {
+        let stream = self.accept_stream()?;
+        let addr = stream.inner.peer_addr()?;
+        Ok((stream, addr))
+    }
+
+    /// Accepts a connection along with the credentials of the process that opened it.
+    pub fn accept_with_cred(&self) -> io::Result<(UnixStream, UCred)> {
+        let stream = self.accept_stream()?;
+        let cred = stream.peer_cred()?;
+        Ok((stream, cred))
+    }
+
+    fn accept_stream(&self) -> io::Result<UnixStream> {
+        syscall!(accept4(
+            self.as_raw_fd(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            SOCK_CLOEXEC | SOCK_NONBLOCK,
+        ))
+        .map(|socket| unsafe { UnixStream::from_raw_fd(socket) })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
+
+    /// Shuts the socket down when the listener is dropped. Skipped if the fd is taken with
+    /// `into_raw_fd`.
+    pub fn set_shutdown_on_drop(&mut self, shutdown: bool) {
+        self.cleanup.shutdown = shutdown.then(|| self.inner.as_raw_fd());
+    }
+
+    /// Removes the socket file when the listener is dropped, so the path can be bound
+    /// again. Only applies to listeners created with `bind`, and is skipped if the fd is
+    /// taken with `into_raw_fd`.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.cleanup.unlink = if unlink { self.path.clone() } else { None };
+    }
+
+    pub fn from_std(listener: net::UnixListener) -> UnixListener {
+        Self::from(listener)
+    }
+}
+
+impl From<net::UnixListener> for UnixListener {
+    fn from(l: net::UnixListener) -> Self {
+        UnixListener {
+            cleanup: DropCleanup::default(),
+            inner: l,
+            path: None,
+        }
+    }
+}
+
+impl IntoRawFd for UnixListener {
+    fn into_raw_fd(mut self) -> RawFd {
+        self.cleanup.disarm();
+        self.inner.into_raw_fd()
+    }
+}
+
+impl AsRawFd for UnixListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl FromRawFd for UnixListener {
+    unsafe fn from_raw_fd(fd: RawFd) -> UnixListener {
+        UnixListener::from_std(FromRawFd::from_raw_fd(fd))
+    }
+}
+
+pub struct UnixStream {
+    inner: net::UnixStream,
+}
+
+impl UnixStream {
+    /// Connects to the listener at `path`. Unlike TCP, a Unix socket connect doesn't stay
+    /// pending: if the listener's backlog is full it fails with `WouldBlock`, and has to be
+    /// retried once the listener has accepted some connections.
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<UnixStream> {
+        let socket = create_new_socket(AF_UNIX, SOCK_STREAM)?;
+        let stream = UnixStream::from_std(socket.into_std());
+        let (raw_addr, raw_addr_length) = unix_socket_addr(path.as_ref())?;
+
+        match syscall!(connect(
+            stream.as_raw_fd(),
+            &raw_addr as *const _ as *const _,
+            raw_addr_length
+        )) {
+            Err(err) if err.raw_os_error() != Some(EINPROGRESS) => Err(err),
+            _ => Ok(()),
+        }?;
+        Ok(stream)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    pub fn peer_cred(&self) -> io::Result<UCred> {
+        peer_cred(self.as_raw_fd())
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    /// Sends `buf` with copies of `fds` attached (`SCM_RIGHTS`). The fds arrive with the
+    /// first byte of `buf`, so it must not be empty.
+    pub fn send_with_fds(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        if fds.len() > SCM_MAX_FD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too many fds for one message",
+            ));
+        }
+        let iov = IoSlice::new(buf);
+        let mut control = CmsgBuilder::new();
+        if !fds.is_empty() {
+            let data: Vec<u8> = fds.iter().flat_map(|fd| fd.to_ne_bytes()).collect();
+            control.push(SOL_SOCKET, SCM_RIGHTS, &data);
+        }
+
+        let mut msg: msghdr = unsafe { zeroed() };
+        msg.msg_iov = &iov as *const IoSlice as *mut iovec;
+        msg.msg_iovlen = 1;
+        if !control.is_empty() {
+            msg.msg_control = control.as_bytes().as_ptr() as *mut c_void;
+            msg.msg_controllen = control.as_bytes().len() as _;
+        }
+
+        syscall!(sendmsg(self.as_raw_fd(), &msg, MSG_NOSIGNAL)).map(|n| n as usize)
+    }
+
+    /// Receives into `buf`, appending any fds that came along to `fds`. They are received
+    /// with close-on-exec set. If the call fails, `fds` is left as it was.
+    pub fn recv_with_fds(&self, buf: &mut [u8], fds: &mut Vec<OwnedFd>) -> io::Result<usize> {
+        let mut control = vec![0u8; cmsg_space(SCM_MAX_FD * size_of::<RawFd>())];
+        let mut iov = IoSliceMut::new(buf);
+
+        let mut msg: msghdr = unsafe { zeroed() };
+        msg.msg_iov = &mut iov as *mut IoSliceMut as *mut iovec;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = control.len() as _;
+
+        let n = syscall!(recvmsg(self.as_raw_fd(), &mut msg, MSG_CMSG_CLOEXEC))? as usize;
+
+        // Taken ownership of right away, so they're closed if the call fails.
+        let mut received = Vec::new();
+        for cmsg in CmsgIter::new(&control[..msg.msg_controllen as usize]) {
+            if (cmsg.level, cmsg.kind) == (SOL_SOCKET, SCM_RIGHTS) {
+                for fd in cmsg.data.chunks_exact(size_of::<RawFd>()) {
+                    let fd = RawFd::from_ne_bytes(fd.try_into().unwrap());
+                    received.push(unsafe { OwnedFd::from_raw_fd(fd) });
+                }
+            }
+        }
+
+        if msg.msg_flags & MSG_CTRUNC != 0 {
+            return Err(io::Error::other(
+                "fds were discarded, control data truncated",
+            ));
+        }
+        fds.extend(received);
+        Ok(n)
+    }
+
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
+
+    pub fn from_std(stream: net::UnixStream) -> UnixStream {
+        Self::from(stream)
+    }
+}
+
+impl Read for UnixStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.inner.read_vectored(bufs)
+    }
+}
+
+/// Writes pass `MSG_NOSIGNAL`, so writing to a closed connection fails with `EPIPE`
+/// instead of raising `SIGPIPE`.
+impl Write for UnixStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        send(self.as_raw_fd(), buf, 0)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        send_vectored(self.as_raw_fd(), bufs, 0)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl From<net::UnixStream> for UnixStream {
+    fn from(s: net::UnixStream) -> Self {
+        UnixStream { inner: s }
+    }
+}
+
+impl IntoRawFd for UnixStream {
+    fn into_raw_fd(self) -> RawFd {
+        self.inner.into_raw_fd()
+    }
+}
+
+impl AsRawFd for UnixStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl FromRawFd for UnixStream {
+    unsafe fn from_raw_fd(fd: RawFd) -> UnixStream {
+        UnixStream::from_std(FromRawFd::from_raw_fd(fd))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, time::Duration};
+
+    use libc::POLLIN;
+
+    use super::*;
+    use crate::net::poll_fd;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A socket path unique to the test, removed again when the returned listener drops.
+    fn listener(name: &str) -> (UnixListener, PathBuf) {
+        let path = std::env::temp_dir().join(format!("rio-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut listener = UnixListener::bind(&path).unwrap();
+        listener.set_unlink_on_drop(true);
+        (listener, path)
+    }
+
+    fn accept(listener: &UnixListener) -> UnixStream {
+        poll_fd(listener.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        listener.accept().unwrap().0
+    }
+
+    #[test]
+    fn accept_with_cred_reports_the_connecting_process() {
+        let (listener, path) = listener("cred");
+        let client = UnixStream::connect(&path).unwrap();
+        poll_fd(listener.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        let (_stream, cred) = listener.accept_with_cred().unwrap();
+        let expected = UCred {
+            pid: unsafe { libc::getpid() },
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        };
+        assert_eq!(cred, expected);
+        assert_eq!(client.peer_cred().unwrap(), expected);
+    }
+
+    #[test]
+    fn fds_round_trip() {
+        let (listener, path) = listener("fds");
+        let client = UnixStream::connect(&path).unwrap();
+        let server = accept(&listener);
+
+        let mut pipe = [0; 2];
+        syscall!(pipe2(pipe.as_mut_ptr(), SOCK_CLOEXEC)).unwrap();
+        let (read_end, write_end) =
+            unsafe { (OwnedFd::from_raw_fd(pipe[0]), OwnedFd::from_raw_fd(pipe[1])) };
+        assert_eq!(
+            client
+                .send_with_fds(b"x", &[read_end.as_raw_fd(), read_end.as_raw_fd()])
+                .unwrap(),
+            1
+        );
+        drop(read_end);
+
+        let mut buf = [0; 4];
+        let mut fds = Vec::new();
+        poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        assert_eq!(server.recv_with_fds(&mut buf, &mut fds).unwrap(), 1);
+        assert_eq!(&buf[..1], b"x");
+        assert_eq!(fds.len(), 2);
+
+        // The received fd is a working copy of the pipe's read end.
+        File::from(write_end).write_all(b"pipe").unwrap();
+        let mut received = File::from(fds.remove(0));
+        received.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pipe");
+
+        // Plain data comes through without fds.
+        client.send_with_fds(b"y", &[]).unwrap();
+        poll_fd(server.as_raw_fd(), POLLIN, Some(TIMEOUT)).unwrap();
+        assert_eq!(server.recv_with_fds(&mut buf, &mut fds).unwrap(), 1);
+        assert_eq!(fds.len(), 1);
+    }
+
+    #[test]
+    fn connect_fails_with_would_block_on_a_full_backlog() {
+        let (listener, path) = listener("backlog");
+        // With a backlog of zero, one connection can wait to be accepted.
+        syscall!(listen(listener.as_raw_fd(), 0)).unwrap();
+        let _queued = UnixStream::connect(&path).unwrap();
+        let err = UnixStream::connect(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        let _accepted = accept(&listener);
+        UnixStream::connect(&path).unwrap();
+    }
+
+    #[test]
+    fn unlink_on_drop_is_skipped_after_into_raw_fd() {
+        let (dropped, path) = listener("unlink");
+        drop(dropped);
+        assert!(!path.exists());
+
+        let (taken, path) = listener("unlink");
+        let fd = unsafe { OwnedFd::from_raw_fd(taken.into_raw_fd()) };
+        assert!(path.exists());
+        // Still listening on the fd taken out.
+        let _client = UnixStream::connect(&path).unwrap();
+        drop(fd);
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+
+        // Without the option, the file stays.
+        let mut listener = UnixListener::bind(&path).unwrap();
+        listener.set_unlink_on_drop(false);
+        drop(listener);
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn send_with_fds_refuses_too_many_fds() {
+        let (listener, path) = listener("many-fds");
+        let client = UnixStream::connect(&path).unwrap();
+        let _server = accept(&listener);
+        let fds = vec![client.as_raw_fd(); SCM_MAX_FD + 1];
+        let err = client.send_with_fds(b"x", &fds).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,272 @@
+use std::io;
+
+use crate::codec::Decoded;
+
+/// Default limit on the payload of a single frame.
+pub const MAX_PAYLOAD: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_u8(code: u8) -> Option<Opcode> {
+        match code {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xa => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xa,
+        }
+    }
+
+    pub fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+/// A single WebSocket frame (RFC 6455, section 5). Extensions aren't supported, so frames
+/// with reserved bits set are rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WsFrame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    /// The masking key; clients must mask every frame they send, servers must not.
+    pub mask: Option<[u8; 4]>,
+    /// The payload, always unmasked.
+    pub payload: Vec<u8>,
+}
+
+impl WsFrame {
+    pub fn new(opcode: Opcode, payload: Vec<u8>) -> WsFrame {
+        WsFrame {
+            fin: true,
+            opcode,
+            mask: None,
+            payload,
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Decoded<WsFrame>> {
+        Self::decode_limited(buf, MAX_PAYLOAD)
+    }
+
+    /// Decodes a frame, failing with `InvalidData` if its payload is longer than
+    /// `max_payload`. The length is checked as soon as the header arrives.
+    pub fn decode_limited(buf: &[u8], max_payload: u64) -> io::Result<Decoded<WsFrame>> {
+        let (first, second) = match buf {
+            [first, second, ..] => (*first, *second),
+            _ => return Ok(Decoded::Incomplete),
+        };
+        if first & 0x70 != 0 {
+            return Err(invalid("reserved bits set"));
+        }
+        let fin = first & 0x80 != 0;
+        let opcode = Opcode::from_u8(first & 0x0f).ok_or_else(|| invalid("reserved opcode"))?;
+
+        let (length, mut offset) = match second & 0x7f {
+            126 => match buf.get(2..4) {
+                Some(bytes) => (u16::from_be_bytes([bytes[0], bytes[1]]) as u64, 4),
+                None => return Ok(Decoded::Incomplete),
+            },
+            127 => match buf.get(2..10) {
+                Some(bytes) => (u64::from_be_bytes(bytes.try_into().unwrap()), 10),
+                None => return Ok(Decoded::Incomplete),
+            },
+            length => (length as u64, 2),
+        };
+        if length >> 63 != 0 {
+            return Err(invalid("payload length has the most significant bit set"));
+        }
+        if opcode.is_control() && (!fin || length > 125) {
+            return Err(invalid("fragmented or oversized control frame"));
+        }
+        if length > max_payload {
+            return Err(invalid("frame payload too large"));
+        }
+
+        let mask = if second & 0x80 != 0 {
+            match buf.get(offset..offset + 4) {
+                Some(key) => {
+                    offset += 4;
+                    Some([key[0], key[1], key[2], key[3]])
+                }
+                None => return Ok(Decoded::Incomplete),
+            }
+        } else {
+            None
+        };
+
+        let end = offset + length as usize;
+        let mut payload = match buf.get(offset..end) {
+            Some(payload) => payload.to_vec(),
+            None => return Ok(Decoded::Incomplete),
+        };
+        if let Some(key) = mask {
+            apply_mask(&mut payload, key);
+        }
+
+        let frame = WsFrame {
+            fin,
+            opcode,
+            mask,
+            payload,
+        };
+        Ok(Decoded::Complete(frame, end))
+    }
+
+    /// Appends the encoded frame to `out`, masking the payload if `mask` is set.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let mask_bit = if self.mask.is_some() { 0x80 } else { 0 };
+        out.push((self.fin as u8) << 7 | self.opcode.as_u8());
+
+        let length = self.payload.len();
+        if length < 126 {
+            out.push(mask_bit | length as u8);
+        } else if length <= u16::MAX as usize {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(length as u16).to_be_bytes());
+        } else {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+
+        let start = out.len();
+        match self.mask {
+            Some(key) => {
+                out.extend_from_slice(&key);
+                out.extend_from_slice(&self.payload);
+                apply_mask(&mut out[start + 4..], key);
+            }
+            None => out.extend_from_slice(&self.payload),
+        }
+    }
+}
+
+/// Masking is an XOR with the key repeated over the payload, so it also unmasks.
+fn apply_mask(payload: &mut [u8], key: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= key[i % 4];
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(frame: &WsFrame) -> Vec<u8> {
+        let mut buf = Vec::new();
+        frame.encode(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn round_trips_every_length_encoding() {
+        for len in [0, 125, 126, 65535, 65536] {
+            for mask in [None, Some([1, 2, 3, 4])] {
+                let frame = WsFrame {
+                    fin: len != 126,
+                    opcode: Opcode::Binary,
+                    mask,
+                    payload: (0..len).map(|i| i as u8).collect(),
+                };
+                let buf = encode(&frame);
+                assert_eq!(
+                    WsFrame::decode(&buf).unwrap(),
+                    Decoded::Complete(frame, buf.len())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn matches_the_rfc_examples() {
+        // RFC 6455, section 5.7.
+        let unmasked = b"\x81\x05Hello";
+        let masked = b"\x81\x85\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58";
+        let mut frame = WsFrame::new(Opcode::Text, b"Hello".to_vec());
+        assert_eq!(encode(&frame), unmasked);
+        frame.mask = Some([0x37, 0xfa, 0x21, 0x3d]);
+        assert_eq!(encode(&frame), masked);
+        assert_eq!(
+            WsFrame::decode(masked).unwrap(),
+            Decoded::Complete(frame, masked.len())
+        );
+
+        let fragment = b"\x01\x03Hel";
+        match WsFrame::decode(fragment).unwrap() {
+            Decoded::Complete(frame, _) => {
+                assert!(!frame.fin);
+                assert_eq!(frame.opcode, Opcode::Text);
+            }
+            Decoded::Incomplete => panic!("incomplete"),
+        }
+    }
+
+    #[test]
+    fn waits_for_the_whole_frame() {
+        for len in [5, 200, 70000] {
+            let mut frame = WsFrame::new(Opcode::Binary, vec![7; len]);
+            frame.mask = Some([9, 8, 7, 6]);
+            let buf = encode(&frame);
+            // Cuts inside the header, the extended length, the mask and the payload.
+            for end in [0, 1, 2, 3, 9, 13, buf.len() - 1] {
+                let end = end.min(buf.len() - 1);
+                assert_eq!(WsFrame::decode(&buf[..end]).unwrap(), Decoded::Incomplete);
+            }
+        }
+    }
+
+    #[test]
+    fn enforces_max_payload() {
+        let buf = encode(&WsFrame::new(Opcode::Binary, vec![0; 200]));
+        assert!(WsFrame::decode_limited(&buf, 200).is_ok());
+        // Rejected from the header alone, before the payload arrives.
+        let err = WsFrame::decode_limited(&buf[..4], 199).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        let malformed: [&[u8]; 6] = [
+            // Reserved bit.
+            b"\xc1\x00",
+            // Reserved opcode.
+            b"\x83\x00",
+            // Fragmented ping.
+            b"\x09\x00",
+            // Control frame over 125 bytes.
+            b"\x88\x7e\x00\x7e",
+            // Length with the most significant bit set.
+            b"\x82\x7f\x80\x00\x00\x00\x00\x00\x00\x00",
+            b"\x82\x7f\xff\xff\xff\xff\xff\xff\xff\xff",
+        ];
+        for buf in malformed {
+            let err = WsFrame::decode_limited(buf, u64::MAX).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", buf);
+        }
+    }
+}